@@ -0,0 +1,105 @@
+//! Encoding of bodies for their `Content-Transfer-Encoding`
+
+use crate::builder::quoted_printable;
+
+/// Maximum length of an encoded line allowed by
+/// [RFC 2045](https://tools.ietf.org/html/rfc2045#section-6.8), without the line ending
+pub const MAX_LINE_LEN: usize = 76;
+
+/// Encodes bodies with `base64` or `quoted-printable`, wrapping the encoded lines
+///
+/// Lines are at most 76 characters long by default, as required by RFC 2045. Some legacy
+/// systems need shorter lines, like the 64 characters of PEM.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct BodyEncoder {
+    line_len: usize,
+}
+
+impl Default for BodyEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BodyEncoder {
+    /// Creates an encoder with 76 characters long lines
+    pub fn new() -> BodyEncoder {
+        BodyEncoder {
+            line_len: MAX_LINE_LEN,
+        }
+    }
+
+    /// Set the maximum length of the encoded lines
+    ///
+    /// The length is clamped between 4 and 76 characters. `base64` lines are rounded
+    /// down to a multiple of 4, so they end on a complete group.
+    pub fn line_length(mut self, len: usize) -> BodyEncoder {
+        self.line_len = len.clamp(4, MAX_LINE_LEN);
+        self
+    }
+
+    /// Encodes a body with `base64`
+    pub fn base64(&self, body: &[u8]) -> String {
+        let encoded = base64::encode(body);
+        let line_len = self.line_len - self.line_len % 4;
+        let mut wrapped = String::with_capacity(encoded.len() + encoded.len() / line_len * 2);
+        for (idx, line) in encoded.as_bytes().chunks(line_len).enumerate() {
+            if idx > 0 {
+                wrapped.push_str("\r\n");
+            }
+            // The unwrap is fine because base64 is ASCII.
+            wrapped.push_str(std::str::from_utf8(line).unwrap());
+        }
+        wrapped
+    }
+
+    /// Encodes a text body with `quoted-printable`
+    pub fn quoted_printable(&self, body: &str) -> String {
+        quoted_printable::encode_with_line_len(body, self.line_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BodyEncoder;
+
+    #[test]
+    fn test_base64_line_length() {
+        let body = (0..=255).collect::<Vec<u8>>();
+
+        let encoded = BodyEncoder::new().base64(&body);
+        assert!(encoded.lines().all(|line| line.len() <= 76));
+        assert_eq!(encoded.lines().next().unwrap().len(), 76);
+
+        let encoded = BodyEncoder::new().line_length(64).base64(&body);
+        let lines = encoded.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[..5].iter().all(|line| line.len() == 64));
+        assert_eq!(lines[5].len(), 24);
+        assert_eq!(base64::decode(&lines.concat()).unwrap(), body);
+
+        let encoded = BodyEncoder::new().line_length(10).base64(&body);
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 8));
+        assert_eq!(BodyEncoder::new().base64(b""), "");
+    }
+
+    #[test]
+    fn test_quoted_printable_line_length() {
+        let body = "é".repeat(40);
+
+        let encoded = BodyEncoder::new().line_length(64).quoted_printable(&body);
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 64));
+        assert!(encoded.split("\r\n").any(|line| line.len() > 60));
+
+        let encoded = BodyEncoder::new().quoted_printable(&body);
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 76));
+        assert!(encoded.split("\r\n").any(|line| line.len() > 64));
+    }
+
+    #[test]
+    fn test_line_length_clamped() {
+        assert_eq!(BodyEncoder::new().line_length(1000), BodyEncoder::new());
+        let encoded = BodyEncoder::new().line_length(0).quoted_printable("ééé");
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 4));
+    }
+}
@@ -14,6 +14,20 @@ pub enum Error {
     Envelope(crate::error::Error),
     /// Unparseable filename for attachment
     CannotParseFilename,
+    /// Malformed language tag
+    InvalidLanguageTag(String),
+    /// Header name containing forbidden characters
+    InvalidHeaderName(String),
+    /// Header value containing forbidden characters, for the header with the given name
+    InvalidHeaderValue(String),
+    /// Unparseable address list
+    InvalidAddressList(String),
+    /// Generated MIME boundary that is malformed or appears in the content of the part
+    InvalidBoundary(String),
+    /// Body that can not be sent with the given content transfer encoding
+    InvalidBody(String),
+    /// Raw MIME part that is malformed, with the reason
+    InvalidRawPart(&'static str),
     /// IO error
     Io(io::Error),
 }
@@ -22,6 +36,13 @@ impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         fmt.write_str(&match *self {
             CannotParseFilename => "Could not parse attachment filename".to_owned(),
+            InvalidLanguageTag(ref tag) => format!("Invalid language tag: \"{}\"", tag),
+            InvalidHeaderName(ref name) => format!("Invalid header name: \"{}\"", name),
+            InvalidHeaderValue(ref name) => format!("Invalid value for header \"{}\"", name),
+            InvalidAddressList(ref err) => format!("Invalid address list: {}", err),
+            InvalidBoundary(ref boundary) => format!("Invalid MIME boundary: \"{}\"", boundary),
+            InvalidBody(ref encoding) => format!("Body can not be encoded as {}", encoding),
+            InvalidRawPart(reason) => format!("Invalid raw MIME part: {}", reason),
             Io(ref err) => err.to_string(),
             Envelope(ref err) => err.to_string(),
         })
@@ -29,7 +50,7 @@ impl Display for Error {
 }
 
 impl StdError for Error {
-    fn cause(&self) -> Option<&dyn StdError> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
             Envelope(ref err) => Some(err),
             Io(ref err) => Some(err),
@@ -0,0 +1,141 @@
+//! Formatting of text bodies as `format=flowed`
+//!
+//! [RFC 3676](https://tools.ietf.org/html/rfc3676)
+
+/// Maximum length of a formatted line, without the line ending
+pub const MAX_LINE_LEN: usize = 78;
+
+/// Formats a text body as `format=flowed`
+///
+/// Long lines are wrapped after a space, leaving the space at the end of the line so that
+/// the reader can join them again. Trailing spaces of the original lines are removed, and
+/// lines starting with a space, `>` or `From ` are space-stuffed. Line breaks, `\r\n` or
+/// `\n`, are kept as `\r\n`.
+///
+/// With `delsp`, an extra space is added before each soft line break, to be deleted by
+/// the reader, as required by the `delsp=yes` parameter.
+pub fn encode(body: &str, delsp: bool) -> String {
+    let mut encoded = String::with_capacity(body.len());
+    for (idx, line) in body.split('\n').enumerate() {
+        if idx > 0 {
+            encoded.push_str("\r\n");
+        }
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        // The signature separator keeps its trailing space
+        let mut line = if line == "-- " {
+            line
+        } else {
+            line.trim_end_matches(' ')
+        };
+
+        loop {
+            let stuffed = needs_stuffing(line);
+            let max_len = MAX_LINE_LEN - stuffed as usize - delsp as usize;
+            let split = soft_break(line, max_len);
+            if stuffed {
+                encoded.push(' ');
+            }
+            match split {
+                Some(split) => {
+                    encoded.push_str(&line[..split]);
+                    if delsp {
+                        encoded.push(' ');
+                    }
+                    encoded.push_str("\r\n");
+                    line = &line[split..];
+                }
+                None => {
+                    encoded.push_str(line);
+                    break;
+                }
+            }
+        }
+    }
+    encoded
+}
+
+fn needs_stuffing(line: &str) -> bool {
+    line.starts_with(' ') || line.starts_with('>') || line.starts_with("From ")
+}
+
+/// Finds where to wrap a line longer than `max_len` characters, after a space
+/// followed by another character
+///
+/// Words longer than a line are kept whole.
+fn soft_break(line: &str, max_len: usize) -> Option<usize> {
+    if line.chars().count() <= max_len {
+        return None;
+    }
+    let mut last = None;
+    let mut chars = line.char_indices().enumerate().peekable();
+    while let Some((count, (idx, c))) = chars.next() {
+        let next = match chars.peek() {
+            Some(&(_, (_, next))) => next,
+            None => break,
+        };
+        if c == ' ' && next != ' ' {
+            if count < max_len || last.is_none() {
+                last = Some(idx + 1);
+            }
+            if count >= max_len {
+                break;
+            }
+        }
+    }
+    last
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, MAX_LINE_LEN};
+
+    #[test]
+    fn test_short_lines() {
+        assert_eq!(
+            encode("Hello\nworld  \r\n\n-- \nMe", false),
+            "Hello\r\nworld\r\n\r\n-- \r\nMe"
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        let line = "word ".repeat(40);
+        let encoded = encode(line.trim_end(), false);
+        let lines = encoded.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LEN));
+        assert!(lines[..2].iter().all(|line| line.ends_with("word ")));
+        assert!(lines[2].ends_with("word"));
+        assert_eq!(lines.concat(), line.trim_end());
+
+        let encoded = encode(line.trim_end(), true);
+        let lines = encoded.split("\r\n").collect::<Vec<_>>();
+        assert!(lines[..lines.len() - 1]
+            .iter()
+            .all(|line| line.ends_with("word  ") && line.len() <= MAX_LINE_LEN));
+    }
+
+    #[test]
+    fn test_long_word() {
+        let word = "a".repeat(100);
+        assert_eq!(encode(&word, false), word);
+        assert_eq!(
+            encode(&format!("{} b", word), false),
+            format!("{} \r\nb", word)
+        );
+    }
+
+    #[test]
+    fn test_space_stuffing() {
+        assert_eq!(
+            encode(" indented\n> quoted\nFrom me\nFromage", false),
+            "  indented\r\n > quoted\r\n From me\r\nFromage"
+        );
+
+        let line = format!("{}From here", "a ".repeat(37));
+        assert_eq!(
+            encode(&line, false),
+            format!("{}\r\n From here", "a ".repeat(37))
+        );
+    }
+}
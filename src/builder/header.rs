@@ -0,0 +1,846 @@
+//! Typed headers for the email builder
+
+use crate::builder::error::Error;
+use crate::builder::utf8_b;
+use email::results::{ParsingError, ParsingResult};
+use email::rfc5322::MIME_LINE_LENGTH;
+use email::{Address, FromHeader, ToFoldedHeader, ToHeader};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use time::Tm;
+use uuid::Uuid;
+
+/// `Content-Language` header, a list of language tags
+///
+/// [RFC 3282](https://tools.ietf.org/html/rfc3282)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ContentLanguage(Vec<String>);
+
+impl ContentLanguage {
+    /// Creates a `Content-Language` header from a list of language tags, which fails if
+    /// the list is empty or if a tag is not well-formed
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(
+        tags: I,
+    ) -> Result<ContentLanguage, Error> {
+        let tags = tags.into_iter().map(Into::into).collect::<Vec<String>>();
+        if tags.is_empty() {
+            return Err(Error::InvalidLanguageTag(String::new()));
+        }
+        if let Some(tag) = tags.iter().find(|tag| !ContentLanguage::is_valid_tag(tag)) {
+            return Err(Error::InvalidLanguageTag(tag.clone()));
+        }
+        Ok(ContentLanguage(tags))
+    }
+
+    /// Checks the syntax of a language tag
+    ///
+    /// This only checks the general form of the tag: subtags of one to eight alphanumeric
+    /// characters separated by hyphens, starting with an alphabetic one.
+    ///
+    /// [RFC 5646, section 2.1](https://tools.ietf.org/html/rfc5646#section-2.1)
+    pub fn is_valid_tag(tag: &str) -> bool {
+        let mut subtags = tag.split('-');
+        let first_valid = subtags
+            .next()
+            .map(|primary| {
+                !primary.is_empty()
+                    && primary.len() <= 8
+                    && primary.bytes().all(|b| b.is_ascii_alphabetic())
+            })
+            .unwrap_or(false);
+        first_valid
+            && subtags.all(|subtag| {
+                !subtag.is_empty()
+                    && subtag.len() <= 8
+                    && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+            })
+    }
+
+    /// Language tags of the header
+    pub fn tags(&self) -> &[String] {
+        self.0.as_slice()
+    }
+}
+
+impl Display for ContentLanguage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
+
+impl FromStr for ContentLanguage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ContentLanguage::new(s.split(',').map(str::trim))
+    }
+}
+
+impl FromHeader for ContentLanguage {
+    fn from_header(value: String) -> ParsingResult<ContentLanguage> {
+        value
+            .parse()
+            .map_err(|err: Error| ParsingError::new(err.to_string()))
+    }
+}
+
+impl ToHeader for ContentLanguage {
+    fn to_header(value: ContentLanguage) -> ParsingResult<String> {
+        Ok(value.to_string())
+    }
+}
+
+/// Presentation of a part
+///
+/// [RFC 2183, section 2](https://tools.ietf.org/html/rfc2183#section-2)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DispositionType {
+    /// Displayed automatically with the message
+    Inline,
+    /// Separate from the main body of the message
+    Attachment,
+}
+
+impl Display for DispositionType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DispositionType::Inline => "inline",
+            DispositionType::Attachment => "attachment",
+        })
+    }
+}
+
+/// `Content-Transfer-Encoding` header
+///
+/// [RFC 2045, section 6](https://tools.ietf.org/html/rfc2045#section-6)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ContentTransferEncoding {
+    /// Lines of ASCII text
+    SevenBit,
+    /// Text with the non-ASCII bytes and long lines encoded, readable when mostly ASCII
+    QuotedPrintable,
+    /// Any content, encoded in base64
+    Base64,
+    /// Lines of text, which may contain non-ASCII bytes
+    EightBit,
+    /// Content sent as is
+    Binary,
+}
+
+impl Display for ContentTransferEncoding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ContentTransferEncoding::SevenBit => "7bit",
+            ContentTransferEncoding::QuotedPrintable => "quoted-printable",
+            ContentTransferEncoding::Base64 => "base64",
+            ContentTransferEncoding::EightBit => "8bit",
+            ContentTransferEncoding::Binary => "binary",
+        })
+    }
+}
+
+impl ToHeader for ContentTransferEncoding {
+    fn to_header(value: ContentTransferEncoding) -> ParsingResult<String> {
+        Ok(value.to_string())
+    }
+}
+
+/// Maximum length of a section of an encoded parameter value
+const MAX_PARAMETER_SECTION_LEN: usize = 60;
+
+/// `Content-Disposition` header
+///
+/// File names that are not printable ASCII are encoded as extended parameters, split into
+/// several sections when they are long.
+///
+/// [RFC 2183](https://tools.ietf.org/html/rfc2183),
+/// [RFC 2231](https://tools.ietf.org/html/rfc2231)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ContentDisposition {
+    /// Disposition type
+    pub disposition: DispositionType,
+    /// Suggested file name
+    pub filename: Option<String>,
+    /// Date the file was created
+    pub creation_date: Option<Tm>,
+    /// Date the file was last modified
+    pub modification_date: Option<Tm>,
+}
+
+impl ContentDisposition {
+    /// Creates an `inline` disposition without file name
+    pub fn inline() -> ContentDisposition {
+        ContentDisposition {
+            disposition: DispositionType::Inline,
+            filename: None,
+            creation_date: None,
+            modification_date: None,
+        }
+    }
+
+    /// Creates an `attachment` disposition with the given file name
+    pub fn attachment<S: Into<String>>(filename: S) -> ContentDisposition {
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            filename: Some(filename.into()),
+            creation_date: None,
+            modification_date: None,
+        }
+    }
+
+    /// Sets the `creation-date` parameter
+    pub fn creation_date(mut self, date: Tm) -> ContentDisposition {
+        self.creation_date = Some(date);
+        self
+    }
+
+    /// Sets the `modification-date` parameter, like the modification time of a file
+    pub fn modification_date(mut self, date: Tm) -> ContentDisposition {
+        self.modification_date = Some(date);
+        self
+    }
+
+    /// Formats a parameter, as a quoted string if possible or as an extended parameter
+    fn format_parameter(name: &str, value: &str) -> String {
+        if value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+            return format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            );
+        }
+
+        // Split the encoded value into sections, without splitting characters
+        let mut sections = vec![String::new()];
+        for c in value.chars() {
+            let mut buf = [0; 4];
+            let encoded = c
+                .encode_utf8(&mut buf)
+                .bytes()
+                .map(|b| {
+                    if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                        (b as char).to_string()
+                    } else {
+                        format!("%{:02X}", b)
+                    }
+                })
+                .collect::<String>();
+            if sections.last().unwrap().len() + encoded.len() > MAX_PARAMETER_SECTION_LEN {
+                sections.push(String::new());
+            }
+            sections.last_mut().unwrap().push_str(&encoded);
+        }
+
+        if sections.len() == 1 {
+            format!("{}*=UTF-8''{}", name, sections[0])
+        } else {
+            sections
+                .iter()
+                .enumerate()
+                .map(|(idx, section)| {
+                    let charset = if idx == 0 { "UTF-8''" } else { "" };
+                    format!("{}*{}*={}{}", name, idx, charset, section)
+                })
+                .collect::<Vec<String>>()
+                .join(";\r\n ")
+        }
+    }
+}
+
+impl ToHeader for ContentDisposition {
+    fn to_header(value: ContentDisposition) -> ParsingResult<String> {
+        let mut parameters = vec![value.disposition.to_string()];
+        if let Some(ref filename) = value.filename {
+            parameters.push(ContentDisposition::format_parameter("filename", filename));
+        }
+        // Dates are quoted strings in the RFC 822 format
+        if let Some(ref date) = value.creation_date {
+            parameters.push(format!("creation-date=\"{}\"", date.rfc822z()));
+        }
+        if let Some(ref date) = value.modification_date {
+            parameters.push(format!("modification-date=\"{}\"", date.rfc822z()));
+        }
+        Ok(parameters.join("; "))
+    }
+}
+
+/// `Content-ID` header, identifying a part referenced with a `cid:` URL
+///
+/// The identifier is written between angle brackets in the header, and without them in
+/// the URL.
+///
+/// [RFC 2392](https://tools.ietf.org/html/rfc2392)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ContentId(String);
+
+impl ContentId {
+    /// Creates a `Content-ID` from an identifier, with or without angle brackets
+    pub fn new<S: Into<String>>(id: S) -> ContentId {
+        let id = id.into();
+        let id = id.trim();
+        ContentId(
+            id.strip_prefix('<')
+                .and_then(|id| id.strip_suffix('>'))
+                .unwrap_or(id)
+                .to_string(),
+        )
+    }
+
+    /// Generates a unique `Content-ID`
+    pub fn generate() -> ContentId {
+        ContentId(format!("{}.lettre@localhost", Uuid::new_v4()))
+    }
+
+    /// Returns the `cid:` URL referencing the part, to be used in the HTML body
+    pub fn url(&self) -> String {
+        let mut url = "cid:".to_string();
+        for byte in self.0.bytes() {
+            if byte.is_ascii_alphanumeric() || b"!$&'*+-./=@^_`{|}~".contains(&byte) {
+                url.push(byte as char);
+            } else {
+                url.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        url
+    }
+}
+
+impl Display for ContentId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.0)
+    }
+}
+
+impl ToHeader for ContentId {
+    fn to_header(value: ContentId) -> ParsingResult<String> {
+        Ok(value.to_string())
+    }
+}
+
+/// `Received` header, the trace of a hop of the message
+///
+/// It is formatted as `from <host> by <host> with <protocol> id <id> for <address>; <date>`,
+/// without the clauses that are not set.
+///
+/// [RFC 5321, section 4.4](https://tools.ietf.org/html/rfc5321#section-4.4)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Received {
+    from: Option<String>,
+    by: Option<String>,
+    protocol: Option<String>,
+    id: Option<String>,
+    recipient: Option<String>,
+    date: Tm,
+}
+
+impl Received {
+    /// Creates a trace of a hop received at the given date
+    pub fn new(date: Tm) -> Received {
+        Received {
+            from: None,
+            by: None,
+            protocol: None,
+            id: None,
+            recipient: None,
+            date,
+        }
+    }
+
+    /// Sets the host the message was received from
+    pub fn from<S: Into<String>>(mut self, host: S) -> Received {
+        self.from = Some(host.into());
+        self
+    }
+
+    /// Sets the host that received the message
+    pub fn by<S: Into<String>>(mut self, host: S) -> Received {
+        self.by = Some(host.into());
+        self
+    }
+
+    /// Sets the protocol the message was received with, like `ESMTP` or `ESMTPSA`
+    pub fn with<S: Into<String>>(mut self, protocol: S) -> Received {
+        self.protocol = Some(protocol.into());
+        self
+    }
+
+    /// Sets the identifier given to the message by the receiving host
+    pub fn id<S: Into<String>>(mut self, id: S) -> Received {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the recipient the message was received for
+    pub fn recipient<S: Into<String>>(mut self, address: S) -> Received {
+        self.recipient = Some(address.into());
+        self
+    }
+}
+
+impl Display for Received {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut clauses = vec![];
+        for &(name, value) in &[
+            ("from", &self.from),
+            ("by", &self.by),
+            ("with", &self.protocol),
+            ("id", &self.id),
+        ] {
+            if let Some(ref value) = *value {
+                clauses.push(format!("{} {}", name, value));
+            }
+        }
+        if let Some(ref recipient) = self.recipient {
+            clauses.push(format!("for <{}>", recipient));
+        }
+        write!(f, "{}; {}", clauses.join(" "), self.date.rfc822z())
+    }
+}
+
+impl ToHeader for Received {
+    fn to_header(value: Received) -> ParsingResult<String> {
+        Ok(value.to_string())
+    }
+}
+
+/// List of mailboxes and groups, as used in the `To` and `Cc` headers
+///
+/// Empty groups are formatted as `name:;`, which can be parsed back.
+///
+/// [RFC 5322, section 3.4](https://tools.ietf.org/html/rfc5322#section-3.4)
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AddressList(pub Vec<Address>);
+
+impl AddressList {
+    /// Splits a list into its mailboxes and groups, as the parser of `email` does not
+    /// accept a comma after a group
+    fn split(list: &str) -> Vec<&str> {
+        let mut addresses = vec![];
+        let mut start = 0;
+        let (mut quoted, mut escaped, mut angle, mut group) = (false, false, false, false);
+        let mut comment_depth = 0usize;
+
+        for (idx, c) in list.char_indices() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\\' => escaped = true,
+                '"' => quoted = !quoted,
+                _ if quoted => {}
+                '(' => comment_depth += 1,
+                ')' => comment_depth = comment_depth.saturating_sub(1),
+                _ if comment_depth > 0 => {}
+                '<' => angle = true,
+                '>' => angle = false,
+                _ if angle => {}
+                ':' => group = true,
+                ';' => group = false,
+                ',' if !group => {
+                    addresses.push(&list[start..idx]);
+                    start = idx + 1;
+                }
+                _ => {}
+            }
+        }
+        addresses.push(&list[start..]);
+        addresses.retain(|address| !address.trim().is_empty());
+        addresses
+    }
+}
+
+impl FromStr for AddressList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AddressList::from_header(s.to_string())
+            .map_err(|err| Error::InvalidAddressList(err.to_string()))
+    }
+}
+
+impl FromHeader for AddressList {
+    fn from_header(value: String) -> ParsingResult<AddressList> {
+        let mut addresses = vec![];
+        for address in AddressList::split(&value) {
+            addresses.append(&mut Vec::<Address>::from_header(address.to_string())?);
+        }
+        Ok(AddressList(addresses))
+    }
+}
+
+impl ToFoldedHeader for AddressList {
+    fn to_folded_header(start_pos: usize, value: AddressList) -> ParsingResult<String> {
+        let mut header = String::new();
+        let mut line_len = start_pos;
+
+        for (idx, address) in value.0.iter().enumerate() {
+            let address = match *address {
+                Address::Group(ref name, ref members) if members.is_empty() => {
+                    format!("{}:;", name)
+                }
+                ref address => address.to_string(),
+            };
+
+            if idx > 0 {
+                header.push(',');
+                // Wrap before the address if it does not fit on the current line
+                if line_len + address.len() + 3 > MIME_LINE_LENGTH {
+                    header.push_str("\r\n\t");
+                    line_len = 0;
+                } else {
+                    header.push(' ');
+                    line_len += 2;
+                }
+            }
+            line_len += address.len();
+            header.push_str(&address);
+        }
+
+        Ok(header)
+    }
+}
+
+/// Checks that a header name only contains printable ASCII characters, except colon
+///
+/// [RFC 5322, section 2.2](https://tools.ietf.org/html/rfc5322#section-2.2)
+pub fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+}
+
+/// Checks that a header value does not contain any CR or LF character, which would allow
+/// injecting other headers
+pub fn is_valid_header_value(value: &str) -> bool {
+    !value.contains(&['\r', '\n'][..])
+}
+
+/// Checks that a formatted header only contains line breaks used for folding, i.e. CRLF
+/// followed by a space or a tab
+///
+/// [RFC 5322, section 2.2.3](https://tools.ietf.org/html/rfc5322#section-2.2.3)
+pub fn is_valid_folded_header(header: &str) -> bool {
+    let bytes = header.as_bytes();
+    bytes.iter().enumerate().all(|(idx, byte)| match *byte {
+        b'\r' => {
+            bytes.get(idx + 1) == Some(&b'\n')
+                && (bytes.get(idx + 2) == Some(&b' ') || bytes.get(idx + 2) == Some(&b'\t'))
+        }
+        b'\n' => idx > 0 && bytes[idx - 1] == b'\r',
+        _ => true,
+    })
+}
+
+macro_rules! text_header {
+    ($(#[$doc:meta])* $type_name:ident, $header_name:expr) => {
+        $(#[$doc])*
+        #[derive(PartialEq, Eq, Clone, Debug)]
+        pub struct $type_name(pub String);
+
+        impl $type_name {
+            /// Name of the header
+            pub const NAME: &'static str = $header_name;
+        }
+
+        impl FromHeader for $type_name {
+            fn from_header(value: String) -> ParsingResult<$type_name> {
+                String::from_header(value).map($type_name)
+            }
+        }
+
+        impl ToHeader for $type_name {
+            fn to_header(value: $type_name) -> ParsingResult<String> {
+                Ok(utf8_b::encode(&value.0))
+            }
+        }
+    };
+}
+
+text_header!(
+    /// `Organization` header, the organization to which the sender belongs
+    ///
+    /// [RFC 1036, section 2.2.8](https://tools.ietf.org/html/rfc1036#section-2.2.8)
+    Organization,
+    "Organization"
+);
+text_header!(
+    /// `User-Agent` header, the software used to create the message
+    ///
+    /// [RFC 5536, section 3.2.13](https://tools.ietf.org/html/rfc5536#section-3.2.13)
+    UserAgent,
+    "User-Agent"
+);
+text_header!(
+    /// `X-Mailer` header, the software used to create the message
+    ///
+    /// [RFC 2076, section 3.4](https://tools.ietf.org/html/rfc2076#section-3.4)
+    XMailer,
+    "X-Mailer"
+);
+text_header!(
+    /// `Comments` header, additional comments on the message
+    ///
+    /// [RFC 5322, section 3.6.5](https://tools.ietf.org/html/rfc5322#section-3.6.5)
+    Comments,
+    "Comments"
+);
+text_header!(
+    /// `Content-Description` header, a description of the content of a part
+    ///
+    /// [RFC 2045, section 8](https://tools.ietf.org/html/rfc2045#section-8)
+    ContentDescription,
+    "Content-Description"
+);
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_valid_folded_header, is_valid_header_name, is_valid_header_value, AddressList, Comments,
+        ContentDisposition, ContentId, ContentLanguage, Organization,
+    };
+    use email::{Address, Header, Mailbox};
+
+    #[test]
+    fn test_content_language_single() {
+        let language = ContentLanguage::new(vec!["en-US"]).unwrap();
+        let header = Header::new_with_value("Content-Language".to_string(), language).unwrap();
+        assert_eq!(header.to_string(), "Content-Language: en-US");
+
+        let parsed = header.get_value::<ContentLanguage>().unwrap();
+        assert_eq!(parsed.tags(), &["en-US".to_string()]);
+    }
+
+    #[test]
+    fn test_content_language_multiple() {
+        let language = "en-US, fr,de-CH-1901".parse::<ContentLanguage>().unwrap();
+        assert_eq!(language.tags(), &["en-US", "fr", "de-CH-1901"]);
+
+        let header = Header::new_with_value("Content-Language".to_string(), language).unwrap();
+        assert_eq!(
+            header.to_string(),
+            "Content-Language: en-US, fr, de-CH-1901"
+        );
+    }
+
+    #[test]
+    fn test_content_language_invalid() {
+        assert!("".parse::<ContentLanguage>().is_err());
+        assert!("en,".parse::<ContentLanguage>().is_err());
+        assert!("en US".parse::<ContentLanguage>().is_err());
+        assert!("1en".parse::<ContentLanguage>().is_err());
+        assert!("en--US".parse::<ContentLanguage>().is_err());
+        assert!("toolongtag".parse::<ContentLanguage>().is_err());
+        assert!(ContentLanguage::new(Vec::<String>::new()).is_err());
+    }
+
+    #[test]
+    fn test_text_headers() {
+        let header = Header::new_with_value(
+            Organization::NAME.to_string(),
+            Organization("Société Générale".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            header.to_string(),
+            "Organization: =?utf-8?b?U29jacOpdMOpIEfDqW7DqXJhbGU=?="
+        );
+        assert_eq!(
+            header.get_value::<Organization>().unwrap(),
+            Organization("Société Générale".to_string())
+        );
+
+        let header = Header::new_with_value(
+            Comments::NAME.to_string(),
+            Comments("Sent from my desk".to_string()),
+        )
+        .unwrap();
+        assert_eq!(header.to_string(), "Comments: Sent from my desk");
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        let header = |disposition| {
+            Header::new_with_value("Content-Disposition".to_string(), disposition)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            header(ContentDisposition::inline()),
+            "Content-Disposition: inline"
+        );
+        assert_eq!(
+            header(ContentDisposition::attachment("report 2019.pdf")),
+            "Content-Disposition: attachment; filename=\"report 2019.pdf\""
+        );
+        assert_eq!(
+            header(ContentDisposition::attachment("a \"quoted\" \\ name")),
+            "Content-Disposition: attachment; filename=\"a \\\"quoted\\\" \\\\ name\""
+        );
+        assert_eq!(
+            header(ContentDisposition::attachment("Отчёт.pdf")),
+            "Content-Disposition: attachment; \
+             filename*=UTF-8''%D0%9E%D1%82%D1%87%D1%91%D1%82.pdf"
+        );
+        assert_eq!(
+            header(ContentDisposition::attachment("100% done\t.txt")),
+            "Content-Disposition: attachment; filename*=UTF-8''100%25%20done%09.txt"
+        );
+    }
+
+    #[test]
+    fn test_content_disposition_dates() {
+        let created = time::at_utc(time::Timespec::new(855_764_991, 0));
+        let modified = time::at_utc(time::Timespec::new(1_577_880_000, 0));
+        let header = Header::new_with_value(
+            "Content-Disposition".to_string(),
+            ContentDisposition::attachment("notes.txt")
+                .creation_date(created)
+                .modification_date(modified),
+        )
+        .unwrap()
+        .to_string();
+        assert_eq!(
+            header,
+            "Content-Disposition: attachment; filename=\"notes.txt\"; \
+             creation-date=\"Wed, 12 Feb 1997 16:29:51 -0000\"; \
+             modification-date=\"Wed, 01 Jan 2020 12:00:00 -0000\""
+        );
+    }
+
+    #[test]
+    fn test_content_disposition_continuation() {
+        let header = Header::new_with_value(
+            "Content-Disposition".to_string(),
+            ContentDisposition::attachment("Годовой отчёт.pdf"),
+        )
+        .unwrap()
+        .to_string();
+        assert_eq!(
+            header,
+            "Content-Disposition: attachment; \
+             filename*0*=UTF-8''%D0%93%D0%BE%D0%B4%D0%BE%D0%B2%D0%BE%D0%B9%20%D0%BE%D1%82;\r\n \
+             filename*1*=%D1%87%D1%91%D1%82.pdf"
+        );
+        assert!(is_valid_folded_header(&header));
+    }
+
+    #[test]
+    fn test_address_list() {
+        let list = "Team: alice@example.com, \"Bob\" <bob@example.org>;, carol@example.net"
+            .parse::<AddressList>()
+            .unwrap();
+        assert_eq!(
+            list.0,
+            vec![
+                Address::new_group(
+                    "Team".to_string(),
+                    vec![
+                        Mailbox::new("alice@example.com".to_string()),
+                        Mailbox::new_with_name("Bob".to_string(), "bob@example.org".to_string()),
+                    ]
+                ),
+                Address::new_mailbox("carol@example.net".to_string()),
+            ]
+        );
+
+        let header = Header::new_with_value("To".to_string(), list.clone()).unwrap();
+        assert_eq!(
+            header.to_string(),
+            "To: Team: <alice@example.com>, \"Bob\" <bob@example.org>;, <carol@example.net>"
+        );
+        assert_eq!(header.get_value::<AddressList>().unwrap(), list);
+
+        let list = "\"Doe, John\" <john@example.com>, Team: <a@example.com>, b@example.com;"
+            .parse::<AddressList>()
+            .unwrap();
+        assert_eq!(list.0.len(), 2);
+        assert!("Team: alice;".parse::<AddressList>().is_err());
+    }
+
+    #[test]
+    fn test_address_list_empty_group() {
+        let list = "undisclosed-recipients:;".parse::<AddressList>().unwrap();
+        assert_eq!(
+            list.0,
+            vec![Address::new_group(
+                "undisclosed-recipients".to_string(),
+                vec![]
+            )]
+        );
+
+        let header = Header::new_with_value("To".to_string(), list.clone()).unwrap();
+        assert_eq!(header.to_string(), "To: undisclosed-recipients:;");
+        assert_eq!(header.get_value::<AddressList>().unwrap(), list);
+    }
+
+    #[test]
+    fn test_address_list_folding() {
+        let list = AddressList(
+            (0..4)
+                .map(|idx| Address::new_mailbox(format!("recipient{}@example.com", idx)))
+                .collect(),
+        );
+        let header = Header::new_with_value("To".to_string(), list).unwrap();
+        assert_eq!(
+            header.to_string(),
+            "To: <recipient0@example.com>, <recipient1@example.com>,\r\n\t\
+             <recipient2@example.com>, <recipient3@example.com>"
+        );
+    }
+
+    #[test]
+    fn test_header_validation() {
+        assert!(is_valid_header_name("X-Mailer"));
+        assert!(!is_valid_header_name(""));
+        assert!(!is_valid_header_name("X Mailer"));
+        assert!(!is_valid_header_name("X-Mailer:"));
+        assert!(!is_valid_header_name("X-Mailér"));
+
+        assert!(is_valid_header_value("lettre 0.9"));
+        assert!(is_valid_header_value(""));
+        assert!(!is_valid_header_value("value\r\nBcc: attacker@example.com"));
+        assert!(!is_valid_header_value("value\nBcc: attacker@example.com"));
+        assert!(!is_valid_header_value("value\r"));
+    }
+
+    #[test]
+    fn test_folded_header_validation() {
+        assert!(is_valid_folded_header("To: <a@localhost>"));
+        assert!(is_valid_folded_header(
+            "To: <a@localhost>,\r\n\t<b@localhost>"
+        ));
+        assert!(is_valid_folded_header("Subject: a\r\n b"));
+        assert!(!is_valid_folded_header(
+            "Subject: a\r\nBcc: attacker@example.com"
+        ));
+        assert!(!is_valid_folded_header(
+            "Subject: a\nBcc: attacker@example.com"
+        ));
+        assert!(!is_valid_folded_header(
+            "Subject: a\rBcc: attacker@example.com"
+        ));
+        assert!(!is_valid_folded_header("Subject: a\r\n"));
+    }
+
+    #[test]
+    fn test_content_id() {
+        let id = ContentId::new("<logo@example.com>");
+        assert_eq!(id, ContentId::new("logo@example.com"));
+        let header = Header::new_with_value("Content-ID".to_string(), id.clone()).unwrap();
+        assert_eq!(header.to_string(), "Content-ID: <logo@example.com>");
+        assert_eq!(id.url(), "cid:logo@example.com");
+
+        assert_eq!(ContentId::new("a b%").url(), "cid:a%20b%25");
+    }
+
+    #[test]
+    fn test_content_id_generate() {
+        let id = ContentId::generate();
+        assert_ne!(id, ContentId::generate());
+        let header = Header::new_with_value("Content-ID".to_string(), id.clone()).unwrap();
+        assert_eq!(
+            header.to_string(),
+            format!("Content-ID: <{}>", &id.url()["cid:".len()..])
+        );
+    }
+}
@@ -1,15 +1,31 @@
-use crate::{error::Error as LettreError, EmailAddress, Envelope, SendableEmail};
+use crate::{error::Error as LettreError, EmailAddress, Envelope, RawMessage, SendableEmail};
+use email::HeaderMap;
 pub use email::{Address, Header, Mailbox, MimeMessage, MimeMultipartType};
+use encoder::BodyEncoder;
 use error::Error;
+use header::{
+    is_valid_folded_header, is_valid_header_name, is_valid_header_value, AddressList, Comments,
+    ContentDisposition, ContentLanguage, ContentTransferEncoding, DispositionType, Organization,
+    Received, UserAgent, XMailer,
+};
 use mime::Mime;
+use part::{MultiPart, SinglePart};
 use std::ffi::OsStr;
+use std::fmt::{self, Debug, Formatter};
 use std::fs;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 use time::{now, Tm};
 use uuid::Uuid;
 
+pub mod encoder;
 pub mod error;
+pub mod flowed;
+pub mod header;
+pub mod part;
+pub mod quoted_printable;
+pub mod utf8_b;
 
 impl From<EmailAddress> for email::Mailbox {
     fn from(addr: EmailAddress) -> Self {
@@ -30,9 +46,144 @@ impl Default for PartBuilder {
     }
 }
 
+/// Parses a mailbox, an address with an optional display name like
+/// `"John Doe" <john@example.com>`, and validates its address
+pub fn parse_mailbox(mailbox: &str) -> Result<Mailbox, Error> {
+    let mailbox =
+        Mailbox::from_str(mailbox).map_err(|err| Error::InvalidAddressList(err.to_string()))?;
+    EmailAddress::parse(&mailbox.address)
+        .map_err(|err| Error::Envelope(LettreError::InvalidEmailAddress(err)))?;
+    Ok(mailbox)
+}
+
 /// Represents a message id
 pub type MessageId = String;
 
+/// Type of a `multipart/report` message
+///
+/// [RFC 6522](https://tools.ietf.org/html/rfc6522)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ReportType {
+    /// Delivery status notification
+    ///
+    /// [RFC 3464](https://tools.ietf.org/html/rfc3464)
+    DeliveryStatus,
+    /// Message disposition notification
+    ///
+    /// [RFC 8098](https://tools.ietf.org/html/rfc8098)
+    DispositionNotification,
+}
+
+impl ReportType {
+    /// Content type of the machine-readable part
+    fn content_type(self) -> &'static str {
+        match self {
+            ReportType::DeliveryStatus => "message/delivery-status",
+            ReportType::DispositionNotification => "message/disposition-notification",
+        }
+    }
+}
+
+impl fmt::Display for ReportType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ReportType::DeliveryStatus => "delivery-status",
+            ReportType::DispositionNotification => "disposition-notification",
+        })
+    }
+}
+
+/// Mailer identifier to opt in to with `EmailBuilder::x_mailer`, without the version of lettre
+pub const DEFAULT_X_MAILER: &str = "lettre";
+
+/// Number of boundaries generated for a part before giving up, if they all appear in
+/// its content
+const BOUNDARY_ATTEMPTS: usize = 10;
+
+/// Order of the main headers of a built message
+///
+/// Trace headers (`Return-Path`, `Received` and `Resent-*`) come first, then these headers,
+/// then the other headers in the order they were added, then the MIME headers.
+const HEADER_ORDER: &[&str] = &[
+    "Date",
+    "Subject",
+    "Sender",
+    "To",
+    "From",
+    "Cc",
+    "Reply-To",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+];
+
+/// Generator of the boundaries of multipart parts
+#[derive(Clone)]
+pub struct BoundaryGenerator(Arc<dyn Fn() -> String + Send + Sync>);
+
+impl BoundaryGenerator {
+    /// Creates a generator from a function
+    pub fn new<F: Fn() -> String + Send + Sync + 'static>(generator: F) -> BoundaryGenerator {
+        BoundaryGenerator(Arc::new(generator))
+    }
+
+    /// Checks that a boundary can be used unquoted in the `Content-Type` header
+    ///
+    /// [RFC 2046, section 5.1.1](https://tools.ietf.org/html/rfc2046#section-5.1.1)
+    fn is_valid(boundary: &str) -> bool {
+        !boundary.is_empty()
+            && boundary.len() <= 70
+            && boundary
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "'+_-.".contains(c))
+    }
+
+    /// Sets the boundaries of a part and of its children, regenerating them when they
+    /// appear in the content they delimit
+    fn apply(&self, part: &mut MimeMessage) -> Result<(), Error> {
+        for child in &mut part.children {
+            self.apply(child)?;
+        }
+        if part.message_type.is_none() {
+            return Ok(());
+        }
+
+        let children = part
+            .children
+            .iter()
+            .map(MimeMessage::as_string)
+            .collect::<Vec<String>>();
+        let mut boundary = String::new();
+        for _ in 0..BOUNDARY_ATTEMPTS {
+            boundary = (self.0)();
+            if !BoundaryGenerator::is_valid(&boundary) {
+                return Err(Error::InvalidBoundary(boundary));
+            }
+            if !part.body.contains(&boundary)
+                && !children.iter().any(|child| child.contains(&boundary))
+            {
+                replace_boundary(part, boundary);
+                return Ok(());
+            }
+        }
+        Err(Error::InvalidBoundary(boundary))
+    }
+}
+
+impl Debug for BoundaryGenerator {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("BoundaryGenerator")
+    }
+}
+
+impl PartialEq for BoundaryGenerator {
+    fn eq(&self, other: &BoundaryGenerator) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for BoundaryGenerator {}
+
 /// Builds an `Email` structure
 #[derive(PartialEq, Eq, Clone, Debug, Default)]
 pub struct EmailBuilder {
@@ -60,10 +211,50 @@ pub struct EmailBuilder {
     date_issued: bool,
     /// Message-ID
     message_id: Option<String>,
+    /// Do not add a `Date` header when none is set
+    without_date: bool,
+    /// Do not add a `Message-ID` header when none is set
+    without_message_id: bool,
+    /// The `Resent-*` blocks, most recent first
+    resent: Vec<ResentBlock>,
+    /// The `Received` traces, in the order of the hops
+    received: Vec<Received>,
+    /// Generator of the multipart boundaries, random ones are used if not set
+    boundary_generator: Option<BoundaryGenerator>,
+    /// Domain of the generated Message-ID
+    message_id_domain: Option<String>,
+    /// Keep the line endings of the text bodies as they are
+    raw_line_endings: bool,
+    /// Type of the report, if the message is a `multipart/report`
+    report_type: Option<ReportType>,
+    /// Encoder of the attachments
+    body_encoder: BodyEncoder,
+}
+
+/// A block of `Resent-*` headers, added when a message is reintroduced into the
+/// transport system, for example when forwarding or redistributing it
+///
+/// [RFC 5322, section 3.6.6](https://tools.ietf.org/html/rfc5322#section-3.6.6)
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct ResentBlock {
+    /// The `Resent-Date`, the time of the build if not set
+    date: Option<Tm>,
+    /// The `Resent-From` addresses
+    from: Vec<Address>,
+    /// The `Resent-To` addresses
+    to: Vec<Address>,
+    /// The `Resent-Cc` addresses
+    cc: Vec<Address>,
+    /// The `Resent-Message-ID`
+    message_id: Option<MessageId>,
 }
 
 /// Simple email representation
+///
+/// With the `serde-impls` feature, a built email can be stored and restored later, keeping
+/// the formatted message unchanged.
 #[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
 pub struct Email {
     /// Message
     message: Vec<u8>,
@@ -71,6 +262,35 @@ pub struct Email {
     envelope: Envelope,
     /// Message-ID
     message_id: String,
+    /// Tree of the MIME parts
+    #[cfg_attr(feature = "serde-impls", serde(default))]
+    structure: PartStructure,
+}
+
+/// Structure of a MIME part of a built email, without its headers and body
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartStructure {
+    /// Value of the `Content-Type` header
+    pub content_type: Option<String>,
+    /// Boundary between the children, for multipart parts
+    pub boundary: Option<String>,
+    /// Child parts
+    pub children: Vec<PartStructure>,
+}
+
+impl PartStructure {
+    fn new(part: &MimeMessage) -> PartStructure {
+        PartStructure {
+            content_type: part.headers.get_value("Content-Type".to_string()).ok(),
+            boundary: if part.children.is_empty() {
+                None
+            } else {
+                Some(part.boundary.clone())
+            },
+            children: part.children.iter().map(PartStructure::new).collect(),
+        }
+    }
 }
 
 impl Into<SendableEmail> for Email {
@@ -83,11 +303,73 @@ impl Into<SendableEmail> for Email {
     }
 }
 
+impl<'a> From<&'a Email> for SendableEmail {
+    fn from(email: &'a Email) -> SendableEmail {
+        email.sendable_with_envelope(email.envelope.clone())
+    }
+}
+
 impl Email {
     /// Creates a new email builder
     pub fn builder() -> EmailBuilder {
         EmailBuilder::new()
     }
+
+    /// Returns the message as it is sent on the wire
+    pub fn formatted(&self) -> Vec<u8> {
+        self.message.clone()
+    }
+
+    /// Converts the email into a `RawMessage`, to send the formatted message several times
+    /// without copying it
+    pub fn into_raw(self) -> RawMessage {
+        RawMessage::new(self.envelope, self.message_id, self.message)
+    }
+
+    /// Returns the message with its body in the canonical form used for DKIM signatures
+    ///
+    /// The trailing empty lines of the body are removed, and it ends with a single line
+    /// ending, an empty body being only a line ending. The headers are unchanged.
+    ///
+    /// [RFC 6376, section 3.4.3](https://tools.ietf.org/html/rfc6376#section-3.4.3)
+    pub fn formatted_for_signing(&self) -> Vec<u8> {
+        let split = self
+            .message
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|idx| idx + 4)
+            .unwrap_or_else(|| self.message.len());
+        let (headers, mut body) = self.message.split_at(split);
+        while body.ends_with(b"\r\n") {
+            body = &body[..body.len() - 2];
+        }
+        let mut message = Vec::with_capacity(headers.len() + body.len() + 2);
+        message.extend_from_slice(headers);
+        message.extend_from_slice(body);
+        message.extend_from_slice(b"\r\n");
+        message
+    }
+
+    /// Returns the tree of the MIME parts, to check the structure without parsing the
+    /// formatted message
+    ///
+    /// Emails stored before this was recorded are restored with an empty structure.
+    pub fn structure(&self) -> &PartStructure {
+        &self.structure
+    }
+
+    /// Returns the size of the formatted message in bytes, without copying it
+    pub fn formatted_size(&self) -> usize {
+        self.message.len()
+    }
+
+    /// Returns the message ready to be sent with another envelope, for example with a
+    /// return path specific to a recipient
+    ///
+    /// The message itself is not modified.
+    pub fn sendable_with_envelope(&self, envelope: Envelope) -> SendableEmail {
+        SendableEmail::new(envelope, self.message_id.clone(), self.message.clone())
+    }
 }
 
 impl PartBuilder {
@@ -104,6 +386,25 @@ impl PartBuilder {
         self
     }
 
+    /// Gets all the headers with the given name, in the order they were added
+    ///
+    /// Header names are compared case-insensitively.
+    pub fn headers(&self, name: &str) -> Vec<&Header> {
+        self.message
+            .headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case(name))
+            .collect()
+    }
+
+    /// Removes all the headers with the given name, and returns them in the order they
+    /// were added
+    ///
+    /// Header names are compared case-insensitively.
+    pub fn remove_headers(&mut self, name: &str) -> Vec<Header> {
+        remove_headers(&mut self.message, name)
+    }
+
     /// Sets the body
     pub fn body<S: Into<String>>(mut self, body: S) -> PartBuilder {
         self.message.body = body.into();
@@ -121,19 +422,100 @@ impl PartBuilder {
         self.header(("Content-Type", content_type.to_string()))
     }
 
+    /// Adds a `Content-Language` header
+    pub fn content_language(self, language: ContentLanguage) -> PartBuilder {
+        // The unwrap is fine because the conversion for ContentLanguage never errs.
+        self.header(Header::new_with_value("Content-Language".into(), language).unwrap())
+    }
+
     /// Adds a child part
     pub fn child(mut self, child: MimeMessage) -> PartBuilder {
         self.message.children.push(child);
         self
     }
 
+    /// Replaces the body, the children and the multipart type with the ones of another
+    /// part, and adds its headers
+    fn set_content(&mut self, part: MimeMessage) {
+        for header in part.headers.iter() {
+            self.message.headers.insert(header.clone());
+        }
+        self.message.body = part.body;
+        self.message.children = part.children;
+        self.message.message_type = part.message_type;
+        self.message.boundary = part.boundary;
+    }
+
     /// Gets built `MimeMessage`
+    ///
+    /// A multipart `Content-Type` is added, unless the part already has one.
     pub fn build(mut self) -> MimeMessage {
-        self.message.update_headers();
+        if !has_multipart_content_type(&self.message) {
+            self.message.update_headers();
+        }
         self.message
     }
 }
 
+impl ResentBlock {
+    /// Creates a new empty resent block
+    pub fn new() -> ResentBlock {
+        ResentBlock::default()
+    }
+
+    /// Adds a `Resent-From` address
+    pub fn from<A: Into<Mailbox>>(mut self, address: A) -> ResentBlock {
+        self.from.push(Address::Mailbox(address.into()));
+        self
+    }
+
+    /// Adds a `Resent-To` address
+    pub fn to<A: Into<Mailbox>>(mut self, address: A) -> ResentBlock {
+        self.to.push(Address::Mailbox(address.into()));
+        self
+    }
+
+    /// Adds a `Resent-Cc` address
+    pub fn cc<A: Into<Mailbox>>(mut self, address: A) -> ResentBlock {
+        self.cc.push(Address::Mailbox(address.into()));
+        self
+    }
+
+    /// Sets the `Resent-Date`
+    pub fn date(mut self, date: &Tm) -> ResentBlock {
+        self.date = Some(*date);
+        self
+    }
+
+    /// Sets the `Resent-Message-ID`
+    pub fn message_id<S: Into<String>>(mut self, id: S) -> ResentBlock {
+        self.message_id = Some(id.into());
+        self
+    }
+
+    /// Converts the block into its headers, in the order recommended by RFC 5322
+    fn into_headers(self) -> Vec<Header> {
+        // The unwraps are fine because the conversions for Vec<Address> never errs.
+        let mut headers = vec![Header::new(
+            "Resent-Date".into(),
+            Tm::rfc822z(&self.date.unwrap_or_else(now)).to_string(),
+        )];
+        if !self.from.is_empty() {
+            headers.push(Header::new_with_value("Resent-From".into(), self.from).unwrap());
+        }
+        if !self.to.is_empty() {
+            headers.push(Header::new_with_value("Resent-To".into(), self.to).unwrap());
+        }
+        if !self.cc.is_empty() {
+            headers.push(Header::new_with_value("Resent-Cc".into(), self.cc).unwrap());
+        }
+        if let Some(id) = self.message_id {
+            headers.push(Header::new("Resent-Message-ID".into(), id));
+        }
+        headers
+    }
+}
+
 impl EmailBuilder {
     /// Creates a new empty email
     pub fn new() -> EmailBuilder {
@@ -150,6 +532,15 @@ impl EmailBuilder {
             envelope: None,
             date_issued: false,
             message_id: None,
+            without_date: false,
+            without_message_id: false,
+            resent: vec![],
+            received: vec![],
+            boundary_generator: None,
+            message_id_domain: None,
+            raw_line_endings: false,
+            report_type: None,
+            body_encoder: BodyEncoder::new(),
         }
     }
 
@@ -165,6 +556,35 @@ impl EmailBuilder {
         self
     }
 
+    /// Gets all the headers with the given name, in the order they were added
+    ///
+    /// Only the headers already added are returned, headers computed when building
+    /// the email (like `To` or `From`) are not.
+    pub fn headers(&self, name: &str) -> Vec<&Header> {
+        self.message.headers(name)
+    }
+
+    /// Removes all the headers with the given name, and returns them in the order they
+    /// were added
+    pub fn remove_headers(&mut self, name: &str) -> Vec<Header> {
+        self.message.remove_headers(name)
+    }
+
+    /// Adds a header from its raw name and value
+    ///
+    /// The name and the value are checked to prevent header injection: the name can only
+    /// contain printable ASCII characters, and the value can not contain CR or LF.
+    /// Calling it several times with the same name adds several headers.
+    pub fn header_raw(self, name: &str, value: &str) -> Result<EmailBuilder, Error> {
+        if !is_valid_header_name(name) {
+            return Err(Error::InvalidHeaderName(name.to_string()));
+        }
+        if !is_valid_header_value(value) {
+            return Err(Error::InvalidHeaderValue(name.to_string()));
+        }
+        Ok(self.header((name, value)))
+    }
+
     /// Adds a `From` header and stores the sender address
     pub fn from<A: Into<Mailbox>>(mut self, address: A) -> EmailBuilder {
         let mailbox = address.into();
@@ -186,7 +606,38 @@ impl EmailBuilder {
         self
     }
 
-    /// Adds a `Bcc` header and stores the recipient address
+    /// Adds a group to the `To` header and stores the addresses of its members as
+    /// recipients
+    ///
+    /// An empty group, like `undisclosed-recipients:;`, does not add any recipient.
+    pub fn to_group<S: Into<String>>(mut self, name: S, members: Vec<Mailbox>) -> EmailBuilder {
+        self.to.push(Address::Group(name.into(), members));
+        self
+    }
+
+    /// Adds a group to the `Cc` header and stores the addresses of its members as
+    /// recipients
+    pub fn cc_group<S: Into<String>>(mut self, name: S, members: Vec<Mailbox>) -> EmailBuilder {
+        self.cc.push(Address::Group(name.into(), members));
+        self
+    }
+
+    /// Adds the mailboxes and groups of an address list to the `To` header
+    pub fn to_list(mut self, list: &str) -> Result<EmailBuilder, Error> {
+        self.to.append(&mut list.parse::<AddressList>()?.0);
+        Ok(self)
+    }
+
+    /// Adds the mailboxes and groups of an address list to the `Cc` header
+    pub fn cc_list(mut self, list: &str) -> Result<EmailBuilder, Error> {
+        self.cc.append(&mut list.parse::<AddressList>()?.0);
+        Ok(self)
+    }
+
+    /// Adds a blind carbon copy recipient
+    ///
+    /// The address is only added to the envelope, as no `Bcc` header is ever included in
+    /// the built message, even when added with `header`.
     pub fn bcc<A: Into<Mailbox>>(mut self, address: A) -> EmailBuilder {
         let mailbox = address.into();
         self.bcc.push(Address::Mailbox(mailbox));
@@ -220,8 +671,51 @@ impl EmailBuilder {
     }
 
     /// Adds a `Subject` header
+    ///
+    /// Subjects containing non-ASCII or control characters are encoded.
     pub fn subject<S: Into<String>>(mut self, subject: S) -> EmailBuilder {
-        self.message = self.message.header(("Subject".to_string(), subject.into()));
+        self.message = self
+            .message
+            .header(("Subject", utf8_b::encode(&subject.into())));
+        self
+    }
+
+    /// Adds an `Organization` header
+    pub fn organization<S: Into<String>>(self, organization: S) -> EmailBuilder {
+        // The unwrap is fine because the conversion for Organization never errs.
+        self.header(
+            Header::new_with_value(Organization::NAME.into(), Organization(organization.into()))
+                .unwrap(),
+        )
+    }
+
+    /// Adds a `User-Agent` header, identifying the software that created the message
+    pub fn user_agent<S: Into<String>>(self, user_agent: S) -> EmailBuilder {
+        // The unwrap is fine because the conversion for UserAgent never errs.
+        self.header(
+            Header::new_with_value(UserAgent::NAME.into(), UserAgent(user_agent.into())).unwrap(),
+        )
+    }
+
+    /// Adds an `X-Mailer` header, identifying the software that created the message
+    ///
+    /// No mailer is set by default. `DEFAULT_X_MAILER` identifies lettre.
+    pub fn x_mailer<S: Into<String>>(self, mailer: S) -> EmailBuilder {
+        // The unwrap is fine because the conversion for XMailer never errs.
+        self.header(Header::new_with_value(XMailer::NAME.into(), XMailer(mailer.into())).unwrap())
+    }
+
+    /// Adds a `Comments` header
+    pub fn comments<S: Into<String>>(self, comments: S) -> EmailBuilder {
+        // The unwrap is fine because the conversion for Comments never errs.
+        self.header(
+            Header::new_with_value(Comments::NAME.into(), Comments(comments.into())).unwrap(),
+        )
+    }
+
+    /// Adds a `Content-Language` header for the whole message
+    pub fn content_language(mut self, language: ContentLanguage) -> EmailBuilder {
+        self.message = self.message.content_language(language);
         self
     }
 
@@ -232,6 +726,32 @@ impl EmailBuilder {
         self
     }
 
+    /// Does not add the current date as `Date` header when no date is set
+    ///
+    /// This is useful when the header is added later, for example by a submission server.
+    pub fn without_date(mut self) -> EmailBuilder {
+        self.without_date = true;
+        self
+    }
+
+    /// Adds a block of `Resent-*` headers
+    ///
+    /// The blocks are emitted at the top of the message in the order they were added,
+    /// so the most recent one should be added first.
+    pub fn resent(mut self, block: ResentBlock) -> EmailBuilder {
+        self.resent.push(block);
+        self
+    }
+
+    /// Adds a `Received` trace header
+    ///
+    /// Traces are emitted at the top of the message, the last added first, so the hops
+    /// should be added in the order they happened.
+    pub fn received(mut self, received: Received) -> EmailBuilder {
+        self.received.push(received);
+        self
+    }
+
     /// Adds an attachment to the email from a file
     ///
     /// If not specified, the filename will be extracted from the file path.
@@ -252,6 +772,30 @@ impl EmailBuilder {
         )
     }
 
+    /// Set the encoder of the attachments added afterwards, to change the length of
+    /// their encoded lines
+    pub fn body_encoder(mut self, encoder: BodyEncoder) -> EmailBuilder {
+        self.body_encoder = encoder;
+        self
+    }
+
+    /// Sets the email content to HTML and plain text alternatives, followed by attachments
+    ///
+    /// The message is a `multipart/mixed` containing the `multipart/alternative` body,
+    /// then the attachments in order, like with `alternative` and `attachment_with`.
+    pub fn alternative_with_attachments<S: Into<String>, T: Into<String>>(
+        self,
+        body_html: S,
+        body_text: T,
+        attachments: Vec<SinglePart>,
+    ) -> EmailBuilder {
+        attachments
+            .into_iter()
+            .fold(self.alternative(body_html, body_text), |builder, part| {
+                builder.attachment_with(part)
+            })
+    }
+
     /// Adds an attachment to the email from a vector of bytes.
     pub fn attachment(
         self,
@@ -259,13 +803,17 @@ impl EmailBuilder {
         filename: &str,
         content_type: &Mime,
     ) -> Result<EmailBuilder, Error> {
-        let encoded_body = base64::encode(&body);
+        let encoded_body = self.body_encoder.base64(body);
         let content = PartBuilder::new()
             .body(encoded_body)
-            .header((
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", filename),
-            ))
+            // The unwrap is fine because the conversion for ContentDisposition never errs.
+            .header(
+                Header::new_with_value(
+                    "Content-Disposition".into(),
+                    ContentDisposition::attachment(filename),
+                )
+                .unwrap(),
+            )
             .header(("Content-Type", content_type.to_string()))
             .header(("Content-Transfer-Encoding", "base64"))
             .build();
@@ -273,6 +821,52 @@ impl EmailBuilder {
         Ok(self.message_type(MimeMultipartType::Mixed).child(content))
     }
 
+    /// Adds an attachment to the email from a vector of bytes, encoded with the given
+    /// `Content-Transfer-Encoding` instead of `base64`
+    pub fn attachment_encoded(
+        self,
+        body: &[u8],
+        filename: &str,
+        content_type: &Mime,
+        encoding: ContentTransferEncoding,
+    ) -> Result<EmailBuilder, Error> {
+        let part = SinglePart::encoded_with(body, content_type, encoding, &self.body_encoder)?
+            .content_disposition(ContentDisposition::attachment(filename));
+        Ok(self.attachment_with(part))
+    }
+
+    /// Attaches another message as a `message/rfc822` part, to forward it as an attachment
+    ///
+    /// Fails if the message is not valid UTF-8, which is possible for a deserialized email,
+    /// since `message/rfc822` parts can not be encoded with `base64`.
+    pub fn attach_message(self, message: &Email) -> Result<EmailBuilder, Error> {
+        let encoding = if message.message.is_ascii() {
+            ContentTransferEncoding::SevenBit
+        } else {
+            ContentTransferEncoding::EightBit
+        };
+        let body = String::from_utf8(message.message.clone())
+            .map_err(|_| Error::InvalidBody(encoding.to_string()))?;
+        // The unwrap is fine because the type is valid.
+        let part = SinglePart::new()
+            .content_type(&"message/rfc822".parse().unwrap())
+            .content_transfer_encoding(encoding)
+            .content_disposition(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                filename: None,
+                creation_date: None,
+                modification_date: None,
+            })
+            .body(body);
+        Ok(self.attachment_with(part))
+    }
+
+    /// Adds an attachment built as a part, with its own disposition and encoding
+    pub fn attachment_with(self, part: SinglePart) -> EmailBuilder {
+        self.message_type(MimeMultipartType::Mixed)
+            .child(part.build())
+    }
+
     /// Set the message type
     pub fn message_type(mut self, message_type: MimeMultipartType) -> EmailBuilder {
         self.message = self.message.message_type(message_type);
@@ -285,6 +879,21 @@ impl EmailBuilder {
         self
     }
 
+    /// Sets the content of the email to a single part, with its headers
+    pub fn singlepart(mut self, part: SinglePart) -> EmailBuilder {
+        self.message.set_content(part.build());
+        self
+    }
+
+    /// Sets the content of the email to a tree of parts, with the headers of the root part
+    ///
+    /// Unlike `text`, `html` or `alternative`, the parts are not wrapped in a
+    /// `multipart/mixed` part.
+    pub fn multipart(mut self, part: MultiPart) -> EmailBuilder {
+        self.message.set_content(part.build());
+        self
+    }
+
     /// Sets the email body to plain text content
     pub fn text<S: Into<String>>(self, body: S) -> EmailBuilder {
         let text = PartBuilder::new()
@@ -294,6 +903,30 @@ impl EmailBuilder {
         self.child(text)
     }
 
+    /// Sets the email body to plain text content with additional `Content-Type` parameters
+    ///
+    /// See `SinglePart::plain_with_parameters`.
+    pub fn text_with_parameters(
+        self,
+        body: &str,
+        parameters: &[(&str, &str)],
+    ) -> Result<EmailBuilder, Error> {
+        let part = SinglePart::plain_with_parameters(body, parameters)?;
+        Ok(self.child(part.build()))
+    }
+
+    /// Sets the email body to plain text content in the given charset
+    ///
+    /// See `SinglePart::plain_with_charset`.
+    pub fn text_with_charset(
+        self,
+        body: &str,
+        charset: &'static encoding_rs::Encoding,
+    ) -> Result<EmailBuilder, Error> {
+        let part = SinglePart::plain_with_charset(body, charset)?;
+        Ok(self.child(part.build()))
+    }
+
     /// Sets the email body to HTML content
     pub fn html<S: Into<String>>(self, body: S) -> EmailBuilder {
         let html = PartBuilder::new()
@@ -328,6 +961,49 @@ impl EmailBuilder {
             .child(alternate.build())
     }
 
+    /// Sets the email content to a `multipart/report`, like a bounce or a read receipt
+    ///
+    /// The report contains a human-readable explanation, the machine-readable status and,
+    /// optionally, the original message.
+    pub fn report<S: Into<String>, T: Into<String>>(
+        mut self,
+        report_type: ReportType,
+        text: S,
+        status: T,
+        original: Option<&str>,
+    ) -> EmailBuilder {
+        self.report_type = Some(report_type);
+        let text = PartBuilder::new()
+            .body(text)
+            .header(("Content-Type", mime::TEXT_PLAIN_UTF_8.to_string()))
+            .build();
+        let status = PartBuilder::new()
+            .body(status)
+            .header(("Content-Type", report_type.content_type()))
+            .build();
+        let mut builder = self
+            .message_type(MimeMultipartType::Mixed)
+            .child(text)
+            .child(status);
+        if let Some(original) = original {
+            let original = PartBuilder::new()
+                .body(original)
+                .header(("Content-Type", "message/rfc822"))
+                .build();
+            builder = builder.child(original);
+        }
+        builder
+    }
+
+    /// Sets the domain of the generated `Message-ID`, which is the domain of the author
+    /// by default
+    ///
+    /// It has no effect if the `Message-ID` is set with `message_id`.
+    pub fn message_id_domain<S: Into<String>>(mut self, domain: S) -> EmailBuilder {
+        self.message_id_domain = Some(domain.into());
+        self
+    }
+
     /// Sets the `Message-ID` header
     pub fn message_id<S: Clone + Into<String>>(mut self, id: S) -> EmailBuilder {
         self.message = self.message.header(("Message-ID", id.clone()));
@@ -335,6 +1011,35 @@ impl EmailBuilder {
         self
     }
 
+    /// Does not add a generated `Message-ID` header when none is set
+    ///
+    /// A generated id is still used to identify the email in the transports.
+    pub fn without_message_id(mut self) -> EmailBuilder {
+        self.without_message_id = true;
+        self
+    }
+
+    /// Sets the generator of the boundaries of all the multipart parts
+    ///
+    /// This is mostly useful to get reproducible messages, for example in tests. Generated
+    /// boundaries must only contain alphanumeric characters and `'+_-.`, and are generated
+    /// again if they appear in the content of the part.
+    pub fn boundary_generator<F: Fn() -> String + Send + Sync + 'static>(
+        mut self,
+        generator: F,
+    ) -> EmailBuilder {
+        self.boundary_generator = Some(BoundaryGenerator::new(generator));
+        self
+    }
+
+    /// Converts the lone `\n` and `\r` of the bodies to `\r\n`, enabled by default
+    ///
+    /// Parts encoded with `base64` or `binary` are never modified.
+    pub fn normalize_line_endings(mut self, enabled: bool) -> EmailBuilder {
+        self.raw_line_endings = !enabled;
+        self
+    }
+
     /// Sets the envelope for manual destination control
     /// If this function is not called, the envelope will be calculated
     /// from the "to" and "cc" addresses you set.
@@ -346,22 +1051,108 @@ impl EmailBuilder {
     /// Only builds the body, this can be used to encrypt or sign
     /// using S/MIME
     pub fn build_body(self) -> Result<Vec<u8>, Error> {
-        Ok(self.message.build().as_string().into_bytes())
+        let mut message = self.message.build();
+        if !self.raw_line_endings {
+            normalize_line_endings(&mut message);
+        }
+        if let Some(generator) = self.boundary_generator {
+            generator.apply(&mut message)?;
+        }
+        if let Some(report_type) = self.report_type {
+            set_report_content_type(&mut message, report_type);
+        }
+        check_headers(&message)?;
+        Ok(message.as_string().into_bytes())
     }
 
-    /// Builds the Email
-    pub fn build(mut self) -> Result<Email, Error> {
-        // If there are multiple addresses in "From", the "Sender" is required.
-        if self.from.len() >= 2 && self.sender.is_none() {
-            // So, we must find something to put as Sender.
-            for possible_sender in &self.from {
-                // Only a mailbox can be used as sender, not Address::Group.
-                if let Address::Mailbox(ref mbx) = *possible_sender {
-                    self.sender = Some(mbx.clone());
-                    break;
+    /// Computes the envelope of the email, without building the message
+    ///
+    /// It is the envelope set with `envelope`, or the one calculated from the `From`,
+    /// `Sender`, `To`, `Cc` and `Bcc` addresses.
+    pub fn build_envelope(&self) -> Result<Envelope, Error> {
+        if let Some(ref envelope) = self.envelope {
+            return Ok(envelope.clone());
+        }
+
+        let mut bcc = self.bcc.clone();
+        for header in self.headers("Bcc") {
+            let value = header
+                .get_value::<String>()
+                .map_err(|err| Error::InvalidAddressList(err.to_string()))?;
+            bcc.append(&mut value.parse::<AddressList>()?.0);
+        }
+        // With several authors, the first mailbox is the sender
+        let sender = self.sender.clone().or_else(|| {
+            if self.from.len() < 2 {
+                return None;
+            }
+            self.from.iter().find_map(|address| match *address {
+                Address::Mailbox(ref mailbox) => Some(mailbox.clone()),
+                Address::Group(..) => None,
+            })
+        });
+
+        let mut to = vec![];
+        // add all receivers in to_header and cc_header
+        for receiver in self.to.iter().chain(self.cc.iter()).chain(bcc.iter()) {
+            match *receiver {
+                Address::Mailbox(ref m) => to.push(EmailAddress::from_str(&m.address)?),
+                Address::Group(_, ref ms) => {
+                    for m in ms.iter() {
+                        to.push(EmailAddress::from_str(&m.address.clone())?);
+                    }
                 }
             }
-            // Address::Group is not yet supported, so the line below will never panic.
+        }
+        let from = Some(EmailAddress::from_str(&match sender {
+            Some(x) => Ok(x.address), // if we have a sender_header, use it
+            None => {
+                // use a from header
+                debug_assert!(self.from.len() <= 1); // else we'd have sender_header
+                match self.from.first() {
+                    Some(a) => match *a {
+                        // if we have a from header
+                        Address::Mailbox(ref mailbox) => Ok(mailbox.address.clone()), // use it
+                        Address::Group(_, ref mailbox_list) => match mailbox_list.first() {
+                            // if it's an author group, use the first author
+                            Some(mailbox) => Ok(mailbox.address.clone()),
+                            // for an empty author group (the rarest of the rare cases)
+                            None => Err(Error::Envelope(LettreError::MissingFrom)), // empty envelope sender
+                        },
+                    },
+                    // if we don't have a from header
+                    None => Err(Error::Envelope(LettreError::MissingFrom)), // empty envelope sender
+                }
+            }
+        }?)?);
+        Ok(Envelope::new(from, to)?)
+    }
+
+    /// Builds the Email
+    ///
+    /// The headers are written in a fixed order: trace headers, `Date`, `Subject`, `Sender`,
+    /// `To`, `From`, `Cc`, `Reply-To`, `Message-ID`, `In-Reply-To`, `References`, the other
+    /// headers in the order they were added, and the MIME headers.
+    pub fn build(mut self) -> Result<Email, Error> {
+        // Bcc recipients only belong to the envelope, never to the headers
+        for header in self.message.remove_headers("Bcc") {
+            let value = header
+                .get_value::<String>()
+                .map_err(|err| Error::InvalidAddressList(err.to_string()))?;
+            self.bcc.append(&mut value.parse::<AddressList>()?.0);
+        }
+
+        // If there are multiple addresses in "From", the "Sender" is required.
+        if self.from.len() >= 2 && self.sender.is_none() {
+            // So, we must find something to put as Sender.
+            for possible_sender in &self.from {
+                // Only a mailbox can be used as sender, not Address::Group.
+                if let Address::Mailbox(ref mbx) = *possible_sender {
+                    self.sender = Some(mbx.clone());
+                    break;
+                }
+            }
+            // Address::Group is not yet supported, so the line below will never panic.
             // If groups are supported one day, add another Error for this case
             //  and return it here, if sender_header is still None at this point.
             assert!(self.sender.is_some());
@@ -370,53 +1161,28 @@ impl EmailBuilder {
         if let Some(ref v) = self.sender {
             self.message = self.message.header(("Sender", v.to_string()));
         }
-        // Calculate the envelope
-        let envelope = match self.envelope {
-            Some(e) => e,
-            None => {
-                // we need to generate the envelope
-                let mut to = vec![];
-                // add all receivers in to_header and cc_header
-                for receiver in self.to.iter().chain(self.cc.iter()).chain(self.bcc.iter()) {
-                    match *receiver {
-                        Address::Mailbox(ref m) => to.push(EmailAddress::from_str(&m.address)?),
-                        Address::Group(_, ref ms) => {
-                            for m in ms.iter() {
-                                to.push(EmailAddress::from_str(&m.address.clone())?);
-                            }
-                        }
-                    }
-                }
-                let from = Some(EmailAddress::from_str(&match self.sender {
-                    Some(x) => Ok(x.address), // if we have a sender_header, use it
-                    None => {
-                        // use a from header
-                        debug_assert!(self.from.len() <= 1); // else we'd have sender_header
-                        match self.from.first() {
-                            Some(a) => match *a {
-                                // if we have a from header
-                                Address::Mailbox(ref mailbox) => Ok(mailbox.address.clone()), // use it
-                                Address::Group(_, ref mailbox_list) => match mailbox_list.first() {
-                                    // if it's an author group, use the first author
-                                    Some(mailbox) => Ok(mailbox.address.clone()),
-                                    // for an empty author group (the rarest of the rare cases)
-                                    None => Err(Error::Envelope(LettreError::MissingFrom)), // empty envelope sender
-                                },
-                            },
-                            // if we don't have a from header
-                            None => Err(Error::Envelope(LettreError::MissingFrom)), // empty envelope sender
-                        }
-                    }
-                }?)?);
-                Envelope::new(from, to)?
-            }
-        };
+        let envelope = self.build_envelope()?;
+        // The domain of the generated Message-ID defaults to the one of the author
+        let author = self
+            .from
+            .iter()
+            .find_map(|address| match *address {
+                Address::Mailbox(ref mailbox) => Some(mailbox.address.clone()),
+                Address::Group(..) => None,
+            })
+            .or_else(|| envelope.from().map(ToString::to_string));
+        let message_id_domain = self.message_id_domain.take().or_else(|| {
+            author
+                .as_ref()
+                .and_then(|address| address.rfind('@').map(|idx| address[idx + 1..].to_string()))
+        });
+
         // Add the collected addresses as mailbox-list all at once.
         // The unwraps are fine because the conversions for Vec<Address> never errs.
         if !self.to.is_empty() {
             self.message = self
                 .message
-                .header(Header::new_with_value("To".into(), self.to).unwrap());
+                .header(Header::new_with_value("To".into(), AddressList(self.to)).unwrap());
         }
         if !self.from.is_empty() {
             self.message = self
@@ -433,7 +1199,7 @@ impl EmailBuilder {
         if !self.cc.is_empty() {
             self.message = self
                 .message
-                .header(Header::new_with_value("Cc".into(), self.cc).unwrap());
+                .header(Header::new_with_value("Cc".into(), AddressList(self.cc)).unwrap());
         }
         if !self.reply_to.is_empty() {
             self.message = self
@@ -451,7 +1217,7 @@ impl EmailBuilder {
             );
         }
 
-        if !self.date_issued {
+        if !self.date_issued && !self.without_date {
             self.message = self
                 .message
                 .header(("Date", Tm::rfc822z(&now()).to_string()));
@@ -463,26 +1229,230 @@ impl EmailBuilder {
             Some(id) => id,
             None => {
                 let message_id = Uuid::new_v4();
-                self.message = self
-                    .message
-                    .header(("Message-ID", format!("<{}.lettre@localhost>", message_id)));
+                if !self.without_message_id {
+                    let header = match message_id_domain {
+                        Some(domain) => format!("<{}@{}>", message_id, domain),
+                        None => format!("<{}.lettre@localhost>", message_id),
+                    };
+                    self.message = self.message.header(("Message-ID", header));
+                }
                 message_id.to_string()
             }
         };
 
+        // Traces and resent blocks are prepended to the existing headers
+        if !self.received.is_empty() || !self.resent.is_empty() {
+            let mut headers = HeaderMap::new();
+            // The unwrap is fine because the conversion for Received never errs.
+            for received in self.received.into_iter().rev() {
+                headers.insert(Header::new_with_value("Received".into(), received).unwrap());
+            }
+            for block in self.resent {
+                for header in block.into_headers() {
+                    headers.insert(header);
+                }
+            }
+            for header in self.message.message.headers.iter() {
+                headers.insert(header.clone());
+            }
+            self.message.message.headers = headers;
+        }
+
+        // A message without body still declares its type, like the text parts
+        let part = &self.message.message;
+        if part.body.is_empty()
+            && part.children.is_empty()
+            && !part
+                .headers
+                .iter()
+                .any(|header| header.name.eq_ignore_ascii_case("Content-Type"))
+        {
+            self.message = self
+                .message
+                .header(("Content-Type", mime::TEXT_PLAIN_UTF_8.to_string()));
+        }
+
+        let mut message = self.message.build();
+        if !self.raw_line_endings {
+            normalize_line_endings(&mut message);
+        }
+        if let Some(generator) = self.boundary_generator {
+            generator.apply(&mut message)?;
+        }
+        if let Some(report_type) = self.report_type {
+            set_report_content_type(&mut message, report_type);
+        }
+        sort_headers(&mut message);
+        check_headers(&message)?;
+
         Ok(Email {
-            message: self.message.build().as_string().into_bytes(),
+            message: message.as_string().into_bytes(),
             envelope,
             message_id,
+            structure: PartStructure::new(&message),
         })
     }
 }
 
+/// Checks if a part has a `multipart/*` content type
+fn has_multipart_content_type(part: &MimeMessage) -> bool {
+    part.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Content-Type")
+            && header
+                .get_value::<String>()
+                .map(|value| {
+                    value
+                        .trim_start()
+                        .to_ascii_lowercase()
+                        .starts_with("multipart/")
+                })
+                .unwrap_or(false)
+    })
+}
+
+/// Sets the boundary of a part, and replaces the previous one in its `Content-Type` header
+fn replace_boundary(part: &mut MimeMessage, boundary: String) {
+    let previous = format!("boundary={}", part.boundary);
+    part.boundary = boundary;
+    let content_type = remove_headers(part, "Content-Type")
+        .first()
+        .and_then(|header| header.get_value::<String>().ok());
+    match content_type {
+        Some(value) if value.contains(&previous) => {
+            let value = value.replacen(&previous, &format!("boundary={}", part.boundary), 1);
+            part.headers
+                .insert(Header::new("Content-Type".to_string(), value));
+        }
+        _ => part.update_headers(),
+    }
+}
+
+/// Position of a header in the canonical order of a message
+fn header_rank(name: &str) -> usize {
+    let is = |prefix: &str| match name.get(..prefix.len()) {
+        Some(start) => start.eq_ignore_ascii_case(prefix),
+        None => false,
+    };
+    if name.eq_ignore_ascii_case("Return-Path")
+        || name.eq_ignore_ascii_case("Received")
+        || is("Resent-")
+    {
+        0
+    } else if let Some(idx) = HEADER_ORDER
+        .iter()
+        .position(|header| name.eq_ignore_ascii_case(header))
+    {
+        1 + idx
+    } else if name.eq_ignore_ascii_case("MIME-Version") || is("Content-") {
+        2 + HEADER_ORDER.len()
+    } else {
+        1 + HEADER_ORDER.len()
+    }
+}
+
+/// Sorts the headers of a message in the canonical order, keeping the order of the
+/// headers of a same rank
+fn sort_headers(message: &mut MimeMessage) {
+    let mut sorted = message.headers.iter().cloned().collect::<Vec<Header>>();
+    sorted.sort_by_key(|header| header_rank(&header.name));
+    let mut headers = HeaderMap::new();
+    for header in sorted {
+        headers.insert(header);
+    }
+    message.headers = headers;
+}
+
+/// Removes all the headers of a part with the given name, and returns them
+fn remove_headers(part: &mut MimeMessage, name: &str) -> Vec<Header> {
+    let mut removed = vec![];
+    let mut headers = HeaderMap::new();
+    for header in part.headers.iter() {
+        if header.name.eq_ignore_ascii_case(name) {
+            removed.push(header.clone());
+        } else {
+            headers.insert(header.clone());
+        }
+    }
+    part.headers = headers;
+    removed
+}
+
+/// Replaces the `multipart/mixed` content type of a message with a `multipart/report`
+fn set_report_content_type(message: &mut MimeMessage, report_type: ReportType) {
+    remove_headers(message, "Content-Type");
+    message.headers.insert(Header::new(
+        "Content-Type".to_string(),
+        format!(
+            "multipart/report; report-type={}; boundary={}",
+            report_type, message.boundary
+        ),
+    ));
+}
+
+/// Converts the lone `\n` and `\r` of the bodies of a message and of all its parts to
+/// `\r\n`, except for the `base64` and `binary` encoded ones
+fn normalize_line_endings(part: &mut MimeMessage) {
+    let encoded = part.headers.iter().any(|header| {
+        header
+            .name
+            .eq_ignore_ascii_case("Content-Transfer-Encoding")
+            && header
+                .get_value::<String>()
+                .map(|value| {
+                    let value = value.trim();
+                    value.eq_ignore_ascii_case("base64") || value.eq_ignore_ascii_case("binary")
+                })
+                .unwrap_or(false)
+    });
+    if !encoded {
+        let mut body = String::with_capacity(part.body.len());
+        let mut chars = part.body.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => {
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    body.push_str("\r\n");
+                }
+                '\n' => body.push_str("\r\n"),
+                c => body.push(c),
+            }
+        }
+        part.body = body;
+    }
+    part.children.iter_mut().for_each(normalize_line_endings);
+}
+
+/// Checks the headers of a message and of all its parts for line breaks that could be used
+/// to inject headers, for example through a display name
+fn check_headers(message: &MimeMessage) -> Result<(), Error> {
+    if let Some(header) = message
+        .headers
+        .iter()
+        .find(|header| !is_valid_folded_header(&header.to_string()))
+    {
+        return Err(Error::InvalidHeaderValue(header.name.clone()));
+    }
+    message.children.iter().try_for_each(check_headers)
+}
+
 #[cfg(test)]
 mod test {
-    use super::{EmailBuilder, SendableEmail};
-    use crate::EmailAddress;
-    use time::now;
+    use super::encoder::BodyEncoder;
+    use super::error::Error;
+    use super::header::{ContentDisposition, ContentLanguage, ContentTransferEncoding, Received};
+    use super::part::{MultiPart, SinglePart};
+    use super::Email;
+    use super::{
+        parse_mailbox, EmailBuilder, MimeMessage, PartBuilder, PartStructure, ReportType,
+        ResentBlock, SendableEmail, DEFAULT_X_MAILER,
+    };
+    use crate::error::{AddressError, Error as LettreError};
+    use crate::stub::StubTransport;
+    use crate::{EmailAddress, Envelope, Transport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use time::{at, now, Timespec};
 
     #[test]
     fn test_multiple_from() {
@@ -504,14 +1474,71 @@ mod test {
             format!(
                 "Date: {}\r\nSubject: Invitation\r\nSender: \
                  <dieter@example.com>\r\nTo: <anna@example.com>\r\nFrom: \
-                 <dieter@example.com>, <joachim@example.com>\r\nMIME-Version: \
-                 1.0\r\nMessage-ID: <{}.lettre@localhost>\r\n\r\nWe invite you!\r\n",
+                 <dieter@example.com>, <joachim@example.com>\r\nMessage-ID: \
+                 <{}@example.com>\r\nMIME-Version: 1.0\r\n\r\nWe invite you!\r\n",
                 date_now.rfc822z(),
                 id
             )
         );
     }
 
+    #[test]
+    fn test_multiple_from_envelope() {
+        let email: SendableEmail = EmailBuilder::new()
+            .to("anna@example.com")
+            .from(("dieter@example.com", "Dieter"))
+            .from("joachim@example.com")
+            .body("We invite you!")
+            .build()
+            .unwrap()
+            .into();
+        assert_eq!(
+            email.envelope().from().unwrap().to_string(),
+            "dieter@example.com"
+        );
+
+        // An explicit sender is kept
+        let email: SendableEmail = EmailBuilder::new()
+            .to("anna@example.com")
+            .from("dieter@example.com")
+            .from("joachim@example.com")
+            .sender("secretary@example.com")
+            .body("We invite you!")
+            .build()
+            .unwrap()
+            .into();
+        assert_eq!(
+            email.envelope().from().unwrap().to_string(),
+            "secretary@example.com"
+        );
+        let message = email.message_to_string().unwrap();
+        assert!(message.contains("\r\nSender: <secretary@example.com>\r\n"));
+        assert_eq!(message.matches("Sender:").count(), 1);
+    }
+
+    #[test]
+    fn test_build_envelope() {
+        let builder = EmailBuilder::new()
+            .to("anna@example.com")
+            .cc("cc@example.com")
+            .bcc("bcc@example.com")
+            .header(("Bcc", "hidden@example.com"))
+            .from("dieter@example.com")
+            .from("joachim@example.com")
+            .body("We invite you!");
+        let envelope = builder.build_envelope().unwrap();
+        assert_eq!(envelope.from().unwrap().to_string(), "dieter@example.com");
+        assert_eq!(envelope.to().len(), 4);
+
+        let email: SendableEmail = builder.build().unwrap().into();
+        assert_eq!(&envelope, email.envelope());
+
+        assert!(EmailBuilder::new()
+            .to("anna@example.com")
+            .build_envelope()
+            .is_err());
+    }
+
     #[test]
     fn test_email_builder() {
         let email_builder = EmailBuilder::new();
@@ -536,18 +1563,56 @@ mod test {
         assert_eq!(
             email.message_to_string().unwrap(),
             format!(
-                "Date: {}\r\nSubject: Hello\r\nX-test: value\r\nSender: \
-                 <sender@localhost>\r\nTo: <user@localhost>\r\nFrom: \
-                 <user@localhost>\r\nCc: \"Alias\" <cc@localhost>\r\n\
-                 Reply-To: <reply@localhost>\r\nIn-Reply-To: original\r\n\
-                 MIME-Version: 1.0\r\nMessage-ID: \
-                 <{}.lettre@localhost>\r\n\r\nHello World!\r\n",
+                "Date: {}\r\nSubject: Hello\r\nSender: <sender@localhost>\r\nTo: \
+                 <user@localhost>\r\nFrom: <user@localhost>\r\nCc: \"Alias\" \
+                 <cc@localhost>\r\nReply-To: <reply@localhost>\r\nMessage-ID: \
+                 <{}@localhost>\r\nIn-Reply-To: original\r\nX-test: value\r\n\
+                 MIME-Version: 1.0\r\n\r\nHello World!\r\n",
                 date_now.rfc822z(),
                 id
             )
         );
     }
 
+    #[test]
+    fn test_message_id_domain() {
+        let message_id = |builder: EmailBuilder| {
+            let email: SendableEmail = builder.body("Hello").build().unwrap().into();
+            let id = email.message_id().to_string();
+            let message = email.message_to_string().unwrap();
+            let header = message
+                .lines()
+                .find(|line| line.starts_with("Message-ID: "))
+                .unwrap()
+                .to_string();
+            (id, header)
+        };
+
+        let (id, header) = message_id(
+            EmailBuilder::new()
+                .to("user@localhost")
+                .from(("author@example.com", "Author")),
+        );
+        assert_eq!(header, format!("Message-ID: <{}@example.com>", id));
+
+        let (id, header) = message_id(
+            EmailBuilder::new()
+                .to("user@localhost")
+                .from("author@example.com")
+                .message_id_domain("mail.example.org"),
+        );
+        assert_eq!(header, format!("Message-ID: <{}@mail.example.org>", id));
+
+        let (_, header) = message_id(
+            EmailBuilder::new()
+                .to("user@localhost")
+                .from("author@example.com")
+                .message_id_domain("mail.example.org")
+                .message_id("<explicit@localhost>"),
+        );
+        assert_eq!(header, "Message-ID: <explicit@localhost>");
+    }
+
     #[test]
     fn test_custom_message_id() {
         let email_builder = EmailBuilder::new();
@@ -572,11 +1637,11 @@ mod test {
         assert_eq!(
             email.message_to_string().unwrap(),
             format!(
-                "Date: {}\r\nSubject: Hello\r\nX-test: value\r\nMessage-ID: \
-                 my-shiny-id\r\nSender: <sender@localhost>\r\nTo: <user@localhost>\r\nFrom: \
-                 <user@localhost>\r\nCc: \"Alias\" <cc@localhost>\r\nReply-To: \
-                 <reply@localhost>\r\nIn-Reply-To: original\r\nMIME-Version: 1.0\r\n\r\nHello \
-                 World!\r\n",
+                "Date: {}\r\nSubject: Hello\r\nSender: <sender@localhost>\r\nTo: \
+                 <user@localhost>\r\nFrom: <user@localhost>\r\nCc: \"Alias\" \
+                 <cc@localhost>\r\nReply-To: <reply@localhost>\r\nMessage-ID: \
+                 my-shiny-id\r\nIn-Reply-To: original\r\nX-test: value\r\n\
+                 MIME-Version: 1.0\r\n\r\nHello World!\r\n",
                 date_now.rfc822z()
             )
         );
@@ -633,4 +1698,1120 @@ mod test {
             .as_slice()
         );
     }
+
+    #[test]
+    fn test_email_groups() {
+        let email: SendableEmail = EmailBuilder::new()
+            .to_list("Team: alice@example.com, bob@example.org;")
+            .unwrap()
+            .cc_group("undisclosed-recipients", vec![])
+            .bcc("carol@example.net")
+            .from("user@localhost")
+            .date(&now())
+            .message_id("<abc@localhost>")
+            .body("Hello World!")
+            .build()
+            .unwrap()
+            .into();
+
+        assert_eq!(
+            email.envelope().to(),
+            &[
+                EmailAddress::new("alice@example.com".to_string()).unwrap(),
+                EmailAddress::new("bob@example.org".to_string()).unwrap(),
+                EmailAddress::new("carol@example.net".to_string()).unwrap(),
+            ]
+        );
+
+        let message = email.message_to_string().unwrap();
+        assert!(message.contains("To: Team: <alice@example.com>, <bob@example.org>;\r\n"));
+        assert!(message.contains("Cc: undisclosed-recipients:;\r\n"));
+    }
+
+    fn counter_generator() -> impl Fn() -> String {
+        let counter = AtomicUsize::new(0);
+        move || format!("boundary-{}", counter.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn multipart_email(generator: impl Fn() -> String + Send + Sync + 'static) -> EmailBuilder {
+        EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .date(&at(Timespec::new(1_234_567_890, 0)))
+            .message_id("<abc@localhost>")
+            .alternative("<p>Hello World!</p>", "Hello boundary-0")
+            .attachment(b"attached", "file.txt", &mime::TEXT_PLAIN)
+            .unwrap()
+            .boundary_generator(generator)
+    }
+
+    #[test]
+    fn test_boundary_generator() {
+        let email = multipart_email(counter_generator()).build().unwrap();
+        let message = String::from_utf8(email.formatted()).unwrap();
+
+        // "boundary-0" appears in the text part, so the next one is used
+        assert!(message.contains("Content-Type: multipart/alternative; boundary=boundary-1\r\n"));
+        assert!(message.contains("Content-Type: multipart/mixed; boundary=boundary-2\r\n"));
+        assert_eq!(message.matches("Content-Type: multipart").count(), 2);
+        assert!(message.ends_with("\r\n--boundary-2--\r\n"));
+
+        let other = multipart_email(counter_generator()).build().unwrap();
+        assert_eq!(other.formatted(), email.formatted());
+    }
+
+    #[test]
+    fn test_boundary_generator_invalid() {
+        assert!(multipart_email(|| "boundary-0".to_string())
+            .build()
+            .is_err());
+        assert!(multipart_email(|| "".to_string()).build().is_err());
+        assert!(multipart_email(|| "a b".to_string()).build().is_err());
+        assert!(multipart_email(|| "a".repeat(71)).build().is_err());
+    }
+
+    #[test]
+    fn test_bcc_stripped() {
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .bcc("bcc@localhost")
+            .header(("Bcc", "hidden@localhost, Team: team@localhost;"))
+            .header_raw("bcc", "other@localhost")
+            .unwrap()
+            .body("Hello World!")
+            .build()
+            .unwrap()
+            .into();
+
+        assert_eq!(
+            email.envelope().to(),
+            &[
+                EmailAddress::new("user@localhost".to_string()).unwrap(),
+                EmailAddress::new("bcc@localhost".to_string()).unwrap(),
+                EmailAddress::new("hidden@localhost".to_string()).unwrap(),
+                EmailAddress::new("team@localhost".to_string()).unwrap(),
+                EmailAddress::new("other@localhost".to_string()).unwrap(),
+            ]
+        );
+
+        let message = email.message_to_string().unwrap();
+        assert!(!message.to_lowercase().contains("bcc"));
+        assert!(!message.contains("hidden@localhost"));
+    }
+
+    #[test]
+    fn test_bcc_stripped_multipart() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .bcc("bcc@localhost")
+            .header(("Bcc", "hidden@localhost"))
+            .alternative("<p>Hello</p>", "Hello")
+            .attachment(b"data", "data.bin", &mime::APPLICATION_OCTET_STREAM)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("multipart/mixed"));
+        assert!(!formatted.to_lowercase().contains("bcc"));
+        assert!(!formatted.contains("hidden@localhost"));
+
+        let email: SendableEmail = email.into();
+        assert_eq!(
+            email.envelope().to(),
+            &[
+                EmailAddress::new("user@localhost".to_string()).unwrap(),
+                EmailAddress::new("bcc@localhost".to_string()).unwrap(),
+                EmailAddress::new("hidden@localhost".to_string()).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_email_formatted() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .subject("Hello")
+            .alternative("<p>Hello World!</p>", "Hello World!")
+            .attachment(b"attached", "file.txt", &mime::TEXT_PLAIN)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let formatted = email.formatted();
+        assert_eq!(email.formatted_size(), formatted.len());
+        assert_eq!(email.clone().into_raw().bytes(), formatted.as_slice());
+
+        let sendable: SendableEmail = email.into();
+        assert_eq!(
+            sendable.message_to_string().unwrap().into_bytes(),
+            formatted
+        );
+    }
+
+    #[test]
+    fn test_email_send_borrowed() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("sender@localhost")
+            .subject("Hello")
+            .text("Hello World!")
+            .build()
+            .unwrap();
+
+        let mut transport = StubTransport::new_positive();
+        assert!(transport.send(&email).is_ok());
+        assert!(transport.send(&email).is_ok());
+
+        let sendable: SendableEmail = (&email).into();
+        assert_eq!(sendable.message_id(), email.message_id);
+        assert_eq!(sendable.envelope(), &email.envelope);
+        assert_eq!(
+            sendable.message_to_string().unwrap().into_bytes(),
+            email.formatted()
+        );
+    }
+
+    #[test]
+    fn test_email_sendable_with_envelope() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .subject("Hello")
+            .body("Hello World!")
+            .build()
+            .unwrap();
+        let envelope = Envelope::new(
+            Some(EmailAddress::new("bounce+user=localhost@localhost".to_string()).unwrap()),
+            vec![EmailAddress::new("user@localhost".to_string()).unwrap()],
+        )
+        .unwrap();
+
+        let sendable = email.sendable_with_envelope(envelope.clone());
+        assert_eq!(sendable.envelope(), &envelope);
+        assert_eq!(sendable.message_id(), email.message_id);
+        assert_eq!(
+            sendable.message_to_string().unwrap().into_bytes(),
+            email.formatted()
+        );
+    }
+
+    #[cfg(feature = "serde-impls")]
+    #[test]
+    fn test_email_serde() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .subject("Hello")
+            .alternative("<p>Hello World!</p>", "Hello World!")
+            .attachment(b"attached", "file.txt", &mime::TEXT_PLAIN)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let serialized = serde_json::to_string(&email).unwrap();
+        let deserialized: Email = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, email);
+
+        let expected: SendableEmail = email.into();
+        let restored: SendableEmail = deserialized.into();
+        assert_eq!(restored.envelope(), expected.envelope());
+        assert_eq!(restored.message_id(), expected.message_id());
+        assert_eq!(
+            restored.message_to_string().unwrap(),
+            expected.message_to_string().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resent_block() {
+        let date_now = now();
+
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .date(&date_now)
+            .subject("Hello")
+            .body("Hello World!")
+            .message_id("<original@localhost>")
+            .resent(
+                ResentBlock::new()
+                    .from("forwarder@localhost")
+                    .to(("list@localhost", "List"))
+                    .cc("archive@localhost")
+                    .date(&date_now)
+                    .message_id("<resent@localhost>"),
+            )
+            .build()
+            .unwrap()
+            .into();
+        assert_eq!(
+            email.message_to_string().unwrap(),
+            format!(
+                "Resent-Date: {}\r\nResent-From: <forwarder@localhost>\r\nResent-To: \
+                 \"List\" <list@localhost>\r\nResent-Cc: <archive@localhost>\r\n\
+                 Resent-Message-ID: <resent@localhost>\r\nDate: {}\r\nSubject: Hello\r\n\
+                 To: <user@localhost>\r\nFrom: <user@localhost>\r\nMessage-ID: \
+                 <original@localhost>\r\nMIME-Version: 1.0\r\n\r\nHello World!\r\n",
+                date_now.rfc822z(),
+                date_now.rfc822z()
+            )
+        );
+    }
+
+    #[test]
+    fn test_content_language_part() {
+        let part = PartBuilder::new()
+            .body("Bonjour")
+            .content_language("fr-FR".parse::<ContentLanguage>().unwrap())
+            .build();
+        assert_eq!(
+            part.as_string(),
+            "Content-Language: fr-FR\r\n\r\nBonjour\r\n"
+        );
+    }
+
+    #[test]
+    fn test_organization_comments() {
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .organization("Société Générale")
+            .comments("Envoyé depuis mon bureau")
+            .message_id("<id@localhost>")
+            .body("Hello World!")
+            .build()
+            .unwrap()
+            .into();
+        let message = email.message_to_string().unwrap();
+        assert!(message.contains("Organization: =?utf-8?b?U29jacOpdMOpIEfDqW7DqXJhbGU=?=\r\n"));
+        assert!(message.contains("Comments: =?utf-8?b?RW52b3nDqSBkZXB1aXMgbW9uIGJ1cmVhdQ==?=\r\n"));
+    }
+
+    #[test]
+    fn test_header_raw() {
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .header_raw("Received", "from a.example by b.example")
+            .unwrap()
+            .header_raw("Received", "from b.example by c.example")
+            .unwrap()
+            .body("Hello World!")
+            .build()
+            .unwrap()
+            .into();
+        let message = email.message_to_string().unwrap();
+        assert!(message.starts_with(
+            "Received: from a.example by b.example\r\nReceived: from b.example by c.example\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_header_raw_injection() {
+        assert!(EmailBuilder::new()
+            .header_raw("X-Test", "value\r\nBcc: attacker@example.com")
+            .is_err());
+        assert!(EmailBuilder::new()
+            .header_raw("X-Test", "value\nBcc: attacker@example.com")
+            .is_err());
+        assert!(EmailBuilder::new()
+            .header_raw("Bcc: attacker@example.com\r\nX-Test", "value")
+            .is_err());
+        assert!(EmailBuilder::new().header_raw("X Test", "value").is_err());
+    }
+
+    #[test]
+    fn test_header_injection() {
+        // Subjects are encoded
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .subject("foo\r\nBcc: attacker@evil.com")
+            .body("Hello World!")
+            .build()
+            .unwrap()
+            .into();
+        assert_eq!(
+            email.envelope().to(),
+            &[EmailAddress::new("user@localhost".to_string()).unwrap()]
+        );
+        let message = email.message_to_string().unwrap();
+        assert!(message.contains("Subject: =?utf-8?b?Zm9vDQpCY2M6IGF0dGFja2VyQGV2aWwuY29t?=\r\n"));
+        assert!(!message.contains("\nBcc:"));
+
+        // Other values are rejected
+        assert!(EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .header(("X-Test", "foo\r\nBcc: attacker@evil.com"))
+            .body("Hello World!")
+            .build()
+            .is_err());
+        assert!(EmailBuilder::new()
+            .to(("user@localhost", "User\r\nBcc: attacker@evil.com"))
+            .from("user@localhost")
+            .body("Hello World!")
+            .build()
+            .is_err());
+        assert!(EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .child(
+                PartBuilder::new()
+                    .header(("Content-Type", "text/plain\nX-Injected: yes"))
+                    .build()
+            )
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_repeated_headers() {
+        let mut email_builder = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .header(("Received", "from a.example by b.example"))
+            .header(("X-Test", "value"))
+            .header(("Received", "from b.example by c.example"))
+            .header(("received", "from c.example by d.example"))
+            .body("Hello World!");
+
+        assert_eq!(
+            email_builder
+                .headers("Received")
+                .iter()
+                .map(|header| header.get_value::<String>().unwrap())
+                .collect::<Vec<String>>(),
+            vec![
+                "from a.example by b.example",
+                "from b.example by c.example",
+                "from c.example by d.example",
+            ]
+        );
+        assert!(email_builder.headers("X-Missing").is_empty());
+
+        let removed = email_builder.remove_headers("RECEIVED");
+        assert_eq!(removed.len(), 3);
+        assert_eq!(
+            removed[0].get_value::<String>().unwrap(),
+            "from a.example by b.example"
+        );
+        assert!(email_builder.headers("Received").is_empty());
+        assert_eq!(email_builder.headers("X-Test").len(), 1);
+
+        let email: SendableEmail = email_builder.build().unwrap().into();
+        let message = email.message_to_string().unwrap();
+        assert!(!message.contains("eceived"));
+        assert!(message.contains("\r\nX-Test: value\r\n"));
+    }
+
+    #[test]
+    fn test_header_order() {
+        let date_now = now();
+        let build = |builder: EmailBuilder| {
+            let email: SendableEmail = builder
+                .date(&date_now)
+                .message_id("<id@localhost>")
+                .body("Hello")
+                .build()
+                .unwrap()
+                .into();
+            email.message_to_string().unwrap()
+        };
+
+        let first = build(
+            EmailBuilder::new()
+                .header(("X-Mailer", "lettre"))
+                .subject("Hello")
+                .header(("Received", "from a.example by b.example"))
+                .to("user@localhost")
+                .from("sender@localhost"),
+        );
+        let second = build(
+            EmailBuilder::new()
+                .from("sender@localhost")
+                .to("user@localhost")
+                .header(("Received", "from a.example by b.example"))
+                .subject("Hello")
+                .header(("X-Mailer", "lettre")),
+        );
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            format!(
+                "Received: from a.example by b.example\r\nDate: {}\r\nSubject: Hello\r\n\
+                 To: <user@localhost>\r\nFrom: <sender@localhost>\r\nMessage-ID: \
+                 <id@localhost>\r\nX-Mailer: lettre\r\nMIME-Version: 1.0\r\n\r\nHello\r\n",
+                date_now.rfc822z()
+            )
+        );
+    }
+
+    #[test]
+    fn test_mailer() {
+        let build = |builder: EmailBuilder| {
+            let email = builder
+                .to("user@localhost")
+                .from("sender@localhost")
+                .body("Hello")
+                .build()
+                .unwrap();
+            String::from_utf8(email.formatted()).unwrap()
+        };
+
+        let formatted = build(EmailBuilder::new());
+        assert!(!formatted.contains("X-Mailer"));
+        assert!(!formatted.contains("User-Agent"));
+
+        let formatted = build(
+            EmailBuilder::new()
+                .x_mailer(DEFAULT_X_MAILER)
+                .user_agent("MyApp/1.2"),
+        );
+        assert!(formatted.contains("\r\nX-Mailer: lettre\r\n"));
+        assert!(formatted.contains("\r\nUser-Agent: MyApp/1.2\r\n"));
+    }
+
+    #[test]
+    fn test_received() {
+        let date = at(Timespec::new(1_234_567_890, 0));
+        let email: SendableEmail = EmailBuilder::new()
+            .to("user@localhost")
+            .from("sender@localhost")
+            .header(("X-Mailer", "lettre"))
+            .received(
+                Received::new(date)
+                    .from("client.example.org")
+                    .by("relay.example.org")
+                    .with("ESMTPSA"),
+            )
+            .received(
+                Received::new(date)
+                    .from("relay.example.org")
+                    .by("mx.example.com")
+                    .with("ESMTP")
+                    .id("4f3a")
+                    .recipient("user@localhost"),
+            )
+            .body("Hello")
+            .build()
+            .unwrap()
+            .into();
+        // Long headers are folded
+        let message = email.message_to_string().unwrap().replace("\r\n\t", " ");
+        assert!(message.starts_with(&format!(
+            "Received: from relay.example.org by mx.example.com with ESMTP id 4f3a for \
+             <user@localhost>; {date}\r\nReceived: from client.example.org by \
+             relay.example.org with ESMTPSA; {date}\r\nDate: ",
+            date = date.rfc822z()
+        )));
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let part = |encoding: &str, body: &str| {
+            PartBuilder::new()
+                .header(("Content-Transfer-Encoding", encoding))
+                .body(body)
+                .build()
+        };
+        let body = EmailBuilder::new()
+            .child(part("7bit", "one\ntwo\r\nthree\rfour"))
+            .child(part("quoted-printable", "caf=C3=A9\n\n"))
+            .child(part("base64", "AAAA\nAAAA"))
+            .build_body()
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("\r\n\r\none\r\ntwo\r\nthree\r\nfour\r\n"));
+        assert!(body.contains("\r\n\r\ncaf=C3=A9\r\n\r\n"));
+        assert!(body.contains("\r\n\r\nAAAA\nAAAA\r\n"));
+
+        let body = EmailBuilder::new()
+            .body("one\ntwo")
+            .normalize_line_endings(false)
+            .build_body()
+            .unwrap();
+        assert_eq!(body, b"\r\none\ntwo\r\n");
+    }
+
+    #[test]
+    fn test_delivery_status_report() {
+        let email = EmailBuilder::new()
+            .from("mailer-daemon@example.com")
+            .to("user@example.com")
+            .subject("Undelivered Mail Returned to Sender")
+            .report(
+                ReportType::DeliveryStatus,
+                "Your message could not be delivered.",
+                "Reporting-MTA: dns; example.com\r\n\r\n\
+                 Final-Recipient: rfc822; nobody@example.com\r\n\
+                 Action: failed\r\n\
+                 Status: 5.1.1",
+                Some("Subject: Hello\r\n\r\nHello"),
+            )
+            .build()
+            .unwrap();
+        let message = String::from_utf8(email.formatted()).unwrap();
+        assert!(message.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+
+        let parsed = MimeMessage::parse(&message).unwrap();
+        let content_types = parsed
+            .children
+            .iter()
+            .map(|part| {
+                part.headers
+                    .get_value::<String>("Content-Type".to_string())
+                    .unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            content_types,
+            [
+                "text/plain; charset=utf-8",
+                "message/delivery-status",
+                "message/rfc822"
+            ]
+        );
+    }
+
+    fn content_type(part: &MimeMessage) -> String {
+        part.headers
+            .get_value::<String>("Content-Type".to_string())
+            .unwrap()
+    }
+
+    fn parse_multipart(content: MultiPart) -> MimeMessage {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .multipart(content)
+            .boundary_generator(counter_generator())
+            .build()
+            .unwrap();
+        let message = String::from_utf8(email.formatted()).unwrap();
+        assert_eq!(message.matches("Content-Type: multipart").count(), 1);
+        MimeMessage::parse(&message).unwrap()
+    }
+
+    #[test]
+    fn test_multipart_alternative() {
+        let message = parse_multipart(
+            MultiPart::alternative()
+                .singlepart(SinglePart::plain("Hello"))
+                .singlepart(SinglePart::html("<p>Hello</p>")),
+        );
+        assert_eq!(
+            content_type(&message),
+            "multipart/alternative; boundary=boundary-0"
+        );
+        assert_eq!(message.children.len(), 2);
+        assert!(message.children[0].body.starts_with("Hello\r\n"));
+        assert_eq!(
+            content_type(&message.children[1]),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn test_multipart_mixed() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .multipart(
+                MultiPart::mixed()
+                    .multipart(
+                        MultiPart::alternative()
+                            .singlepart(SinglePart::plain("Hello"))
+                            .singlepart(SinglePart::html("<p>Hello</p>")),
+                    )
+                    .singlepart(SinglePart::base64(b"attached", &mime::TEXT_PLAIN)),
+            )
+            .boundary_generator(counter_generator())
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+        assert_eq!(
+            content_type(&message),
+            "multipart/mixed; boundary=boundary-1"
+        );
+        assert_eq!(message.children.len(), 2);
+        assert_eq!(
+            content_type(&message.children[0]),
+            "multipart/alternative; boundary=boundary-0"
+        );
+        assert_eq!(message.children[0].children.len(), 2);
+        assert!(message.children[1].body.starts_with("YXR0YWNoZWQ=\r\n"));
+    }
+
+    #[test]
+    fn test_multipart_related() {
+        let message = parse_multipart(
+            MultiPart::related()
+                .singlepart(SinglePart::html("<img src=\"cid:logo\">"))
+                .singlepart(
+                    SinglePart::base64(b"PNG", &mime::IMAGE_PNG).header(("Content-ID", "<logo>")),
+                ),
+        );
+        assert_eq!(
+            content_type(&message),
+            "multipart/related; boundary=boundary-0"
+        );
+        assert_eq!(message.children.len(), 2);
+        assert_eq!(
+            message.children[1]
+                .headers
+                .get_value::<String>("Content-ID".to_string())
+                .unwrap(),
+            "<logo>"
+        );
+    }
+
+    #[test]
+    fn test_attachment_with() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text("See the notes below.")
+            .attachment_with(
+                SinglePart::quoted_printable("Café notes\n", &mime::TEXT_PLAIN_UTF_8)
+                    .content_disposition(ContentDisposition::inline())
+                    .header(("Content-Description", "Notes")),
+            )
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+        assert_eq!(message.children.len(), 2);
+
+        let attachment = &message.children[1];
+        let header = |name: &str| {
+            attachment
+                .headers
+                .get_value::<String>(name.to_string())
+                .unwrap()
+        };
+        assert_eq!(header("Content-Disposition"), "inline");
+        assert_eq!(header("Content-Transfer-Encoding"), "quoted-printable");
+        assert_eq!(header("Content-Description"), "Notes");
+        assert!(attachment.body.starts_with("Caf=C3=A9 notes\r\n"));
+        assert!(attachment
+            .decoded_body_string()
+            .unwrap()
+            .starts_with("Café notes\r\n"));
+    }
+
+    #[test]
+    fn test_attachment_encoded() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text("Two attachments")
+            .attachment_encoded(
+                "Café\n".as_bytes(),
+                "notes.txt",
+                &mime::TEXT_PLAIN_UTF_8,
+                ContentTransferEncoding::QuotedPrintable,
+            )
+            .unwrap()
+            .attachment_encoded(
+                b"pre-encoded",
+                "blob.bin",
+                &mime::APPLICATION_OCTET_STREAM,
+                ContentTransferEncoding::Binary,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+        assert_eq!(message.children.len(), 3);
+
+        let header = |part: &MimeMessage, name: &str| {
+            part.headers.get_value::<String>(name.to_string()).unwrap()
+        };
+        let text = &message.children[1];
+        assert_eq!(
+            header(text, "Content-Transfer-Encoding"),
+            "quoted-printable"
+        );
+        assert_eq!(
+            header(text, "Content-Disposition"),
+            "attachment; filename=\"notes.txt\""
+        );
+        assert!(text.body.starts_with("Caf=C3=A9\r\n"));
+
+        let blob = &message.children[2];
+        assert_eq!(header(blob, "Content-Transfer-Encoding"), "binary");
+        assert_eq!(header(blob, "Content-Type"), "application/octet-stream");
+        assert!(blob.body.starts_with("pre-encoded"));
+
+        match EmailBuilder::new().attachment_encoded(
+            "Café".as_bytes(),
+            "notes.txt",
+            &mime::TEXT_PLAIN,
+            ContentTransferEncoding::SevenBit,
+        ) {
+            Err(Error::InvalidBody(encoding)) => assert_eq!(encoding, "7bit"),
+            _ => panic!("7bit body with non-ASCII characters"),
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .alternative("<p>Hello</p>", "Hello")
+            .build()
+            .unwrap();
+        let structure = email.structure();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+
+        let boundary = structure.boundary.as_ref().unwrap();
+        assert!(formatted.contains(&format!("--{}--\r\n", boundary)));
+        assert_eq!(structure.children.len(), 1);
+
+        let alternative = &structure.children[0];
+        assert!(alternative
+            .content_type
+            .as_ref()
+            .unwrap()
+            .starts_with("multipart/alternative; "));
+        let types = alternative
+            .children
+            .iter()
+            .map(|part| part.content_type.as_ref().unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            ["text/plain; charset=utf-8", "text/html; charset=utf-8"]
+        );
+        assert!(alternative
+            .children
+            .iter()
+            .all(|part| part.boundary.is_none()));
+    }
+
+    #[test]
+    fn test_alternative_with_attachments() {
+        let attachments = vec![
+            SinglePart::base64(b"%PDF", &"application/pdf".parse().unwrap())
+                .content_disposition(ContentDisposition::attachment("report.pdf")),
+            SinglePart::base64(b"data", &mime::APPLICATION_OCTET_STREAM)
+                .content_disposition(ContentDisposition::attachment("data.bin")),
+        ];
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .alternative_with_attachments("<p>Hello</p>", "Hello", attachments)
+            .build()
+            .unwrap();
+
+        let content_type = |part: &PartStructure| {
+            let content_type = part.content_type.as_ref().unwrap();
+            content_type.split(';').next().unwrap().to_string()
+        };
+        let structure = email.structure();
+        assert_eq!(content_type(structure), "multipart/mixed");
+        assert_eq!(
+            structure
+                .children
+                .iter()
+                .map(content_type)
+                .collect::<Vec<_>>(),
+            [
+                "multipart/alternative",
+                "application/pdf",
+                "application/octet-stream"
+            ]
+        );
+        assert_eq!(
+            structure.children[0]
+                .children
+                .iter()
+                .map(content_type)
+                .collect::<Vec<_>>(),
+            ["text/plain", "text/html"]
+        );
+        assert!(structure.children[1..]
+            .iter()
+            .all(|part| part.children.is_empty()));
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("filename=\"report.pdf\""));
+    }
+
+    #[test]
+    fn test_attach_message() {
+        let original = EmailBuilder::new()
+            .to("alice@localhost")
+            .from("bob@localhost")
+            .subject("Original")
+            .text("Hello Alice")
+            .build()
+            .unwrap();
+        let email = EmailBuilder::new()
+            .to("carol@localhost")
+            .from("alice@localhost")
+            .subject("Fwd: Original")
+            .text("See the attached message")
+            .attach_message(&original)
+            .unwrap()
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+        assert_eq!(message.children.len(), 2);
+
+        let attached = &message.children[1];
+        let header = |name: &str| {
+            attached
+                .headers
+                .get_value::<String>(name.to_string())
+                .unwrap()
+        };
+        assert_eq!(header("Content-Type"), "message/rfc822");
+        assert_eq!(header("Content-Transfer-Encoding"), "7bit");
+        assert_eq!(header("Content-Disposition"), "attachment");
+
+        let nested = MimeMessage::parse(&attached.body).unwrap();
+        let nested_header = |name: &str| nested.headers.get_value::<String>(name.to_string());
+        assert_eq!(nested_header("Subject").unwrap(), "Original");
+        assert_eq!(nested_header("From").unwrap(), "<bob@localhost>");
+        assert!(attached.body.contains("Hello Alice"));
+
+        // A deserialized email may contain anything
+        let invalid = Email {
+            message: b"Subject: \xff\r\n\r\nHello".to_vec(),
+            ..original
+        };
+        match EmailBuilder::new().attach_message(&invalid) {
+            Err(Error::InvalidBody(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_attachment_dates() {
+        let modified = at(Timespec::new(1_577_880_000, 0));
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text("Notes attached")
+            .attachment_with(
+                SinglePart::base64(b"Notes", &mime::TEXT_PLAIN).content_disposition(
+                    ContentDisposition::attachment("notes.txt").modification_date(modified),
+                ),
+            )
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+        let disposition = message.children[1]
+            .headers
+            .get_value::<String>("Content-Disposition".to_string())
+            .unwrap();
+        assert!(disposition.contains(&format!("modification-date=\"{}\"", modified.rfc822z())));
+    }
+
+    #[test]
+    fn test_text_with_charset() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text_with_charset("Prix : 5€\nMerci", encoding_rs::WINDOWS_1252)
+            .unwrap()
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("Content-Type: text/plain; charset=windows-1252\r\n"));
+        assert!(formatted.contains("Content-Transfer-Encoding: quoted-printable\r\n"));
+        assert!(formatted.contains("\r\n\r\nPrix : 5=80\r\nMerci\r\n"));
+    }
+
+    #[test]
+    fn test_empty_body() {
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .subject("Ping")
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(formatted.ends_with("\r\n\r\n"));
+        assert!(email.structure().children.is_empty());
+
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .header(("Content-Type", "text/calendar"))
+            .body("")
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert_eq!(formatted.matches("Content-Type:").count(), 1);
+    }
+
+    #[test]
+    fn test_formatted_for_signing() {
+        let date = now();
+        let build = |body: &str| {
+            EmailBuilder::new()
+                .to("user@localhost")
+                .from("user@localhost")
+                .date(&date)
+                .message_id("<id@localhost>")
+                .body(body)
+                .build()
+                .unwrap()
+                .formatted_for_signing()
+        };
+
+        let signed = String::from_utf8(build("Hello\n\n\n")).unwrap();
+        assert!(signed.ends_with("\r\n\r\nHello\r\n"));
+        assert_eq!(build("Hello\n\n\n"), signed.as_bytes());
+        assert_eq!(build("Hello\r\n"), signed.as_bytes());
+        assert_eq!(build("Hello"), signed.as_bytes());
+
+        // An empty body is a single line ending
+        let empty = String::from_utf8(build("\n\n")).unwrap();
+        assert!(empty.ends_with("\r\n\r\n\r\n"));
+        assert!(!empty.ends_with("\r\n\r\n\r\n\r\n"));
+
+        let email = multipart_email(counter_generator()).build().unwrap();
+        let other = multipart_email(counter_generator()).build().unwrap();
+        assert_eq!(email.formatted_for_signing(), other.formatted_for_signing());
+        assert!(email
+            .formatted_for_signing()
+            .ends_with(b"\r\n--boundary-2--\r\n"));
+    }
+
+    #[test]
+    fn test_without_date_message_id() {
+        let builder = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .body("Hello");
+
+        let email = builder.clone().without_date().build().unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(!formatted.contains("Date:"));
+        assert!(formatted.contains("\r\nMessage-ID: <"));
+
+        let email = builder.clone().without_message_id().build().unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.starts_with("Date: "));
+        assert!(!formatted.contains("Message-ID:"));
+        assert!(!SendableEmail::from(&email).message_id().is_empty());
+
+        // Headers set explicitly are kept
+        let email = builder
+            .date(&at(Timespec::new(1_234_567_890, 0)))
+            .message_id("<id@localhost>")
+            .without_date()
+            .without_message_id()
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.starts_with("Date: "));
+        assert!(formatted.contains("\r\nMessage-ID: <id@localhost>\r\n"));
+    }
+
+    #[test]
+    fn test_raw_part() {
+        let raw = "Content-Type: application/pkcs7-mime; smime-type=enveloped-data;\r\n \
+                   name=smime.p7m\r\nContent-Transfer-Encoding: base64\r\n\r\n\
+                   MIAGCSqGSIb3DQEHA6CAMIACAQAx\r\nggHMMIIByAIBADCBsDCBqjELMAkG\r\n";
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text("Hello")
+            .attachment_with(SinglePart::raw(raw).unwrap())
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains(&format!("\r\n{}\r\n--", raw)));
+        assert_eq!(email.structure().children.len(), 2);
+
+        let part = SinglePart::raw("\r\nNo headers").unwrap().build();
+        assert_eq!(part.as_string(), "\r\nNo headers\r\n");
+
+        for raw in &[
+            "Content-Type: text/plain\n\nHello",
+            "Content-Type: text/plain\r\nHello",
+            " folded: value\r\n\r\nHello",
+            "Content Type: text/plain\r\n\r\nHello",
+        ] {
+            match SinglePart::raw(raw) {
+                Err(Error::InvalidRawPart(_)) => (),
+                _ => panic!("accepted {:?}", raw),
+            }
+        }
+    }
+
+    #[test]
+    fn test_text_with_parameters() {
+        let body = format!("{}From the start\n> Not a quote  ", "Hello ".repeat(13));
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text_with_parameters(&body, &[("format", "flowed")])
+            .unwrap()
+            .build()
+            .unwrap();
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("Content-Type: text/plain; charset=utf-8; format=flowed\r\n"));
+        assert!(formatted.contains(&format!(
+            "\r\n\r\n{}\r\n From the start\r\n > Not a quote",
+            "Hello ".repeat(13)
+        )));
+
+        assert!(EmailBuilder::new()
+            .text_with_parameters("Hello", &[("format", "flowed; delsp")])
+            .is_err());
+    }
+
+    #[test]
+    fn test_attachment_line_length() {
+        let body = vec![0xff; 200];
+        let email = EmailBuilder::new()
+            .to("user@localhost")
+            .from("user@localhost")
+            .text("Attachments")
+            .attachment(&body, "default.bin", &mime::APPLICATION_OCTET_STREAM)
+            .unwrap()
+            .body_encoder(BodyEncoder::new().line_length(64))
+            .attachment(&body, "short.bin", &mime::APPLICATION_OCTET_STREAM)
+            .unwrap()
+            .build()
+            .unwrap();
+        let message = MimeMessage::parse(&String::from_utf8(email.formatted()).unwrap()).unwrap();
+
+        let lines = |part: &MimeMessage| {
+            part.body
+                .split("\r\n")
+                // The parsed body of the last part ends with the closing boundary
+                .filter(|line| !line.is_empty() && !line.starts_with("--"))
+                .map(str::len)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lines(&message.children[1]), [76, 76, 76, 40]);
+        assert_eq!(lines(&message.children[2]), [64, 64, 64, 64, 12]);
+    }
+
+    #[test]
+    fn test_parse_mailbox() {
+        let mailbox = parse_mailbox("\"John Doe\" <john@example.com>").unwrap();
+        assert_eq!(mailbox.name, Some("John Doe".to_string()));
+        assert_eq!(mailbox.address, "john@example.com");
+
+        assert!(parse_mailbox("John <john@@example.com>").is_err());
+        match parse_mailbox("John <john@exa_mple.com>") {
+            Err(Error::Envelope(LettreError::InvalidEmailAddress(err))) => {
+                assert_eq!(err, AddressError::InvalidDomain)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
 }
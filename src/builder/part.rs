@@ -0,0 +1,438 @@
+//! Composable parts of a MIME message
+
+use crate::builder::encoder::BodyEncoder;
+use crate::builder::error::Error;
+use crate::builder::flowed;
+use crate::builder::header::{
+    is_valid_header_name, ContentDescription, ContentDisposition, ContentId,
+    ContentTransferEncoding,
+};
+use crate::builder::quoted_printable;
+use crate::builder::{Header, MimeMultipartType, PartBuilder};
+use email::MimeMessage;
+use encoding_rs::Encoding;
+use mime::Mime;
+use std::fmt::{self, Display, Formatter};
+
+/// Subtype of a multipart part
+///
+/// [RFC 2046, section 5.1](https://tools.ietf.org/html/rfc2046#section-5.1)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MultiPartKind {
+    /// Independent parts, like a body and its attachments
+    Mixed,
+    /// Alternative versions of the same content, like plain text and HTML
+    Alternative,
+    /// A root part and the resources it references, like an HTML body and its images
+    ///
+    /// [RFC 2387](https://tools.ietf.org/html/rfc2387)
+    Related,
+    /// A collection of messages
+    Digest,
+    /// Parts to be displayed at the same time
+    Parallel,
+}
+
+impl Display for MultiPartKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MultiPartKind::Mixed => "mixed",
+            MultiPartKind::Alternative => "alternative",
+            MultiPartKind::Related => "related",
+            MultiPartKind::Digest => "digest",
+            MultiPartKind::Parallel => "parallel",
+        })
+    }
+}
+
+/// A part with a body and no children
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct SinglePart {
+    part: PartBuilder,
+}
+
+impl SinglePart {
+    /// Creates a new empty part
+    pub fn new() -> SinglePart {
+        SinglePart {
+            part: PartBuilder::new(),
+        }
+    }
+
+    /// Creates a plain text part
+    pub fn plain<S: Into<String>>(body: S) -> SinglePart {
+        SinglePart::new()
+            .content_type(&mime::TEXT_PLAIN_UTF_8)
+            .body(body)
+    }
+
+    /// Creates a UTF-8 plain text part with additional `Content-Type` parameters
+    ///
+    /// With `format=flowed`, the body is formatted as described in `flowed::encode`,
+    /// honoring `delsp=yes`. Fails if a parameter is not a valid token or value.
+    pub fn plain_with_parameters(
+        body: &str,
+        parameters: &[(&str, &str)],
+    ) -> Result<SinglePart, Error> {
+        let mut content_type = mime::TEXT_PLAIN_UTF_8.to_string();
+        for &(name, value) in parameters {
+            content_type.push_str(&format!("; {}={}", name, value));
+        }
+        let content_type = content_type
+            .parse::<Mime>()
+            .map_err(|_| Error::InvalidHeaderValue("Content-Type".to_string()))?;
+
+        let has_parameter = |name: &str, value: &str| matches!(content_type.get_param(name), Some(param) if param.as_str().eq_ignore_ascii_case(value));
+        let body = if has_parameter("format", "flowed") {
+            flowed::encode(body, has_parameter("delsp", "yes"))
+        } else {
+            body.to_string()
+        };
+        Ok(SinglePart::new().content_type(&content_type).body(body))
+    }
+
+    /// Creates a plain text part in the given charset, like `windows-1252` for legacy systems
+    ///
+    /// The body is converted from UTF-8, and encoded with `quoted-printable` unless the
+    /// result is ASCII. Fails if the charset can not represent some characters.
+    pub fn plain_with_charset(body: &str, charset: &'static Encoding) -> Result<SinglePart, Error> {
+        // Encoders of UTF-16 charsets produce UTF-8
+        let charset = charset.output_encoding();
+        let (bytes, _, unmappable) = charset.encode(body);
+        if unmappable {
+            return Err(Error::InvalidBody(charset.name().to_string()));
+        }
+
+        // The unwrap is fine because the names of the charsets are valid parameter values.
+        let content_type = format!("text/plain; charset={}", charset.name())
+            .parse::<Mime>()
+            .unwrap();
+        let part = SinglePart::new().content_type(&content_type);
+        Ok(if bytes.is_ascii() {
+            part.content_transfer_encoding(ContentTransferEncoding::SevenBit)
+                .body(String::from_utf8_lossy(&bytes))
+        } else {
+            part.content_transfer_encoding(ContentTransferEncoding::QuotedPrintable)
+                .body(quoted_printable::encode_bytes(&bytes))
+        })
+    }
+
+    /// Creates an HTML part
+    pub fn html<S: Into<String>>(body: S) -> SinglePart {
+        SinglePart::new()
+            .content_type(&mime::TEXT_HTML_UTF_8)
+            .body(body)
+    }
+
+    /// Creates a part from binary content, encoded with `base64`
+    pub fn base64(body: &[u8], content_type: &Mime) -> SinglePart {
+        SinglePart::new()
+            .content_type(content_type)
+            .content_transfer_encoding(ContentTransferEncoding::Base64)
+            .body(BodyEncoder::new().base64(body))
+    }
+
+    /// Creates a part from text content, encoded with `quoted-printable`
+    pub fn quoted_printable(body: &str, content_type: &Mime) -> SinglePart {
+        SinglePart::new()
+            .content_type(content_type)
+            .content_transfer_encoding(ContentTransferEncoding::QuotedPrintable)
+            .body(BodyEncoder::new().quoted_printable(body))
+    }
+
+    /// Creates a part from content encoded with the given `Content-Transfer-Encoding`
+    ///
+    /// Only `base64` accepts any content: `7bit` requires ASCII, and the other encodings
+    /// require UTF-8. `7bit`, `8bit` and `binary` content is sent as is.
+    pub fn encoded(
+        body: &[u8],
+        content_type: &Mime,
+        encoding: ContentTransferEncoding,
+    ) -> Result<SinglePart, Error> {
+        SinglePart::encoded_with(body, content_type, encoding, &BodyEncoder::new())
+    }
+
+    /// Creates a part like `encoded`, with the line length of the given encoder
+    pub fn encoded_with(
+        body: &[u8],
+        content_type: &Mime,
+        encoding: ContentTransferEncoding,
+        encoder: &BodyEncoder,
+    ) -> Result<SinglePart, Error> {
+        let part = SinglePart::new().content_type(content_type);
+        if encoding == ContentTransferEncoding::Base64 {
+            return Ok(part
+                .content_transfer_encoding(encoding)
+                .body(encoder.base64(body)));
+        }
+        let body = match std::str::from_utf8(body) {
+            Ok(body) if encoding != ContentTransferEncoding::SevenBit || body.is_ascii() => body,
+            _ => return Err(Error::InvalidBody(encoding.to_string())),
+        };
+        Ok(match encoding {
+            ContentTransferEncoding::QuotedPrintable => part
+                .content_transfer_encoding(encoding)
+                .body(encoder.quoted_printable(body)),
+            _ => part.content_transfer_encoding(encoding).body(body),
+        })
+    }
+
+    /// Creates a part from a complete MIME part already encoded by another tool, for
+    /// example signed or encrypted content
+    ///
+    /// The headers, separated from the body by an empty line, and the body are sent as
+    /// they are. Lines must end with CRLF. A line ending at the end of the part is removed,
+    /// since it is added back before the next boundary.
+    pub fn raw(part: &str) -> Result<SinglePart, Error> {
+        let mut line_endings = part.match_indices(&['\r', '\n'][..]);
+        while let Some((idx, ending)) = line_endings.next() {
+            if ending != "\r" || !part[idx + 1..].starts_with('\n') {
+                return Err(Error::InvalidRawPart("line ending other than CRLF"));
+            }
+            line_endings.next();
+        }
+
+        let (headers, body) = match part.strip_prefix("\r\n") {
+            Some(body) => ("", body),
+            None => match part.find("\r\n\r\n") {
+                Some(idx) => (&part[..idx], &part[idx + 4..]),
+                None => return Err(Error::InvalidRawPart("no empty line after the headers")),
+            },
+        };
+
+        let mut fields: Vec<(&str, String)> = vec![];
+        for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                match fields.last_mut() {
+                    Some((_, value)) => {
+                        value.push_str("\r\n");
+                        value.push_str(line);
+                    }
+                    None => return Err(Error::InvalidRawPart("folded first header")),
+                }
+                continue;
+            }
+            match line.find(':') {
+                Some(idx) if is_valid_header_name(&line[..idx]) => {
+                    let value = &line[idx + 1..];
+                    let value = value.strip_prefix(' ').unwrap_or(value);
+                    fields.push((&line[..idx], value.to_string()));
+                }
+                _ => return Err(Error::InvalidRawPart("malformed header")),
+            }
+        }
+
+        let mut part = SinglePart::new();
+        for (name, value) in fields {
+            part = part.header((name, value));
+        }
+        Ok(part.body(body.strip_suffix("\r\n").unwrap_or(body)))
+    }
+
+    /// Adds a generic header
+    pub fn header<A: Into<Header>>(mut self, header: A) -> SinglePart {
+        self.part = self.part.header(header);
+        self
+    }
+
+    /// Adds a `Content-Type` header with the given MIME type
+    pub fn content_type(mut self, content_type: &Mime) -> SinglePart {
+        self.part = self.part.content_type(content_type);
+        self
+    }
+
+    /// Adds a `Content-Transfer-Encoding` header
+    ///
+    /// The body must already be encoded accordingly.
+    pub fn content_transfer_encoding(self, encoding: ContentTransferEncoding) -> SinglePart {
+        // The unwrap is fine because the conversion for ContentTransferEncoding never errs.
+        self.header(Header::new_with_value("Content-Transfer-Encoding".into(), encoding).unwrap())
+    }
+
+    /// Adds a `Content-Disposition` header
+    pub fn content_disposition(self, disposition: ContentDisposition) -> SinglePart {
+        // The unwrap is fine because the conversion for ContentDisposition never errs.
+        self.header(Header::new_with_value("Content-Disposition".into(), disposition).unwrap())
+    }
+
+    /// Adds a `Content-Description` header, encoded if needed
+    pub fn content_description<S: Into<String>>(self, description: S) -> SinglePart {
+        // The unwrap is fine because the conversion for ContentDescription never errs.
+        self.header(
+            Header::new_with_value(
+                ContentDescription::NAME.into(),
+                ContentDescription(description.into()),
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Adds a `Content-ID` header, to reference the part from the `cid:` URL of the id
+    pub fn content_id(self, id: &ContentId) -> SinglePart {
+        // The unwrap is fine because the conversion for ContentId never errs.
+        self.header(Header::new_with_value("Content-ID".into(), id.clone()).unwrap())
+    }
+
+    /// Sets the body, already encoded with the `Content-Transfer-Encoding` of the part
+    pub fn body<S: Into<String>>(mut self, body: S) -> SinglePart {
+        self.part = self.part.body(body);
+        self
+    }
+
+    /// Gets built `MimeMessage`
+    pub fn build(self) -> MimeMessage {
+        self.part.build()
+    }
+}
+
+/// A part containing other parts
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MultiPart {
+    kind: MultiPartKind,
+    part: PartBuilder,
+}
+
+impl MultiPart {
+    /// Creates a new empty multipart part
+    pub fn new(kind: MultiPartKind) -> MultiPart {
+        MultiPart {
+            kind,
+            part: PartBuilder::new(),
+        }
+    }
+
+    /// Creates a new `multipart/mixed` part
+    pub fn mixed() -> MultiPart {
+        MultiPart::new(MultiPartKind::Mixed)
+    }
+
+    /// Creates a new `multipart/alternative` part
+    pub fn alternative() -> MultiPart {
+        MultiPart::new(MultiPartKind::Alternative)
+    }
+
+    /// Creates a new `multipart/related` part
+    pub fn related() -> MultiPart {
+        MultiPart::new(MultiPartKind::Related)
+    }
+
+    /// Adds a generic header
+    pub fn header<A: Into<Header>>(mut self, header: A) -> MultiPart {
+        self.part = self.part.header(header);
+        self
+    }
+
+    /// Adds a single part
+    pub fn singlepart(mut self, part: SinglePart) -> MultiPart {
+        self.part = self.part.child(part.build());
+        self
+    }
+
+    /// Adds a nested multipart part
+    pub fn multipart(mut self, part: MultiPart) -> MultiPart {
+        self.part = self.part.child(part.build());
+        self
+    }
+
+    /// Gets built `MimeMessage`
+    pub fn build(self) -> MimeMessage {
+        let mut message = self.part.message;
+        // Any multipart type lets the boundary generator know this part needs a boundary,
+        // the actual subtype is only kept in the `Content-Type` header
+        message.message_type = Some(MimeMultipartType::Mixed);
+        message.headers.insert(Header::new(
+            "Content-Type".to_string(),
+            format!("multipart/{}; boundary={}", self.kind, message.boundary),
+        ));
+        message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MultiPart, SinglePart};
+    use crate::builder::error::Error;
+    use crate::builder::header::ContentId;
+
+    #[test]
+    fn test_singlepart() {
+        let part = SinglePart::base64(b"Hello", &mime::TEXT_PLAIN).build();
+        assert_eq!(
+            part.as_string(),
+            "Content-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8=\r\n"
+        );
+    }
+
+    #[test]
+    fn test_plain_with_charset() {
+        let part = SinglePart::plain_with_charset("Café – 5€", encoding_rs::WINDOWS_1252)
+            .unwrap()
+            .build();
+        assert_eq!(
+            part.as_string(),
+            "Content-Type: text/plain; charset=windows-1252\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\r\nCaf=E9 =96 5=80\r\n"
+        );
+
+        let part = SinglePart::plain_with_charset("Hello", encoding_rs::WINDOWS_1252)
+            .unwrap()
+            .build();
+        assert!(part
+            .as_string()
+            .ends_with("Content-Transfer-Encoding: 7bit\r\n\r\nHello\r\n"));
+
+        match SinglePart::plain_with_charset("日本", encoding_rs::WINDOWS_1252) {
+            Err(Error::InvalidBody(charset)) => assert_eq!(charset, "windows-1252"),
+            _ => panic!("characters missing from the charset"),
+        }
+    }
+
+    #[test]
+    fn test_multipart() {
+        let part = MultiPart::related()
+            .singlepart(SinglePart::html("<img src=\"cid:logo\">"))
+            .build();
+        assert_eq!(part.children.len(), 1);
+        assert_eq!(
+            part.headers
+                .get_value::<String>("Content-Type".to_string())
+                .unwrap(),
+            format!("multipart/related; boundary={}", part.boundary)
+        );
+    }
+
+    #[test]
+    fn test_content_id() {
+        let logo = ContentId::generate();
+        let part = MultiPart::related()
+            .singlepart(SinglePart::html(format!("<img src=\"{}\">", logo.url())))
+            .singlepart(SinglePart::base64(b"PNG", &mime::IMAGE_PNG).content_id(&logo))
+            .build();
+
+        let header = part.children[1]
+            .headers
+            .get_value::<String>("Content-ID".to_string())
+            .unwrap();
+        let reference = format!("cid:{}", &header[1..header.len() - 1]);
+        assert!(header.starts_with('<') && header.ends_with('>'));
+        assert_eq!(reference, logo.url());
+        assert!(part.children[0].body.contains(&reference));
+    }
+
+    #[test]
+    fn test_content_description() {
+        let part = SinglePart::base64(b"PDF", &mime::APPLICATION_PDF)
+            .content_description("Relevé de compte")
+            .build();
+        assert!(part
+            .as_string()
+            .contains("Content-Description: =?utf-8?b?UmVsZXbDqSBkZSBjb21wdGU=?=\r\n"));
+
+        let part = SinglePart::plain("Hello")
+            .content_description("Greeting")
+            .build();
+        assert!(part
+            .as_string()
+            .contains("Content-Description: Greeting\r\n"));
+    }
+}
@@ -0,0 +1,116 @@
+//! Encoding of bodies with the `quoted-printable` content transfer encoding
+//!
+//! [RFC 2045, section 6.7](https://tools.ietf.org/html/rfc2045#section-6.7)
+
+/// Maximum length of an encoded line, including the `=` of a soft line break
+pub const MAX_LINE_LEN: usize = 76;
+
+/// Encodes a text body
+///
+/// Line breaks, `\r\n` or `\n`, are kept as `\r\n`, and longer lines are split with soft
+/// line breaks. Characters are encoded in UTF-8.
+pub fn encode(body: &str) -> String {
+    encode_with_line_len(body, MAX_LINE_LEN)
+}
+
+/// Encodes a text body with lines of at most `max_line_len` characters, at least 4
+pub fn encode_with_line_len(body: &str, max_line_len: usize) -> String {
+    encode_bytes_with_line_len(body.as_bytes(), max_line_len)
+}
+
+/// Encodes a text body already converted to its charset
+///
+/// Line breaks are handled like in `encode`, so the charset must be ASCII compatible.
+pub fn encode_bytes(body: &[u8]) -> String {
+    encode_bytes_with_line_len(body, MAX_LINE_LEN)
+}
+
+fn encode_bytes_with_line_len(body: &[u8], max_line_len: usize) -> String {
+    let max_line_len = max_line_len.max(4);
+    let mut encoded = String::with_capacity(body.len());
+    for (idx, line) in body.split(|&byte| byte == b'\n').enumerate() {
+        if idx > 0 {
+            encoded.push_str("\r\n");
+        }
+        let line = match line.split_last() {
+            Some((b'\r', line)) => line,
+            _ => line,
+        };
+        encode_line(line, max_line_len, &mut encoded);
+    }
+    encoded
+}
+
+fn encode_line(bytes: &[u8], max_line_len: usize, encoded: &mut String) {
+    let mut len = 0;
+    for (idx, &byte) in bytes.iter().enumerate() {
+        let last = idx + 1 == bytes.len();
+        let word = match byte {
+            // Whitespace at the end of a line would be removed in transport
+            b' ' | b'\t' if !last => (byte as char).to_string(),
+            b'!'..=b'<' | b'>'..=b'~' => (byte as char).to_string(),
+            _ => format!("={:02X}", byte),
+        };
+        if len + word.len() > max_line_len - 1 {
+            encoded.push_str("=\r\n");
+            len = 0;
+        }
+        len += word.len();
+        encoded.push_str(&word);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, encode_bytes};
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(encode("Hello World!"), "Hello World!");
+        assert_eq!(encode("a=b"), "a=3Db");
+        assert_eq!(encode(""), "");
+    }
+
+    #[test]
+    fn test_line_breaks() {
+        assert_eq!(encode("one\ntwo\r\nthree "), "one\r\ntwo\r\nthree=20");
+        assert_eq!(encode("tab\t\n"), "tab=09\r\n");
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let long = "word ".repeat(20);
+        let long = long.trim_end();
+        let encoded = encode(&format!("Hello,\r\n\r\n{}\r\nBye\r\n", long));
+
+        assert!(!encoded.contains("=0D") && !encoded.contains("=0A"));
+        let lines = encoded.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines[..2], ["Hello,", ""]);
+        // The long line is soft-wrapped
+        assert_eq!(lines[2], format!("{}=", &long[..75]));
+        assert_eq!(lines[3], &long[75..]);
+        assert_eq!(lines[4..], ["Bye", ""]);
+        // A lone carriage return is not a line break
+        assert_eq!(encode("a\rb"), "a=0Db");
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(encode("Café"), "Caf=C3=A9");
+    }
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(encode_bytes(b"Caf\xe9\r\n5\x80"), "Caf=E9\r\n5=80");
+    }
+
+    #[test]
+    fn test_soft_line_breaks() {
+        let encoded = encode(&"é".repeat(20));
+        assert_eq!(
+            encoded,
+            format!("{}=C3=\r\n=A9{}", "=C3=A9".repeat(12), "=C3=A9".repeat(7))
+        );
+        assert!(encoded.lines().all(|line| line.len() <= 76));
+    }
+}
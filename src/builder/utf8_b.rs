@@ -0,0 +1,78 @@
+//! Encoding of header values as RFC 2047 encoded-words, using the `B` encoding and the
+//! UTF-8 charset
+
+/// Maximum length of an encoded-word
+///
+/// [RFC 2047, section 2](https://tools.ietf.org/html/rfc2047#section-2)
+const MAX_ENCODED_WORD_LEN: usize = 75;
+
+/// Length of the `=?utf-8?b?` prefix and `?=` suffix of an encoded-word
+const ENCODED_WORD_OVERHEAD: usize = 12;
+
+/// Maximum number of bytes encoded in a single encoded-word
+const MAX_CHUNK_LEN: usize = (MAX_ENCODED_WORD_LEN - ENCODED_WORD_OVERHEAD) / 4 * 3;
+
+/// Encodes a header value if needed
+///
+/// Values only containing printable ASCII characters and spaces are returned unchanged,
+/// other values are split into encoded-words separated by spaces. Words never exceed
+/// 75 characters and characters are never split between two words.
+pub fn encode(value: &str) -> String {
+    if value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return value.to_string();
+    }
+
+    let mut words = vec![];
+    let mut start = 0;
+    let mut end = 0;
+    for (idx, c) in value.char_indices() {
+        if idx + c.len_utf8() - start > MAX_CHUNK_LEN {
+            words.push(encode_word(&value[start..end]));
+            start = end;
+        }
+        end = idx + c.len_utf8();
+    }
+    words.push(encode_word(&value[start..end]));
+    words.join(" ")
+}
+
+fn encode_word(chunk: &str) -> String {
+    format!("=?utf-8?b?{}?=", base64::encode(chunk))
+}
+
+#[cfg(test)]
+mod test {
+    use super::encode;
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(encode("Hello World!"), "Hello World!");
+        assert_eq!(encode(""), "");
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(encode("Café"), "=?utf-8?b?Q2Fmw6k=?=");
+    }
+
+    #[test]
+    fn test_long_value() {
+        let value = format!("Re: {}", "😀🎉".repeat(40));
+        let encoded = encode(&value);
+
+        let mut decoded = String::new();
+        for word in encoded.split(' ') {
+            assert!(word.len() <= 75);
+            let payload = &word["=?utf-8?b?".len()..word.len() - "?=".len()];
+            // Each word decodes to valid UTF-8 on its own
+            let chunk = String::from_utf8(base64::decode(payload).unwrap()).unwrap();
+            decoded.push_str(&chunk);
+        }
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_control_characters() {
+        assert_eq!(encode("a\r\nb"), "=?utf-8?b?YQ0KYg==?=");
+    }
+}
@@ -12,7 +12,7 @@ pub enum Error {
     /// Missing to in envelope
     MissingTo,
     /// Invalid email
-    InvalidEmailAddress,
+    InvalidEmailAddress(AddressError),
 }
 
 impl Display for Error {
@@ -20,16 +20,53 @@ impl Display for Error {
         fmt.write_str(&match *self {
             MissingFrom => "missing source address, invalid envelope".to_owned(),
             MissingTo => "missing destination address, invalid envelope".to_owned(),
-            InvalidEmailAddress => "invalid email address".to_owned(),
+            InvalidEmailAddress(ref err) => format!("invalid email address: {}", err),
         })
     }
 }
 
 impl StdError for Error {
-    fn cause(&self) -> Option<&dyn StdError> {
-        None
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            InvalidEmailAddress(ref err) => Some(err),
+            _ => None,
+        }
     }
 }
 
+/// Reason for an email address to be invalid
+///
+/// [RFC 5321, section 4.1.2](https://tools.ietf.org/html/rfc5321#section-4.1.2)
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AddressError {
+    /// Missing `@` between the local part and the domain
+    MissingSeparator,
+    /// Malformed local part
+    InvalidUser,
+    /// Local part longer than 64 octets
+    UserTooLong,
+    /// Malformed domain
+    InvalidDomain,
+    /// Domain longer than 255 octets
+    DomainTooLong,
+    /// Address longer than 254 octets
+    TooLong,
+}
+
+impl Display for AddressError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        fmt.write_str(match *self {
+            AddressError::MissingSeparator => "missing @ separator",
+            AddressError::InvalidUser => "invalid local part",
+            AddressError::UserTooLong => "local part is too long",
+            AddressError::InvalidDomain => "invalid domain",
+            AddressError::DomainTooLong => "domain is too long",
+            AddressError::TooLong => "address is too long",
+        })
+    }
+}
+
+impl StdError for AddressError {}
+
 /// Email result type
 pub type EmailResult<T> = Result<T, Error>;
@@ -21,23 +21,19 @@ pub enum Error {
 
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        fmt.write_str(self.description())
-    }
-}
-
-impl StdError for Error {
-    fn description(&self) -> &str {
         match *self {
-            Client(err) => err,
-            Io(ref err) => err.description(),
-            JsonSerialization(ref err) => err.description(),
+            Client(err) => fmt.write_str(err),
+            Io(ref err) => err.fmt(fmt),
+            JsonSerialization(ref err) => err.fmt(fmt),
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn StdError> {
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
-            Io(ref err) => Some(&*err),
-            JsonSerialization(ref err) => Some(&*err),
+            Io(ref err) => Some(err),
+            JsonSerialization(ref err) => Some(err),
             _ => None,
         }
     }
@@ -63,3 +59,23 @@ impl From<&'static str> for Error {
 
 /// SMTP result type
 pub type FileResult = Result<(), Error>;
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn test_source() {
+        let error = Error::from(io::Error::new(io::ErrorKind::NotFound, "no directory"));
+        assert_eq!(error.to_string(), "no directory");
+        assert_eq!(error.source().unwrap().to_string(), "no directory");
+        assert!(error.source().unwrap().is::<io::Error>());
+
+        let error = Error::from(serde_json::from_str::<u8>("{").unwrap_err());
+        assert!(error.source().unwrap().is::<serde_json::Error>());
+
+        assert!(Error::Client("failed").source().is_none());
+    }
+}
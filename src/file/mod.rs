@@ -8,7 +8,7 @@ use crate::Envelope;
 use crate::SendableEmail;
 use crate::Transport;
 use serde_json;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
@@ -19,6 +19,9 @@ pub mod error;
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileTransport {
     path: PathBuf,
+    /// Whether the directory is created when missing
+    #[cfg_attr(feature = "serde-impls", serde(default))]
+    create_dir: bool,
 }
 
 impl FileTransport {
@@ -26,8 +29,17 @@ impl FileTransport {
     pub fn new<P: AsRef<Path>>(path: P) -> FileTransport {
         FileTransport {
             path: PathBuf::from(path.as_ref()),
+            create_dir: false,
         }
     }
+
+    /// Creates the directory and its parents when it does not exist, instead of failing
+    ///
+    /// Disabled by default.
+    pub fn create_dir(mut self, enabled: bool) -> FileTransport {
+        self.create_dir = enabled;
+        self
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -47,6 +59,10 @@ impl<'a> Transport<'a> for FileTransport {
         let message_id = email.message_id().to_string();
         let envelope = email.envelope().clone();
 
+        if self.create_dir {
+            fs::create_dir_all(&self.path)?;
+        }
+
         let mut file = self.path.clone();
         file.push(format!("{}.json", message_id));
 
@@ -24,6 +24,7 @@ pub mod sendmail;
 pub mod smtp;
 pub mod stub;
 
+use crate::error::AddressError;
 use crate::error::EmailResult;
 use crate::error::Error;
 #[cfg(feature = "file-transport")]
@@ -33,16 +34,19 @@ pub use crate::sendmail::SendmailTransport;
 #[cfg(feature = "smtp-transport")]
 pub use crate::smtp::client::net::ClientTlsParameters;
 #[cfg(all(feature = "smtp-transport", feature = "connection-pool"))]
-pub use crate::smtp::r2d2::SmtpConnectionManager;
+pub use crate::smtp::r2d2::{PoolMetrics, SmtpConnectionManager};
 #[cfg(feature = "smtp-transport")]
 pub use crate::smtp::{ClientSecurity, SmtpClient, SmtpTransport};
-use fast_chemail::is_valid_email;
+use idna::domain_to_ascii;
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::io::Cursor;
 use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
+use std::sync::Arc;
 
 /// Email address
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -51,19 +55,157 @@ pub struct EmailAddress(String);
 
 impl EmailAddress {
     pub fn new(address: String) -> EmailResult<EmailAddress> {
-        if !EmailAddress::is_valid(&address) {
-            return Err(Error::InvalidEmailAddress);
-        }
+        check_address(&address).map_err(Error::InvalidEmailAddress)?;
         Ok(EmailAddress(address))
     }
 
     pub fn is_valid(addr: &str) -> bool {
-        is_valid_email(addr) || addr.ends_with("localhost")
+        check_address(addr).is_ok()
+    }
+
+    /// Parses an address, with the same validation as `EmailAddress::new`
+    ///
+    /// The error tells which part of the address is invalid.
+    pub fn parse(addr: &str) -> Result<EmailAddress, AddressError> {
+        check_address(addr)?;
+        Ok(EmailAddress(addr.to_string()))
     }
 
     pub fn into_inner(self) -> String {
         self.0
     }
+
+    /// Returns the address with its domain converted to ASCII (punycode), as required
+    /// by servers that do not support SMTPUTF8
+    ///
+    /// Addresses with an ASCII domain are returned unchanged.
+    pub fn to_ascii(&self) -> EmailResult<EmailAddress> {
+        let (user, domain) = self.split();
+        if domain.is_ascii() {
+            return Ok(self.clone());
+        }
+        let domain = domain_to_ascii(domain)
+            .map_err(|_| Error::InvalidEmailAddress(AddressError::InvalidDomain))?;
+        Ok(EmailAddress(format!("{}@{}", user, domain)))
+    }
+
+    /// Tells if the local part of the address only contains ASCII characters
+    ///
+    /// Addresses with a non-ASCII local part can only be sent to servers supporting
+    /// SMTPUTF8.
+    pub fn is_user_ascii(&self) -> bool {
+        self.split().0.is_ascii()
+    }
+
+    /// Splits the address into its local part and domain
+    fn split(&self) -> (&str, &str) {
+        match self.0.rfind('@') {
+            Some(idx) => (&self.0[..idx], &self.0[idx + 1..]),
+            None => (&self.0, ""),
+        }
+    }
+}
+
+/// Maximum length of an address
+const MAX_ADDRESS_LEN: usize = 254;
+/// Maximum length of the local part of an address
+const MAX_USER_LEN: usize = 64;
+/// Maximum length of the domain of an address
+const MAX_DOMAIN_LEN: usize = 255;
+/// Maximum length of a label of the domain
+const MAX_LABEL_LEN: usize = 63;
+
+/// Validates an address according to the `Mailbox` syntax of RFC 5321, also accepting
+/// non-ASCII characters as allowed by RFC 6531
+///
+/// [RFC 5321, section 4.1.2](https://tools.ietf.org/html/rfc5321#section-4.1.2)
+fn check_address(addr: &str) -> Result<(), AddressError> {
+    let idx = addr.rfind('@').ok_or(AddressError::MissingSeparator)?;
+    let (user, domain) = (&addr[..idx], &addr[idx + 1..]);
+    check_user(user)?;
+    check_domain(domain)?;
+    if addr.len() > MAX_ADDRESS_LEN {
+        return Err(AddressError::TooLong);
+    }
+    Ok(())
+}
+
+/// Validates a local part, either a dot-atom or a quoted string
+fn check_user(user: &str) -> Result<(), AddressError> {
+    if user.len() > MAX_USER_LEN {
+        return Err(AddressError::UserTooLong);
+    }
+    let valid = if user.len() >= 2 && user.starts_with('"') && user.ends_with('"') {
+        is_quoted_string(&user[1..user.len() - 1])
+    } else {
+        user.split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(AddressError::InvalidUser)
+    }
+}
+
+/// Checks the content of a quoted string, which may contain spaces and quoted pairs
+fn is_quoted_string(content: &str) -> bool {
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        let valid = match c {
+            '\\' => matches!(chars.next(), Some(c) if c == ' ' || c.is_ascii_graphic()),
+            '"' => false,
+            ' ' => true,
+            c => is_printable(c),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+/// Validates a domain, either a list of labels or an address literal
+fn check_domain(domain: &str) -> Result<(), AddressError> {
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err(AddressError::DomainTooLong);
+    }
+    let valid = if domain.starts_with('[') && domain.ends_with(']') {
+        let literal = &domain[1..domain.len() - 1];
+        match literal.strip_prefix("IPv6:") {
+            Some(ipv6) => ipv6.parse::<Ipv6Addr>().is_ok(),
+            None => literal.parse::<Ipv4Addr>().is_ok(),
+        }
+    } else {
+        domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LEN
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii() && is_printable(c)
+                })
+        }) && (domain.is_ascii() || domain_to_ascii(domain).is_ok())
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(AddressError::InvalidDomain)
+    }
+}
+
+/// Characters allowed in an atom
+///
+/// [RFC 5322, section 3.2.3](https://tools.ietf.org/html/rfc5322#section-3.2.3)
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || "!#$%&'*+-/=?^_`{|}~".contains(c)
+        || !c.is_ascii() && is_printable(c)
+}
+
+/// Checks that a character is neither a control character nor a space
+fn is_printable(c: char) -> bool {
+    !c.is_control() && !c.is_whitespace()
 }
 
 impl FromStr for EmailAddress {
@@ -108,6 +250,9 @@ pub struct Envelope {
 
 impl Envelope {
     /// Creates a new envelope, which may fail if `to` is empty.
+    ///
+    /// Without sender, the envelope has the null reverse path `<>`, prefer `bounce` when
+    /// this is intended.
     pub fn new(from: Option<EmailAddress>, to: Vec<EmailAddress>) -> EmailResult<Envelope> {
         if to.is_empty() {
             return Err(Error::MissingTo);
@@ -118,6 +263,43 @@ impl Envelope {
         })
     }
 
+    /// Creates the envelope of a bounce or another delivery status notification, with the
+    /// null reverse path `<>` so that it is never bounced back
+    ///
+    /// [RFC 5321, section 4.5.5](https://tools.ietf.org/html/rfc5321#section-4.5.5)
+    pub fn bounce(to: Vec<EmailAddress>) -> EmailResult<Envelope> {
+        Envelope::new(None, to)
+    }
+
+    /// Creates a new envelope from any iterator of recipients
+    ///
+    /// Unlike `new`, the recipients are validated again, as addresses can be created
+    /// without validation when deserialized.
+    pub fn with_recipients<I: IntoIterator<Item = EmailAddress>>(
+        from: Option<EmailAddress>,
+        to: I,
+    ) -> EmailResult<Envelope> {
+        let to = to.into_iter().collect::<Vec<EmailAddress>>();
+        for address in &to {
+            check_address(address.as_ref()).map_err(Error::InvalidEmailAddress)?;
+        }
+        Envelope::new(from, to)
+    }
+
+    /// Removes duplicated recipients, keeping the first occurrence of each one, and returns
+    /// the number of removed recipients
+    ///
+    /// Domains are compared case-insensitively, local parts case-sensitively.
+    pub fn dedup_recipients(&mut self) -> usize {
+        let before = self.forward_path.len();
+        let mut seen = HashSet::new();
+        self.forward_path.retain(|address| {
+            let (user, domain) = address.split();
+            seen.insert((user.to_string(), domain.to_lowercase()))
+        });
+        before - self.forward_path.len()
+    }
+
     /// Destination addresses of the envelope
     pub fn to(&self) -> &[EmailAddress] {
         self.forward_path.as_slice()
@@ -127,6 +309,11 @@ impl Envelope {
     pub fn from(&self) -> Option<&EmailAddress> {
         self.reverse_path.as_ref()
     }
+
+    /// Whether the envelope has the null reverse path, like bounces
+    pub fn is_bounce(&self) -> bool {
+        self.reverse_path.is_none()
+    }
 }
 
 pub enum Message {
@@ -159,6 +346,16 @@ impl SendableEmail {
         }
     }
 
+    /// Creates an email from a shared buffer, like an `Arc<[u8]>` or a `Bytes`, which is
+    /// read in place instead of being copied
+    pub fn new_with_bytes<B: AsRef<[u8]> + Send + 'static>(
+        envelope: Envelope,
+        message_id: String,
+        message: B,
+    ) -> SendableEmail {
+        SendableEmail::new_with_reader(envelope, message_id, Box::new(Cursor::new(message)))
+    }
+
     pub fn new_with_reader(
         envelope: Envelope,
         message_id: String,
@@ -190,6 +387,55 @@ impl SendableEmail {
     }
 }
 
+/// Formatted message with its envelope, which can be sent several times, for example
+/// with different transports, without formatting or copying it again
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RawMessage {
+    envelope: Envelope,
+    message_id: String,
+    bytes: Arc<[u8]>,
+}
+
+impl RawMessage {
+    /// Creates a message from its formatted bytes
+    pub fn new(envelope: Envelope, message_id: String, bytes: Vec<u8>) -> RawMessage {
+        RawMessage {
+            envelope,
+            message_id,
+            bytes: bytes.into(),
+        }
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// Formatted message
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<'a> From<&'a RawMessage> for SendableEmail {
+    fn from(message: &'a RawMessage) -> SendableEmail {
+        SendableEmail::new_with_bytes(
+            message.envelope.clone(),
+            message.message_id.clone(),
+            Arc::clone(&message.bytes),
+        )
+    }
+}
+
+impl From<RawMessage> for SendableEmail {
+    fn from(message: RawMessage) -> SendableEmail {
+        SendableEmail::from(&message)
+    }
+}
+
 /// Transport method for emails
 pub trait Transport<'a> {
     /// Result type for the transport
@@ -198,3 +444,190 @@ pub trait Transport<'a> {
     /// Sends the email
     fn send<E: Into<SendableEmail>>(&mut self, email: E) -> Self::Result;
 }
+
+#[cfg(test)]
+mod test {
+    use super::{EmailAddress, Envelope};
+    use crate::error::{AddressError, Error};
+
+    fn check(address: &str) -> Result<(), AddressError> {
+        match EmailAddress::new(address.to_string()) {
+            Ok(_) => Ok(()),
+            Err(Error::InvalidEmailAddress(err)) => Err(err),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_email_address_valid() {
+        for address in &[
+            "user@localhost",
+            "user@example.com",
+            "first.last+tag@sub.example.com",
+            "!#$%&'*+-/=?^_`{|}~@example.com",
+            "\"john doe\"@example.com",
+            "\"john\\\"doe\"@example.com",
+            "\"john@doe\"@example.com",
+            "user@[127.0.0.1]",
+            "user@[IPv6:::1]",
+            "üser@exämple.com",
+        ] {
+            assert_eq!(check(address), Ok(()), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_email_address_invalid() {
+        for &(address, err) in &[
+            ("user.example.com", AddressError::MissingSeparator),
+            ("", AddressError::MissingSeparator),
+            ("@example.com", AddressError::InvalidUser),
+            ("us er@example.com", AddressError::InvalidUser),
+            ("user.@example.com", AddressError::InvalidUser),
+            ("us..er@example.com", AddressError::InvalidUser),
+            ("us\ter@example.com", AddressError::InvalidUser),
+            ("us\u{0}er@example.com", AddressError::InvalidUser),
+            ("\"john\"doe\"@example.com", AddressError::InvalidUser),
+            ("\"john\ndoe\"@example.com", AddressError::InvalidUser),
+            ("user@", AddressError::InvalidDomain),
+            ("user@example..com", AddressError::InvalidDomain),
+            ("user@.example.com", AddressError::InvalidDomain),
+            ("user@example.com.", AddressError::InvalidDomain),
+            ("user@-example.com", AddressError::InvalidDomain),
+            ("user@exa_mple.com", AddressError::InvalidDomain),
+            ("user@[127.0.0.256]", AddressError::InvalidDomain),
+        ] {
+            assert_eq!(check(address), Err(err), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_email_address_parse() {
+        assert_eq!(
+            EmailAddress::parse("user@example.com"),
+            Ok(EmailAddress("user@example.com".to_string()))
+        );
+        assert_eq!(
+            EmailAddress::parse("user.example.com"),
+            Err(AddressError::MissingSeparator)
+        );
+        assert_eq!(
+            EmailAddress::parse("user@host@example.com"),
+            Err(AddressError::InvalidUser)
+        );
+        assert_eq!(
+            EmailAddress::parse(" user@example.com"),
+            Err(AddressError::InvalidUser)
+        );
+        assert_eq!(
+            EmailAddress::parse("user@example.com "),
+            Err(AddressError::InvalidDomain)
+        );
+    }
+
+    #[test]
+    fn test_email_address_length() {
+        let user = "a".repeat(64);
+        assert_eq!(check(&format!("{}@example.com", user)), Ok(()));
+        assert_eq!(
+            check(&format!("a{}@example.com", user)),
+            Err(AddressError::UserTooLong)
+        );
+
+        let label = "a".repeat(63);
+        assert_eq!(check(&format!("user@{}.com", label)), Ok(()));
+        assert_eq!(
+            check(&format!("user@a{}.com", label)),
+            Err(AddressError::InvalidDomain)
+        );
+
+        let domain = [label.as_str(); 5].join(".");
+        assert_eq!(
+            check(&format!("user@{}", domain)),
+            Err(AddressError::DomainTooLong)
+        );
+
+        let domain = [label.as_str(); 3].join(".");
+        assert_eq!(
+            check(&format!("{}@{}.com", "a".repeat(60), domain)),
+            Err(AddressError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_envelope_dedup_recipients() {
+        let address = |address: &str| EmailAddress::new(address.to_string()).unwrap();
+        let mut envelope = Envelope::with_recipients(
+            None,
+            vec![
+                address("bob@example.com"),
+                address("alice@example.com"),
+                address("bob@EXAMPLE.com"),
+                address("Bob@example.com"),
+                address("alice@example.com"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(envelope.dedup_recipients(), 2);
+        assert_eq!(
+            envelope.to(),
+            &[
+                address("bob@example.com"),
+                address("alice@example.com"),
+                address("Bob@example.com"),
+            ]
+        );
+        assert_eq!(envelope.dedup_recipients(), 0);
+    }
+
+    #[test]
+    fn test_envelope_with_recipients() {
+        let envelope = Envelope::with_recipients(
+            None,
+            "a@example.com b@example.com"
+                .split(' ')
+                .map(|address| EmailAddress::new(address.to_string()).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(envelope.to().len(), 2);
+
+        assert!(Envelope::with_recipients(None, vec![]).is_err());
+        assert!(
+            Envelope::with_recipients(None, vec![EmailAddress("invalid".to_string())]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_envelope_bounce() {
+        let address = EmailAddress::new("user@example.com".to_string()).unwrap();
+        let envelope = Envelope::bounce(vec![address.clone()]).unwrap();
+        assert!(envelope.is_bounce());
+        assert_eq!(envelope.from(), None);
+        assert_eq!(envelope.to(), std::slice::from_ref(&address));
+        assert!(Envelope::bounce(vec![]).is_err());
+
+        assert!(!Envelope::new(Some(address.clone()), vec![address])
+            .unwrap()
+            .is_bounce());
+    }
+
+    #[test]
+    fn test_email_address_to_ascii() {
+        let address = EmailAddress::new("user@exämple.com".to_string()).unwrap();
+        assert_eq!(
+            address.to_ascii().unwrap(),
+            EmailAddress::new("user@xn--exmple-cua.com".to_string()).unwrap()
+        );
+        assert!(address.is_user_ascii());
+
+        let address = EmailAddress::new("user@example.com".to_string()).unwrap();
+        assert_eq!(address.to_ascii().unwrap(), address);
+
+        // Addresses can be built without validation through deserialization
+        let invalid = EmailAddress("user@exämple.xn--a".to_string());
+        assert!(invalid.to_ascii().is_err());
+
+        assert!(EmailAddress::new("user@exä mple.com".to_string()).is_err());
+    }
+}
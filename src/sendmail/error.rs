@@ -21,23 +21,19 @@ pub enum Error {
 
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        fmt.write_str(self.description())
-    }
-}
-
-impl StdError for Error {
-    fn description(&self) -> &str {
         match *self {
-            Client(ref err) => err,
-            Utf8Parsing(ref err) => err.description(),
-            Io(ref err) => err.description(),
+            Client(ref err) => fmt.write_str(err),
+            Utf8Parsing(ref err) => err.fmt(fmt),
+            Io(ref err) => err.fmt(fmt),
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn StdError> {
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
-            Io(ref err) => Some(&*err),
-            Utf8Parsing(ref err) => Some(&*err),
+            Io(ref err) => Some(err),
+            Utf8Parsing(ref err) => Some(err),
             _ => None,
         }
     }
@@ -57,3 +53,22 @@ impl From<FromUtf8Error> for Error {
 
 /// sendmail result type
 pub type SendmailResult = Result<(), Error>;
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn test_source() {
+        let error = Error::from(io::Error::new(io::ErrorKind::NotFound, "no sendmail"));
+        assert_eq!(error.to_string(), "no sendmail");
+        assert!(error.source().unwrap().is::<io::Error>());
+
+        let error = Error::from(String::from_utf8(vec![0xff]).unwrap_err());
+        assert!(error.source().unwrap().is::<std::string::FromUtf8Error>());
+
+        assert!(Error::Client("failed".to_string()).source().is_none());
+    }
+}
@@ -1,7 +1,12 @@
 //! Provides limited SASL authentication mechanisms
 
 use crate::smtp::error::Error;
-use std::fmt::{self, Display, Formatter};
+use hmac::{Hmac, Mac, NewMac};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt::{self, Debug, Display, Formatter};
 
 /// Accepted authentication mechanisms on an encrypted connection
 /// Trying LOGIN last as it is deprecated.
@@ -30,13 +35,24 @@ impl<S: Into<String>, T: Into<String>> IntoCredentials for (S, T) {
 }
 
 /// Contains user credentials
-#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+///
+/// The password is redacted from the `Debug` output.
+#[derive(PartialEq, Eq, Clone, Hash)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
 pub struct Credentials {
     authentication_identity: String,
     secret: String,
 }
 
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("authentication_identity", &self.authentication_identity)
+            .field("secret", &"***")
+            .finish()
+    }
+}
+
 impl Credentials {
     /// Create a `Credentials` struct from username and password
     pub fn new(username: String, password: String) -> Credentials {
@@ -45,6 +61,23 @@ impl Credentials {
             secret: password,
         }
     }
+
+    /// Returns the username and password prepared with SASLprep, so non-ASCII ones match
+    /// the form stored by the server
+    ///
+    /// [RFC 4013](https://tools.ietf.org/html/rfc4013)
+    fn prepared(&self) -> Result<(Cow<'_, str>, Cow<'_, str>), Error> {
+        Ok((
+            saslprep(&self.authentication_identity)?,
+            saslprep(&self.secret)?,
+        ))
+    }
+}
+
+/// Prepares a string with SASLprep, which fails on prohibited characters
+fn saslprep(value: &str) -> Result<Cow<'_, str>, Error> {
+    stringprep::saslprep(value)
+        .map_err(|_| Error::Client("Credentials contain characters prohibited by SASLprep"))
 }
 
 /// Represents authentication mechanisms
@@ -61,6 +94,12 @@ pub enum Mechanism {
     /// Non-standard XOAUTH2 mechanism
     /// https://developers.google.com/gmail/imap/xoauth2-protocol
     Xoauth2,
+    /// SCRAM-SHA-256 authentication mechanism
+    /// RFC 7677: https://tools.ietf.org/html/rfc7677
+    ///
+    /// The exchange is handled by `ScramSha256`, as it needs to keep state between
+    /// the challenges.
+    ScramSha256,
 }
 
 impl Display for Mechanism {
@@ -72,6 +111,7 @@ impl Display for Mechanism {
                 Mechanism::Plain => "PLAIN",
                 Mechanism::Login => "LOGIN",
                 Mechanism::Xoauth2 => "XOAUTH2",
+                Mechanism::ScramSha256 => "SCRAM-SHA-256",
             }
         )
     }
@@ -81,7 +121,7 @@ impl Mechanism {
     /// Does the mechanism supports initial response
     pub fn supports_initial_response(self) -> bool {
         match self {
-            Mechanism::Plain | Mechanism::Xoauth2 => true,
+            Mechanism::Plain | Mechanism::Xoauth2 | Mechanism::ScramSha256 => true,
             Mechanism::Login => false,
         }
     }
@@ -96,21 +136,22 @@ impl Mechanism {
         match self {
             Mechanism::Plain => match challenge {
                 Some(_) => Err(Error::Client("This mechanism does not expect a challenge")),
-                None => Ok(format!(
-                    "\u{0}{}\u{0}{}",
-                    credentials.authentication_identity, credentials.secret
-                )),
+                None => {
+                    let (username, password) = credentials.prepared()?;
+                    Ok(format!("\u{0}{}\u{0}{}", username, password))
+                }
             },
             Mechanism::Login => {
                 let decoded_challenge =
                     challenge.ok_or(Error::Client("This mechanism does expect a challenge"))?;
+                let (username, password) = credentials.prepared()?;
 
                 if vec!["User Name", "Username:", "Username"].contains(&decoded_challenge) {
-                    return Ok(credentials.authentication_identity.to_string());
+                    return Ok(username.into_owned());
                 }
 
                 if vec!["Password", "Password:"].contains(&decoded_challenge) {
-                    return Ok(credentials.secret.to_string());
+                    return Ok(password.into_owned());
                 }
 
                 Err(Error::Client("Unrecognized challenge"))
@@ -122,13 +163,189 @@ impl Mechanism {
                     credentials.authentication_identity, credentials.secret
                 )),
             },
+            Mechanism::ScramSha256 => match challenge {
+                Some(_) => Err(Error::Client("This mechanism needs a stateful exchange")),
+                None => Ok(ScramSha256::new(credentials)?.client_first()),
+            },
+        }
+    }
+}
+
+/// Length of the nonce generated by the client
+const SCRAM_NONCE_LEN: usize = 24;
+
+/// Maximum iteration count accepted from the server, which could otherwise keep the
+/// client busy hashing the password for hours
+pub const SCRAM_MAX_ITERATIONS: u32 = 100_000;
+
+/// Client side of a SCRAM-SHA-256 exchange
+///
+/// The client sends its first message with a random nonce, computes its proof from the
+/// salt and iteration count sent by the server, and finally checks the signature of the
+/// server, which proves that the server knows the password too.
+///
+/// [RFC 5802](https://tools.ietf.org/html/rfc5802)
+#[derive(Clone)]
+pub struct ScramSha256 {
+    client_nonce: String,
+    client_first_bare: String,
+    secret: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl Debug for ScramSha256 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ScramSha256")
+            .field("client_nonce", &self.client_nonce)
+            .field("client_first_bare", &self.client_first_bare)
+            .field("secret", &"***")
+            .field("server_signature", &self.server_signature)
+            .finish()
+    }
+}
+
+impl ScramSha256 {
+    /// Starts an exchange with a random nonce
+    ///
+    /// Fails if the credentials contain characters prohibited by SASLprep.
+    pub fn new(credentials: &Credentials) -> Result<ScramSha256, Error> {
+        let nonce = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SCRAM_NONCE_LEN)
+            .collect();
+        ScramSha256::with_nonce(credentials, nonce)
+    }
+
+    /// Starts an exchange with the given nonce
+    pub fn with_nonce(credentials: &Credentials, nonce: String) -> Result<ScramSha256, Error> {
+        let (username, password) = credentials.prepared()?;
+        let username = username.replace('=', "=3D").replace(',', "=2C");
+        Ok(ScramSha256 {
+            client_first_bare: format!("n={},r={}", username, nonce),
+            client_nonce: nonce,
+            secret: password.into_owned(),
+            server_signature: None,
+        })
+    }
+
+    /// Returns the first message of the client, without channel binding
+    pub fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    /// Returns the final message of the client, containing its proof, from the first
+    /// message of the server
+    pub fn client_final(&mut self, server_first: &str) -> Result<String, Error> {
+        let (mut nonce, mut salt, mut iterations) = (None, None, None);
+        for attribute in server_first.split(',') {
+            if let Some(value) = attribute.strip_prefix("r=") {
+                nonce = Some(value);
+            } else if let Some(value) = attribute.strip_prefix("s=") {
+                salt = Some(base64::decode(value)?);
+            } else if let Some(value) = attribute.strip_prefix("i=") {
+                iterations = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&i| i > 0 && i <= SCRAM_MAX_ITERATIONS);
+            } else if attribute.starts_with("m=") {
+                return Err(Error::Client("Unsupported mandatory SCRAM extension"));
+            }
+        }
+
+        let nonce = nonce
+            .filter(|nonce| nonce.len() > self.client_nonce.len())
+            .filter(|nonce| nonce.starts_with(&self.client_nonce))
+            .ok_or(Error::ResponseParsing("Invalid SCRAM server nonce"))?;
+        let salt = salt.ok_or(Error::ResponseParsing("Missing SCRAM salt"))?;
+        let iterations =
+            iterations.ok_or(Error::ResponseParsing("Invalid SCRAM iteration count"))?;
+
+        let salted_password = hi(self.secret.as_bytes(), &salt, iterations);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        // "biws" is the base64 encoding of the "n,," GS2 header
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(key, signature)| key ^ signature)
+            .collect::<Vec<u8>>();
+
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        self.server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes()));
+
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            base64::encode(&proof)
+        ))
+    }
+
+    /// Checks the signature contained in the final message of the server
+    pub fn verify_server_final(&self, server_final: &str) -> Result<(), Error> {
+        if server_final.starts_with("e=") {
+            return Err(Error::Client(
+                "The server rejected the SCRAM authentication",
+            ));
+        }
+        let signature = server_final
+            .split(',')
+            .find_map(|attribute| attribute.strip_prefix("v="))
+            .ok_or(Error::ResponseParsing("Missing SCRAM server signature"))?;
+
+        match self.server_signature {
+            Some(ref expected) if *expected == base64::decode(signature)? => Ok(()),
+            _ => Err(Error::Client("Invalid SCRAM server signature")),
         }
     }
 }
 
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `Hi` function of SCRAM, i.e. PBKDF2 with HMAC-SHA-256 and a single output block
+///
+/// [RFC 5802, section 2.2](https://tools.ietf.org/html/rfc5802#section-2.2)
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut previous = hmac_sha256(password, &block);
+    let mut result = previous.clone();
+    for _ in 1..iterations {
+        previous = hmac_sha256(password, &previous);
+        for (byte, previous_byte) in result.iter_mut().zip(previous.iter()) {
+            *byte ^= previous_byte;
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Credentials, Mechanism};
+    use super::{Credentials, Mechanism, ScramSha256, SCRAM_MAX_ITERATIONS};
+    use crate::smtp::error::Error;
+
+    #[test]
+    fn test_debug_redacted() {
+        let credentials = Credentials::new("username".to_string(), "hunter2".to_string());
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("username"));
+        assert!(debug.contains("***"));
+        assert!(!debug.contains("hunter2"));
+
+        let scram = ScramSha256::new(&credentials).unwrap();
+        assert!(!format!("{:?}", scram).contains("hunter2"));
+    }
 
     #[test]
     fn test_plain() {
@@ -175,4 +392,90 @@ mod test {
         );
         assert!(mechanism.response(&credentials, Some("test")).is_err());
     }
+
+    #[test]
+    fn test_scram_sha256() {
+        // Test vectors from RFC 7677, section 3
+        let credentials = Credentials::new("user".to_string(), "pencil".to_string());
+        let mut scram =
+            ScramSha256::with_nonce(&credentials, "rOprNGfwEbeRWgbNEkqO".to_string()).unwrap();
+
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(
+            scram
+                .client_final(
+                    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+                )
+                .unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram
+            .verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+        assert!(scram
+            .verify_server_final("v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_err());
+        assert!(scram.verify_server_final("e=invalid-proof").is_err());
+    }
+
+    #[test]
+    fn test_scram_sha256_invalid_server_first() {
+        let credentials = Credentials::new("us,er=".to_string(), "pencil".to_string());
+        let mut scram = ScramSha256::with_nonce(&credentials, "nonce".to_string()).unwrap();
+        assert_eq!(scram.client_first(), "n,,n=us=2Cer=3D,r=nonce");
+
+        // The server nonce must extend the client nonce
+        assert!(scram.client_final("r=other,s=c2FsdA==,i=4096").is_err());
+        assert!(scram.client_final("r=nonce,s=c2FsdA==,i=4096").is_err());
+        assert!(scram.client_final("r=nonce123,s=c2FsdA==,i=0").is_err());
+        match scram.client_final(&format!(
+            "r=nonce123,s=c2FsdA==,i={}",
+            SCRAM_MAX_ITERATIONS + 1
+        )) {
+            Err(Error::ResponseParsing(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(scram
+            .client_final(&format!("r=nonce123,s=c2FsdA==,i={}", u32::MAX))
+            .is_err());
+        assert!(scram.client_final("r=nonce123,i=4096").is_err());
+        assert!(scram
+            .client_final("m=ext,r=nonce123,s=c2FsdA==,i=4096")
+            .is_err());
+        // No signature to verify before the final message of the client
+        assert!(scram
+            .verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_err());
+    }
+
+    #[test]
+    fn test_saslprep() {
+        // Non-ASCII spaces are mapped to spaces, and soft hyphens removed
+        let credentials = Credentials::new(
+            "us\u{ad}er".to_string(),
+            "pass\u{a0}w\u{2163}rd".to_string(),
+        );
+        assert_eq!(
+            Mechanism::Plain.response(&credentials, None).unwrap(),
+            "\u{0}user\u{0}pass wIVrd"
+        );
+        assert_eq!(
+            Mechanism::Login
+                .response(&credentials, Some("Password:"))
+                .unwrap(),
+            "pass wIVrd"
+        );
+        let scram = ScramSha256::with_nonce(&credentials, "nonce".to_string()).unwrap();
+        assert_eq!(scram.client_first(), "n,,n=user,r=nonce");
+
+        // Control characters are prohibited
+        let credentials = Credentials::new("user".to_string(), "pass\u{7}word".to_string());
+        assert!(Mechanism::Plain.response(&credentials, None).is_err());
+        assert!(Mechanism::Login
+            .response(&credentials, Some("Password:"))
+            .is_err());
+        assert!(ScramSha256::with_nonce(&credentials, "nonce".to_string()).is_err());
+    }
 }
@@ -10,6 +10,7 @@ pub type MockCursor = Cursor<Vec<u8>>;
 pub struct MockStream {
     reader: Arc<Mutex<MockCursor>>,
     writer: Arc<Mutex<MockCursor>>,
+    encrypted: bool,
 }
 
 impl Default for MockStream {
@@ -23,6 +24,7 @@ impl MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
             writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            encrypted: false,
         }
     }
 
@@ -30,9 +32,19 @@ impl MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(vec))),
             writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            encrypted: false,
         }
     }
 
+    /// Makes the stream behave like an encrypted connection
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
     pub fn take_vec(&mut self) -> Vec<u8> {
         let mut cursor = self.writer.lock().unwrap();
         let vec = cursor.get_ref().to_vec();
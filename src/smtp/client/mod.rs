@@ -1,28 +1,49 @@
 //! SMTP client
 
-use crate::smtp::authentication::{Credentials, Mechanism};
+use crate::smtp::authentication::{Credentials, Mechanism, ScramSha256};
 use crate::smtp::client::net::{ClientTlsParameters, Connector, NetworkStream, Timeout};
 use crate::smtp::commands::*;
 use crate::smtp::error::{Error, SmtpResult};
 use crate::smtp::response::Response;
 use bufstream::BufStream;
 use log::debug;
-use std::fmt::{Debug, Display};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
 use std::string::String;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub mod mock;
 pub mod net;
 
+/// Size of the chunks of the message encoded and written at once
+const MESSAGE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Default maximum number of challenges accepted during authentication
+pub const DEFAULT_MAX_AUTH_CHALLENGES: u8 = 10;
+
+/// Default maximum size of a response, in bytes
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
 /// The codec used for transparency
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClientCodec {
     escape_count: u8,
 }
 
+impl Default for ClientCodec {
+    fn default() -> Self {
+        // The message starts at the beginning of a line
+        ClientCodec { escape_count: 2 }
+    }
+}
+
 impl ClientCodec {
     /// Creates a new client codec
     pub fn new() -> Self {
@@ -30,34 +51,38 @@ impl ClientCodec {
     }
 
     /// Adds transparency
+    ///
+    /// Dots at the beginning of a line are doubled, including after a bare LF, and an empty
+    /// frame writes the end of data indicator, completing the last line if needed.
     /// TODO: replace CR and LF by CRLF
     fn encode(&mut self, frame: &[u8], buf: &mut Vec<u8>) -> Result<(), Error> {
         match frame.len() {
             0 => {
                 match self.escape_count {
-                    0 => buf.write_all(b"\r\n.\r\n")?,
+                    0 | 3 => buf.write_all(b"\r\n.\r\n")?,
                     1 => buf.write_all(b"\n.\r\n")?,
                     2 => buf.write_all(b".\r\n")?,
                     _ => unreachable!(),
                 }
-                self.escape_count = 0;
+                self.escape_count = 2;
                 Ok(())
             }
             _ => {
                 let mut start = 0;
                 for (idx, byte) in frame.iter().enumerate() {
-                    match self.escape_count {
-                        0 => self.escape_count = if *byte == b'\r' { 1 } else { 0 },
-                        1 => self.escape_count = if *byte == b'\n' { 2 } else { 0 },
-                        2 => self.escape_count = if *byte == b'.' { 3 } else { 0 },
-                        _ => unreachable!(),
-                    }
-                    if self.escape_count == 3 {
-                        self.escape_count = 0;
-                        buf.write_all(&frame[start..idx])?;
-                        buf.write_all(b".")?;
-                        start = idx;
-                    }
+                    // 1: after CR, 2: after CRLF, 3: after a bare LF
+                    self.escape_count = match (*byte, self.escape_count) {
+                        (b'\r', _) => 1,
+                        (b'\n', 1) => 2,
+                        (b'\n', _) => 3,
+                        (b'.', 2) | (b'.', 3) => {
+                            buf.write_all(&frame[start..idx])?;
+                            buf.write_all(b".")?;
+                            start = idx;
+                            0
+                        }
+                        _ => 0,
+                    };
                 }
                 buf.write_all(&frame[start..])?;
                 Ok(())
@@ -66,18 +91,88 @@ impl ClientCodec {
     }
 }
 
+/// Decodes the challenge contained in a 334 response
+fn decode_challenge(response: &Response) -> Result<String, Error> {
+    if !response.has_code(334) {
+        return Err(Error::ResponseParsing("Expecting a challenge"));
+    }
+    let encoded_challenge = response
+        .first_word()
+        .ok_or(Error::ResponseParsing("Could not read auth challenge"))?;
+    Ok(String::from_utf8(base64::decode(encoded_challenge)?)?)
+}
+
+/// Converts an IO error of the stream, which is `Error::ConnectionLost` if the server
+/// closed the connection
+fn stream_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => Error::ConnectionLost,
+        _ => From::from(err),
+    }
+}
+
+/// Error returned when the deadline of the exchange has passed
+fn deadline_error() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the deadline of the exchange has passed",
+    ))
+}
+
 /// Returns the string replacing all the CRLF with "\<CRLF\>"
 /// Used for debug displays
 fn escape_crlf(string: &str) -> String {
     string.replace("\r\n", "<CRLF>")
 }
 
+/// Direction of the bytes given to a `Tap`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Direction {
+    /// Bytes written to the server
+    Sent,
+    /// Bytes read from the server
+    Received,
+}
+
+/// Function called with the bytes exchanged with the server
+type TapFn = dyn Fn(&[u8], Direction) + Send + Sync;
+
+/// Callback receiving all the bytes exchanged with the server, for debugging
+#[derive(Clone)]
+pub struct Tap(Arc<TapFn>);
+
+impl Tap {
+    /// Creates a tap from a function
+    pub fn new<F: Fn(&[u8], Direction) + Send + Sync + 'static>(tap: F) -> Tap {
+        Tap(Arc::new(tap))
+    }
+}
+
+impl Debug for Tap {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Tap")
+    }
+}
+
 /// Structure that implements the SMTP client
 #[derive(Debug, Default)]
 pub struct InnerClient<S: Write + Read = NetworkStream> {
     /// TCP stream between client and server
     /// Value is None before connection
     stream: Option<BufStream<S>>,
+    /// Callback receiving the exchanged bytes
+    tap: Option<Tap>,
+    /// Timeout of the IO calls
+    timeout: Option<Duration>,
+    /// Time after which the exchange is aborted
+    deadline: Option<Instant>,
+    /// The written bytes contain credentials, and are not logged
+    redact_log: bool,
+    /// Maximum size of a response, in bytes
+    max_response_size: usize,
 }
 
 macro_rules! return_err (
@@ -91,7 +186,42 @@ impl<S: Write + Read> InnerClient<S> {
     ///
     /// It does not connects to the server, but only creates the `Client`
     pub fn new() -> InnerClient<S> {
-        InnerClient { stream: None }
+        InnerClient {
+            stream: None,
+            tap: None,
+            timeout: None,
+            deadline: None,
+            redact_log: false,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Sets a callback receiving all the bytes written to and read from the server
+    pub fn set_tap(&mut self, tap: Tap) {
+        self.tap = Some(tap);
+    }
+
+    /// Sets the maximum size of a response, in bytes
+    ///
+    /// Reading a longer response fails, so that a server can not exhaust the memory.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.max_response_size = size;
+    }
+}
+
+#[cfg(unix)]
+impl InnerClient<NetworkStream> {
+    /// Connects to a server listening on a unix socket
+    pub fn connect_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        // Connect should not be called when the client is already connected
+        if self.stream.is_some() {
+            return_err!("The connection is already established", self);
+        }
+
+        debug!("connecting to {}", path.as_ref().display());
+
+        self.set_stream(NetworkStream::Unix(UnixStream::connect(path)?));
+        Ok(())
     }
 }
 
@@ -102,15 +232,26 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
         self.stream = None;
     }
 
+    /// Closes the connection without sending QUIT
+    pub fn abort(&mut self) {
+        self.stream = None;
+    }
+
     /// Sets the underlying stream
     pub fn set_stream(&mut self, stream: S) {
         self.stream = Some(BufStream::new(stream));
     }
 
     /// Upgrades the underlying connection to SSL/TLS
-    pub fn upgrade_tls_stream(&mut self, tls_parameters: &ClientTlsParameters) -> io::Result<()> {
+    ///
+    /// The handshake is bounded by the deadline, if any.
+    pub fn upgrade_tls_stream(
+        &mut self,
+        tls_parameters: &ClientTlsParameters,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
         match self.stream {
-            Some(ref mut stream) => stream.get_mut().upgrade_tls(tls_parameters),
+            Some(ref mut stream) => Ok(stream.get_mut().upgrade_tls(tls_parameters)?),
             None => Ok(()),
         }
     }
@@ -125,6 +266,23 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
 
     /// Set timeout
     pub fn set_timeout(&mut self, duration: Option<Duration>) -> io::Result<()> {
+        self.timeout = duration;
+        self.set_stream_timeout(duration)
+    }
+
+    /// Sets a time after which reads and writes fail, bounding the whole exchange even
+    /// if the server answers slowly enough to never reach the timeout
+    ///
+    /// `None` removes the deadline and restores the timeout.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        self.deadline = deadline;
+        if deadline.is_none() {
+            self.set_stream_timeout(self.timeout)?;
+        }
+        Ok(())
+    }
+
+    fn set_stream_timeout(&mut self, duration: Option<Duration>) -> io::Result<()> {
         if let Some(ref mut stream) = self.stream {
             stream.get_mut().set_read_timeout(duration)?;
             stream.get_mut().set_write_timeout(duration)?;
@@ -132,6 +290,30 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
         Ok(())
     }
 
+    /// Shortens a timeout to the time left before the deadline, or fails if it has passed
+    fn bounded_timeout(&self, timeout: Option<Duration>) -> Result<Option<Duration>, Error> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(timeout),
+        };
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if remaining > Duration::from_millis(0) => Ok(match timeout {
+                Some(timeout) if timeout < remaining => Some(timeout),
+                _ => Some(remaining),
+            }),
+            _ => Err(deadline_error()),
+        }
+    }
+
+    /// Fails if the deadline has passed, or shortens the timeout to the remaining time
+    fn check_deadline(&mut self) -> Result<(), Error> {
+        if self.deadline.is_some() {
+            let timeout = self.bounded_timeout(self.timeout)?;
+            self.set_stream_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
     /// Connects to the configured server
     pub fn connect<A: ToSocketAddrs>(
         &mut self,
@@ -144,35 +326,57 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
             return_err!("The connection is already established", self);
         }
 
-        let mut addresses = addr.to_socket_addrs()?;
+        let addresses = addr.to_socket_addrs()?.collect::<Vec<_>>();
 
-        let server_addr = match addresses.next() {
-            Some(addr) => addr,
-            None => return_err!("Could not resolve hostname", self),
-        };
+        if addresses.is_empty() {
+            return_err!("Could not resolve hostname", self);
+        }
 
-        debug!("connecting to {}", server_addr);
+        debug!("connecting to {:?}", addresses);
 
         // Try to connect
-        self.set_stream(Connector::connect(&server_addr, timeout, tls_parameters)?);
+        let timeout = self.bounded_timeout(timeout)?;
+        self.set_stream(Connector::connect_any(&addresses, timeout, tls_parameters)?);
         Ok(())
     }
 
     /// Checks if the server is connected using the NOOP SMTP command
+    ///
+    /// If the check fails, the stream is dropped as it can not be reused, and a new
+    /// connection can be established.
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::wrong_self_convention))]
     pub fn is_connected(&mut self) -> bool {
-        self.stream.is_some() && self.command(NoopCommand).is_ok()
+        let connected = self.stream.is_some() && self.command(NoopCommand).is_ok();
+        if !connected {
+            self.stream = None;
+        }
+        connected
     }
 
     /// Sends an AUTH command with the given mechanism, and handles challenge if needed
     pub fn auth(&mut self, mechanism: Mechanism, credentials: &Credentials) -> SmtpResult {
-        // TODO
-        let mut challenges = 10;
-        let mut response = self.command(AuthCommand::new(mechanism, credentials.clone(), None)?)?;
+        self.auth_with_max_challenges(mechanism, credentials, DEFAULT_MAX_AUTH_CHALLENGES)
+    }
+
+    /// Sends an AUTH command with the given mechanism, and fails if the server sends more
+    /// than `max_challenges` challenges
+    pub fn auth_with_max_challenges(
+        &mut self,
+        mechanism: Mechanism,
+        credentials: &Credentials,
+        max_challenges: u8,
+    ) -> SmtpResult {
+        if mechanism == Mechanism::ScramSha256 {
+            return self.auth_scram_sha256(ScramSha256::new(credentials)?);
+        }
+
+        let mut challenges = max_challenges;
+        let mut response =
+            self.auth_command(AuthCommand::new(mechanism, credentials.clone(), None)?)?;
 
         while challenges > 0 && response.has_code(334) {
             challenges -= 1;
-            response = self.command(AuthCommand::new_from_response(
+            response = self.auth_command(AuthCommand::new_from_response(
                 mechanism,
                 credentials.clone(),
                 &response,
@@ -186,58 +390,185 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
         }
     }
 
+    /// Runs a SCRAM-SHA-256 exchange, and checks the signature of the server
+    fn auth_scram_sha256(&mut self, mut scram: ScramSha256) -> SmtpResult {
+        let response = self.command(format!(
+            "AUTH {} {}\r\n",
+            Mechanism::ScramSha256,
+            base64::encode(&scram.client_first())
+        ))?;
+        let client_final = scram.client_final(&decode_challenge(&response)?)?;
+        let response = self.auth_command(format!("{}\r\n", base64::encode(&client_final)))?;
+
+        // The server sends its signature as a last challenge, and waits for an empty response
+        if let Err(err) = scram.verify_server_final(&decode_challenge(&response)?) {
+            let _ = self.command("*\r\n");
+            return Err(err);
+        }
+        self.command("\r\n")
+    }
+
     /// Sends the message content
     pub fn message(&mut self, message: Box<dyn Read>) -> SmtpResult {
-        let mut out_buf: Vec<u8> = vec![];
+        let mut out_buf: Vec<u8> = Vec::with_capacity(MESSAGE_CHUNK_SIZE);
         let mut codec = ClientCodec::new();
 
-        let mut message_reader = BufReader::new(message);
+        let mut message_reader = BufReader::with_capacity(MESSAGE_CHUNK_SIZE, message);
+        #[cfg(feature = "tracing")]
+        let mut size = 0;
 
         loop {
             out_buf.clear();
 
-            let consumed = match message_reader.fill_buf() {
-                Ok(bytes) => {
-                    codec.encode(bytes, &mut out_buf)?;
-                    bytes.len()
-                }
-                Err(ref err) => panic!("Failed with: {}", err),
+            let consumed = {
+                let bytes = message_reader.fill_buf()?;
+                codec.encode(bytes, &mut out_buf)?;
+                bytes.len()
             };
             message_reader.consume(consumed);
 
             if consumed == 0 {
+                // The end of data indicator, flushing the whole message
+                self.write(out_buf.as_slice())?;
                 break;
             }
-
-            self.write(out_buf.as_slice())?;
+            self.write_unflushed(out_buf.as_slice())?;
+            #[cfg(feature = "tracing")]
+            {
+                size += consumed as u64;
+            }
         }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("size", size);
 
-        self.write(b"\r\n.\r\n")?;
         self.read_response()
     }
 
     /// Sends an SMTP command
     pub fn command<C: Display>(&mut self, command: C) -> SmtpResult {
-        self.write(command.to_string().as_bytes())?;
+        let command = command.to_string();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(command = %escape_crlf(&command), "smtp command");
+        self.write(command.as_bytes())?;
+        self.read_response()
+    }
+
+    /// Sends an authentication command, without logging the credentials it contains
+    fn auth_command<C: Display>(&mut self, command: C) -> SmtpResult {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(command = "<redacted credentials>", "smtp command");
+        self.redact_log = true;
+        let result = self.write(command.to_string().as_bytes());
+        self.redact_log = false;
+        result?;
         self.read_response()
     }
 
+    /// Sends a command line that is not modeled by lettre, like `XCLIENT`, and returns
+    /// the response
+    ///
+    /// The `CRLF` is added to the command, which must not contain line breaks. The state
+    /// of the transport, like the encryption or the server information, is not updated.
+    pub fn command_raw(&mut self, command: &str) -> SmtpResult {
+        if command.contains(&['\r', '\n'][..]) {
+            return Err(From::from("Command contains a line break"));
+        }
+        self.command(format!("{}\r\n", command))
+    }
+
     /// Writes a string to the server
     fn write(&mut self, string: &[u8]) -> Result<(), Error> {
+        self.write_unflushed(string)?;
+        self.check_deadline()?;
+        self.stream
+            .as_mut()
+            .unwrap()
+            .flush()
+            .map_err(stream_error)?;
+        Ok(())
+    }
+
+    /// Writes a string to the stream, without flushing it
+    fn write_unflushed(&mut self, string: &[u8]) -> Result<(), Error> {
         if self.stream.is_none() {
             return Err(From::from("Connection closed"));
         }
+        self.check_deadline()?;
 
-        self.stream.as_mut().unwrap().write_all(string)?;
-        self.stream.as_mut().unwrap().flush()?;
+        self.stream
+            .as_mut()
+            .unwrap()
+            .write_all(string)
+            .map_err(stream_error)?;
 
-        debug!(
-            "Wrote: {}",
-            escape_crlf(String::from_utf8_lossy(string).as_ref())
-        );
+        if let Some(Tap(ref tap)) = self.tap {
+            tap(string, Direction::Sent);
+        }
+
+        if self.redact_log {
+            debug!("Wrote: <redacted credentials>");
+        } else {
+            debug!(
+                "Wrote: {}",
+                escape_crlf(String::from_utf8_lossy(string).as_ref())
+            );
+        }
         Ok(())
     }
 
+    /// Reads a line, checking the deadline before each read from the stream
+    fn read_line(&mut self, line: &mut String, limit: usize) -> Result<usize, Error> {
+        let mut bytes = vec![];
+        loop {
+            self.check_deadline()?;
+            let stream = self.stream.as_mut().unwrap();
+            let (consumed, complete) = {
+                let available = match stream.fill_buf() {
+                    Ok(available) => available,
+                    // The read timeout was shortened to the deadline
+                    Err(ref err)
+                        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+                            && matches!(
+                                err.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                    {
+                        return Err(deadline_error());
+                    }
+                    Err(err) => return Err(stream_error(err)),
+                };
+                match available.iter().position(|&byte| byte == b'\n') {
+                    Some(idx) => {
+                        bytes.extend_from_slice(&available[..=idx]);
+                        (idx + 1, true)
+                    }
+                    None => {
+                        bytes.extend_from_slice(available);
+                        (available.len(), available.is_empty())
+                    }
+                }
+            };
+            stream.consume(consumed);
+            if bytes.len() > limit {
+                return Err(Error::ResponseParsing(
+                    "The response exceeds the maximum size",
+                ));
+            }
+            if complete {
+                break;
+            }
+        }
+
+        let read = String::from_utf8(bytes).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        })?;
+        line.push_str(&read);
+        Ok(read.len())
+    }
+
     /// Gets the SMTP response
     pub fn read_response(&mut self) -> SmtpResult {
         let mut raw_response = String::new();
@@ -250,19 +581,29 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
                 break;
             }
             // TODO read more than one line
-            let read_count = self.stream.as_mut().unwrap().read_line(&mut raw_response)?;
+            let limit = self.max_response_size.saturating_sub(raw_response.len());
+            let read_count = self.read_line(&mut raw_response, limit)?;
 
-            // EOF is reached
+            // EOF is reached before the end of the response
             if read_count == 0 {
-                break;
+                return Err(Error::ConnectionLost);
             }
 
             response = raw_response.parse::<Response>();
         }
 
         debug!("Read: {}", escape_crlf(raw_response.as_ref()));
+        if let Some(Tap(ref tap)) = self.tap {
+            tap(raw_response.as_bytes(), Direction::Received);
+        }
 
         let final_response = response?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            code = %final_response.code,
+            message = %final_response.message(),
+            "smtp response"
+        );
 
         if final_response.is_positive() {
             Ok(final_response)
@@ -274,7 +615,33 @@ impl<S: Connector + Write + Read + Timeout + Debug> InnerClient<S> {
 
 #[cfg(test)]
 mod test {
-    use super::{escape_crlf, ClientCodec};
+    use super::mock::MockStream;
+    use super::net::ClientTlsParameters;
+    use super::{escape_crlf, ClientCodec, InnerClient, NetworkStream, MESSAGE_CHUNK_SIZE};
+    use crate::smtp::authentication::{Credentials, Mechanism, ScramSha256};
+    use crate::smtp::commands::EhloCommand;
+    use crate::smtp::error::Error;
+    use crate::smtp::extension::{ClientId, Extension, ServerInfo};
+    use native_tls::TlsConnector;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Reader returning its content in chunks of the given sizes
+    struct ChunkedReader {
+        chunks: Vec<&'static [u8]>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
 
     #[test]
     fn test_codec() {
@@ -292,10 +659,279 @@ mod test {
         assert!(codec.encode(b"test", &mut buf).is_ok());
         assert_eq!(
             String::from_utf8(buf).unwrap(),
-            "test\r\n..\r\n\r\ntestte\r\n..\r\nsttesttest.test\n.test\ntest"
+            "test\r\n..\r\n\r\ntestte\r\n..\r\nsttesttest.test\n..test\ntest"
+        );
+    }
+
+    #[test]
+    fn test_codec_end_of_data() {
+        let mut buf: Vec<u8> = vec![];
+        let mut codec = ClientCodec::new();
+        assert!(codec.encode(b".test\r\n", &mut buf).is_ok());
+        assert!(codec.encode(b"", &mut buf).is_ok());
+        assert_eq!(buf, b"..test\r\n.\r\n");
+
+        buf.clear();
+        let mut codec = ClientCodec::new();
+        assert!(codec.encode(b"test\n", &mut buf).is_ok());
+        assert!(codec.encode(b"", &mut buf).is_ok());
+        assert_eq!(buf, b"test\n\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_message_lone_dot() {
+        let mut mock = MockStream::with_vec(b"250 OK\r\n250 OK\r\n".to_vec());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        assert!(client
+            .message(Box::new(&b"Hello\r\n.\r\nWorld\r\n"[..]))
+            .is_ok());
+        assert_eq!(mock.take_vec(), b"Hello\r\n..\r\nWorld\r\n.\r\n");
+
+        assert!(client.message(Box::new(&b"Hello\n.\nWorld"[..])).is_ok());
+        assert_eq!(mock.take_vec(), b"Hello\n..\nWorld\r\n.\r\n");
+    }
+
+    #[test]
+    fn test_message_termination() {
+        let mut mock = MockStream::with_vec(b"250 OK\r\n".repeat(4));
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        // No blank line is added when the body already ends with CRLF
+        assert!(client.message(Box::new(&b"Hello\r\n"[..])).is_ok());
+        assert_eq!(mock.take_vec(), b"Hello\r\n.\r\n");
+
+        assert!(client.message(Box::new(&b"Hello"[..])).is_ok());
+        assert_eq!(mock.take_vec(), b"Hello\r\n.\r\n");
+
+        let reader = ChunkedReader {
+            chunks: vec![b"Hello\r", b"\n"],
+        };
+        assert!(client.message(Box::new(reader)).is_ok());
+        assert_eq!(mock.take_vec(), b"Hello\r\n.\r\n");
+
+        assert!(client.message(Box::new(&b""[..])).is_ok());
+        assert_eq!(mock.take_vec(), b".\r\n");
+    }
+
+    #[test]
+    fn test_command_raw() {
+        let mut mock = MockStream::with_vec(b"250 2.0.0 Ok\r\n".to_vec());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        let response = client
+            .command_raw("XCLIENT NAME=spike.example.com ADDR=192.0.2.1")
+            .unwrap();
+        assert!(response.has_code(250));
+        assert_eq!(response.message, ["2.0.0 Ok"]);
+        assert_eq!(
+            mock.take_vec(),
+            b"XCLIENT NAME=spike.example.com ADDR=192.0.2.1\r\n"
+        );
+
+        assert!(client.command_raw("NOOP\r\nRSET").is_err());
+        assert!(mock.take_vec().is_empty());
+    }
+
+    #[test]
+    fn test_message_chunked() {
+        let mut mock = MockStream::with_vec(b"250 OK\r\n".to_vec());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        let reader = ChunkedReader {
+            chunks: vec![b"Hello\r\n", b".one\r", b"\n", b".two\r\n.", b"\r\nEnd\r\n"],
+        };
+        assert!(client.message(Box::new(reader)).is_ok());
+        assert_eq!(
+            mock.take_vec(),
+            b"Hello\r\n..one\r\n..two\r\n..\r\nEnd\r\n.\r\n".to_vec()
         );
     }
 
+    #[test]
+    fn test_message_large() {
+        let mut mock = MockStream::with_vec(b"250 OK\r\n".to_vec());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        // Lines of varying length, so that dots fall on chunk boundaries
+        let mut message = String::new();
+        let mut expected = String::new();
+        for idx in 0..10_000 {
+            let line = format!(".{}\r\n", "x".repeat(idx % 13));
+            message.push_str(&line);
+            expected.push('.');
+            expected.push_str(&line);
+        }
+        expected.push_str(".\r\n");
+
+        assert!(message.len() > 4 * MESSAGE_CHUNK_SIZE);
+        assert!(client
+            .message(Box::new(io::Cursor::new(message.into_bytes())))
+            .is_ok());
+        assert_eq!(mock.take_vec(), expected.into_bytes());
+    }
+
+    #[test]
+    fn test_read_multiline_ehlo() {
+        let mut mock = MockStream::with_vec(
+            b"250-mail.example.com Hello client.example.com\r\n\
+              250-SIZE 35882577\r\n\
+              250-8BITMIME\r\n\
+              250-PIPELINING\r\n\
+              250-AUTH PLAIN LOGIN XOAUTH2\r\n\
+              250-ENHANCEDSTATUSCODES\r\n\
+              250-SMTPUTF8\r\n\
+              250 STARTTLS\r\n\
+              250 OK\r\n"
+                .to_vec(),
+        );
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        let response = client
+            .command(EhloCommand::new(ClientId::new(
+                "client.example.com".to_string(),
+            )))
+            .unwrap();
+        assert_eq!(response.message.len(), 8);
+
+        let server_info = ServerInfo::from_response(&response).unwrap();
+        assert_eq!(server_info.name, "mail.example.com");
+        for feature in &[
+            Extension::StartTls,
+            Extension::EightBitMime,
+            Extension::SmtpUtfEight,
+            Extension::Authentication(Mechanism::Plain),
+            Extension::Authentication(Mechanism::Login),
+            Extension::Authentication(Mechanism::Xoauth2),
+        ] {
+            assert!(server_info.supports_feature(*feature));
+        }
+        assert_eq!(server_info.features.len(), 6);
+        assert_eq!(mock.take_vec(), b"EHLO client.example.com\r\n");
+
+        // The next response is still available
+        assert!(client.read_response().unwrap().has_code(250));
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        let mock = MockStream::with_vec(format!("250 {}\r\n", "a".repeat(100)).into_bytes());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock));
+        client.set_max_response_size(50);
+        match client.read_response() {
+            Err(Error::ResponseParsing(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // The limit applies to the whole response, not to each line
+        let mock = MockStream::with_vec(b"250-first\r\n250-second\r\n250 third\r\n".to_vec());
+        client.set_stream(NetworkStream::Mock(mock));
+        client.set_max_response_size(25);
+        assert!(client.read_response().is_err());
+
+        let mock = MockStream::with_vec(b"250-first\r\n250-second\r\n250 third\r\n".to_vec());
+        client.set_stream(NetworkStream::Mock(mock));
+        client.set_max_response_size(35);
+        assert_eq!(client.read_response().unwrap().message.len(), 3);
+    }
+
+    #[test]
+    fn test_is_connected() {
+        let mut mock = MockStream::with_vec(b"250 OK\r\n".to_vec());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        assert!(client.is_connected());
+        assert_eq!(mock.take_vec(), b"NOOP\r\n");
+    }
+
+    #[test]
+    fn test_is_connected_dead_connection() {
+        let mut mock = MockStream::new();
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+
+        assert!(!client.is_connected());
+        assert_eq!(mock.take_vec(), b"NOOP\r\n");
+
+        // The stream was dropped, nothing is sent anymore
+        assert!(!client.is_connected());
+        assert!(mock.take_vec().is_empty());
+    }
+
+    fn scram_client(server_final: &str) -> (InnerClient<NetworkStream>, MockStream) {
+        let responses = format!(
+            "334 {}\r\n334 {}\r\n235 2.7.0 Authentication successful\r\n",
+            base64::encode(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+            ),
+            base64::encode(server_final)
+        );
+        let mock = MockStream::with_vec(responses.into_bytes());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+        (client, mock)
+    }
+
+    #[test]
+    fn test_auth_max_challenges() {
+        let mut mock = MockStream::with_vec(b"334 VXNlcm5hbWU6\r\n".repeat(3));
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        client.set_stream(NetworkStream::Mock(mock.clone()));
+        let credentials = Credentials::new("user".to_string(), "password".to_string());
+
+        match client.auth_with_max_challenges(Mechanism::Login, &credentials, 2) {
+            Err(Error::ResponseParsing(message)) => {
+                assert_eq!(message, "Unexpected number of challenges")
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(mock.take_vec(), b"AUTH LOGIN\r\ndXNlcg==\r\ndXNlcg==\r\n");
+    }
+
+    #[test]
+    fn test_auth_scram_sha256() {
+        let credentials = Credentials::new("user".to_string(), "pencil".to_string());
+        let scram =
+            ScramSha256::with_nonce(&credentials, "rOprNGfwEbeRWgbNEkqO".to_string()).unwrap();
+        let (mut client, mut mock) = scram_client("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+        assert!(client.auth_scram_sha256(scram).is_ok());
+        assert_eq!(
+            String::from_utf8(mock.take_vec()).unwrap(),
+            format!(
+                "AUTH SCRAM-SHA-256 {}\r\n{}\r\n\r\n",
+                base64::encode("n,,n=user,r=rOprNGfwEbeRWgbNEkqO"),
+                base64::encode(
+                    "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_auth_scram_sha256_invalid_signature() {
+        let credentials = Credentials::new("user".to_string(), "pencil".to_string());
+        let scram =
+            ScramSha256::with_nonce(&credentials, "rOprNGfwEbeRWgbNEkqO".to_string()).unwrap();
+        let (mut client, mut mock) = scram_client("v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+        assert!(client.auth_scram_sha256(scram).is_err());
+        // The exchange is cancelled
+        assert!(String::from_utf8(mock.take_vec())
+            .unwrap()
+            .ends_with("\r\n*\r\n"));
+    }
+
     #[test]
     fn test_escape_crlf() {
         assert_eq!(escape_crlf("\r\n"), "<CRLF>");
@@ -305,4 +941,24 @@ mod test {
             "EHLO my_name<CRLF>SIZE 42<CRLF>"
         );
     }
+
+    #[test]
+    fn test_connect_tls_error() {
+        // A server answering in plain text to the TLS handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"220 smtp.example.com ESMTP\r\n");
+        });
+
+        let tls_parameters =
+            ClientTlsParameters::new("localhost".to_string(), TlsConnector::new().unwrap());
+        let mut client: InnerClient<NetworkStream> = InnerClient::new();
+        match client.connect(&addr, None, Some(&tls_parameters)) {
+            Err(Error::Tls(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        server.join().unwrap();
+    }
 }
@@ -1,11 +1,20 @@
 //! A trait to represent a stream
 
 use crate::smtp::client::mock::MockStream;
-use native_tls::{Protocol, TlsConnector, TlsStream};
+use native_tls::{HandshakeError, Protocol, TlsConnector, TlsStream};
 use std::io::{self, ErrorKind, Read, Write};
 use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
 use std::time::Duration;
 
+/// Delay before starting a connection attempt to the next address
+///
+/// [RFC 8305, section 5](https://tools.ietf.org/html/rfc8305#section-5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 /// Parameters to use for secure clients
 #[derive(Clone)]
 #[allow(missing_debug_implementations)]
@@ -21,6 +30,62 @@ impl ClientTlsParameters {
     pub fn new(domain: String, connector: TlsConnector) -> ClientTlsParameters {
         ClientTlsParameters { connector, domain }
     }
+
+    /// Creates a builder for `ClientTlsParameters`, using `TLS 1.2` as minimum version
+    pub fn builder(domain: String) -> ClientTlsParametersBuilder {
+        ClientTlsParametersBuilder {
+            domain,
+            min_tls_version: TlsVersion::Tlsv12,
+        }
+    }
+}
+
+/// Builder for `ClientTlsParameters`
+#[derive(Clone, Debug)]
+pub struct ClientTlsParametersBuilder {
+    domain: String,
+    min_tls_version: TlsVersion,
+}
+
+impl ClientTlsParametersBuilder {
+    /// Sets the minimum accepted TLS version
+    pub fn min_tls_version(mut self, version: TlsVersion) -> ClientTlsParametersBuilder {
+        self.min_tls_version = version;
+        self
+    }
+
+    /// Builds the `ClientTlsParameters`, creating a connector configured accordingly
+    pub fn build(self) -> Result<ClientTlsParameters, native_tls::Error> {
+        let connector = TlsConnector::builder()
+            .min_protocol_version(Some(self.min_tls_version.into()))
+            .build()?;
+        Ok(ClientTlsParameters::new(self.domain, connector))
+    }
+}
+
+/// TLS protocol versions
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
+pub enum TlsVersion {
+    /// TLS 1.0, deprecated by [RFC 8996](https://tools.ietf.org/html/rfc8996)
+    Tlsv10,
+    /// TLS 1.1, deprecated by [RFC 8996](https://tools.ietf.org/html/rfc8996)
+    Tlsv11,
+    /// TLS 1.2
+    Tlsv12,
+    /// TLS 1.3, which may not be supported on all platforms
+    Tlsv13,
+}
+
+impl From<TlsVersion> for Protocol {
+    fn from(version: TlsVersion) -> Protocol {
+        match version {
+            TlsVersion::Tlsv10 => Protocol::Tlsv10,
+            TlsVersion::Tlsv11 => Protocol::Tlsv11,
+            TlsVersion::Tlsv12 => Protocol::Tlsv12,
+            TlsVersion::Tlsv13 => Protocol::Tlsv13,
+        }
+    }
 }
 
 /// Accepted protocols by default.
@@ -34,6 +99,9 @@ pub enum NetworkStream {
     Tcp(TcpStream),
     /// Encrypted TCP stream
     Tls(Box<TlsStream<TcpStream>>),
+    /// Unix domain socket, for local submission
+    #[cfg(unix)]
+    Unix(UnixStream),
     /// Mock stream
     Mock(MockStream),
 }
@@ -44,6 +112,11 @@ impl NetworkStream {
         match *self {
             NetworkStream::Tcp(ref s) => s.peer_addr(),
             NetworkStream::Tls(ref s) => s.get_ref().peer_addr(),
+            #[cfg(unix)]
+            NetworkStream::Unix(_) => Err(io::Error::new(
+                ErrorKind::AddrNotAvailable,
+                "a unix socket has no IP address",
+            )),
             NetworkStream::Mock(_) => Ok(SocketAddr::V4(SocketAddrV4::new(
                 Ipv4Addr::new(127, 0, 0, 1),
                 80,
@@ -56,6 +129,8 @@ impl NetworkStream {
         match *self {
             NetworkStream::Tcp(ref s) => s.shutdown(how),
             NetworkStream::Tls(ref s) => s.get_ref().shutdown(how),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref s) => s.shutdown(how),
             NetworkStream::Mock(_) => Ok(()),
         }
     }
@@ -66,6 +141,8 @@ impl Read for NetworkStream {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.read(buf),
             NetworkStream::Tls(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref mut s) => s.read(buf),
             NetworkStream::Mock(ref mut s) => s.read(buf),
         }
     }
@@ -76,6 +153,8 @@ impl Write for NetworkStream {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.write(buf),
             NetworkStream::Tls(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref mut s) => s.write(buf),
             NetworkStream::Mock(ref mut s) => s.write(buf),
         }
     }
@@ -84,11 +163,98 @@ impl Write for NetworkStream {
         match *self {
             NetworkStream::Tcp(ref mut s) => s.flush(),
             NetworkStream::Tls(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref mut s) => s.flush(),
             NetworkStream::Mock(ref mut s) => s.flush(),
         }
     }
 }
 
+/// Wraps a failed TLS handshake into an IO error
+///
+/// The `native-tls` error is kept as the inner error, to be converted back into
+/// `Error::Tls` by the client.
+fn handshake_error<S>(err: HandshakeError<S>) -> io::Error {
+    match err {
+        HandshakeError::Failure(err) => io::Error::new(ErrorKind::Other, err),
+        HandshakeError::WouldBlock(_) => io::Error::new(
+            ErrorKind::WouldBlock,
+            "TLS handshake interrupted on a non-blocking stream",
+        ),
+    }
+}
+
+/// Opens a TCP connection
+fn connect_tcp(addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(duration) => TcpStream::connect_timeout(addr, duration),
+        None => TcpStream::connect(addr),
+    }
+}
+
+/// Orders addresses by alternating between IPv6 and IPv4, starting with the family of
+/// the first one
+///
+/// [RFC 8305, section 4](https://tools.ietf.org/html/rfc8305#section-4)
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .cloned()
+        .partition(|addr| addr.is_ipv6() == addrs[0].is_ipv6());
+    first.reverse();
+    second.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop());
+        ordered.extend(second.pop());
+    }
+    ordered
+}
+
+/// Opens a TCP connection to the first address accepting it
+///
+/// A connection attempt is started for each address in turn, without waiting more than
+/// `CONNECTION_ATTEMPT_DELAY` for the previous ones, so that an unreachable address does
+/// not delay the connection.
+fn connect_tcp_any(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_error = None;
+
+    for addr in interleave_families(addrs) {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            // The receiver is gone once a connection succeeded
+            let _ = sender.send(connect_tcp(&addr, timeout));
+        });
+        pending += 1;
+
+        match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => {
+                pending -= 1;
+                last_error = Some(err);
+            }
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => (),
+        }
+    }
+
+    while pending > 0 {
+        match receiver.recv() {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => {
+                pending -= 1;
+                last_error = Some(err);
+            }
+            Err(_) => break,
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to connect to")))
+}
+
 /// A trait for the concept of opening a stream
 pub trait Connector: Sized {
     /// Opens a connection to the given IP socket
@@ -97,30 +263,77 @@ pub trait Connector: Sized {
         timeout: Option<Duration>,
         tls_parameters: Option<&ClientTlsParameters>,
     ) -> io::Result<Self>;
+    /// Opens a connection to one of the given IP sockets
+    ///
+    /// By default, only the first one is tried.
+    fn connect_any(
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+        tls_parameters: Option<&ClientTlsParameters>,
+    ) -> io::Result<Self> {
+        match addrs.first() {
+            Some(addr) => Self::connect(addr, timeout, tls_parameters),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "no address to connect to",
+            )),
+        }
+    }
     /// Upgrades to TLS connection
     fn upgrade_tls(&mut self, tls_parameters: &ClientTlsParameters) -> io::Result<()>;
     /// Is the NetworkStream encrypted
     fn is_encrypted(&self) -> bool;
 }
 
+impl NetworkStream {
+    /// Wraps a TCP stream, starting the TLS handshake if needed
+    ///
+    /// The handshake fails if the server does not answer within the timeout.
+    fn from_tcp(
+        tcp_stream: TcpStream,
+        timeout: Option<Duration>,
+        tls_parameters: Option<&ClientTlsParameters>,
+    ) -> io::Result<NetworkStream> {
+        match tls_parameters {
+            Some(context) => {
+                tcp_stream.set_read_timeout(timeout)?;
+                tcp_stream.set_write_timeout(timeout)?;
+                context
+                    .connector
+                    .connect(context.domain.as_ref(), tcp_stream)
+                    .map(|tls| NetworkStream::Tls(Box::new(tls)))
+                    .map_err(handshake_error)
+            }
+            None => Ok(NetworkStream::Tcp(tcp_stream)),
+        }
+    }
+}
+
 impl Connector for NetworkStream {
     fn connect(
         addr: &SocketAddr,
         timeout: Option<Duration>,
         tls_parameters: Option<&ClientTlsParameters>,
     ) -> io::Result<NetworkStream> {
-        let tcp_stream = match timeout {
-            Some(duration) => TcpStream::connect_timeout(addr, duration)?,
-            None => TcpStream::connect(addr)?,
-        };
+        NetworkStream::from_tcp(connect_tcp(addr, timeout)?, timeout, tls_parameters)
+    }
 
-        match tls_parameters {
-            Some(context) => context
-                .connector
-                .connect(context.domain.as_ref(), tcp_stream)
-                .map(|tls| NetworkStream::Tls(Box::new(tls)))
-                .map_err(|e| io::Error::new(ErrorKind::Other, e)),
-            None => Ok(NetworkStream::Tcp(tcp_stream)),
+    /// Tries all the addresses, racing the connection attempts as described in
+    /// [RFC 8305](https://tools.ietf.org/html/rfc8305)
+    fn connect_any(
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+        tls_parameters: Option<&ClientTlsParameters>,
+    ) -> io::Result<NetworkStream> {
+        match addrs.len() {
+            0 | 1 => match addrs.first() {
+                Some(addr) => NetworkStream::connect(addr, timeout, tls_parameters),
+                None => Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "no address to connect to",
+                )),
+            },
+            _ => NetworkStream::from_tcp(connect_tcp_any(addrs, timeout)?, timeout, tls_parameters),
         }
     }
 
@@ -132,9 +345,16 @@ impl Connector for NetworkStream {
                 .connect(tls_parameters.domain.as_ref(), stream.try_clone().unwrap())
             {
                 Ok(tls_stream) => NetworkStream::Tls(Box::new(tls_stream)),
-                Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+                Err(err) => return Err(handshake_error(err)),
             },
             NetworkStream::Tls(_) => return Ok(()),
+            #[cfg(unix)]
+            NetworkStream::Unix(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "TLS is not supported over unix sockets",
+                ))
+            }
             NetworkStream::Mock(_) => return Ok(()),
         };
 
@@ -146,7 +366,9 @@ impl Connector for NetworkStream {
         match *self {
             NetworkStream::Tcp(_) => false,
             NetworkStream::Tls(_) => true,
-            NetworkStream::Mock(_) => false,
+            #[cfg(unix)]
+            NetworkStream::Unix(_) => false,
+            NetworkStream::Mock(ref s) => s.is_encrypted(),
         }
     }
 }
@@ -164,6 +386,8 @@ impl Timeout for NetworkStream {
         match *self {
             NetworkStream::Tcp(ref mut stream) => stream.set_read_timeout(duration),
             NetworkStream::Tls(ref mut stream) => stream.get_ref().set_read_timeout(duration),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref mut stream) => stream.set_read_timeout(duration),
             NetworkStream::Mock(_) => Ok(()),
         }
     }
@@ -173,7 +397,73 @@ impl Timeout for NetworkStream {
         match *self {
             NetworkStream::Tcp(ref mut stream) => stream.set_write_timeout(duration),
             NetworkStream::Tls(ref mut stream) => stream.get_ref().set_write_timeout(duration),
+            #[cfg(unix)]
+            NetworkStream::Unix(ref mut stream) => stream.set_write_timeout(duration),
             NetworkStream::Mock(_) => Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{interleave_families, ClientTlsParameters, Connector, NetworkStream, TlsVersion};
+    use native_tls::Protocol;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_tls_version() {
+        for &(version, protocol) in &[
+            (TlsVersion::Tlsv10, "Tlsv10"),
+            (TlsVersion::Tlsv11, "Tlsv11"),
+            (TlsVersion::Tlsv12, "Tlsv12"),
+            (TlsVersion::Tlsv13, "Tlsv13"),
+        ] {
+            assert_eq!(format!("{:?}", Protocol::from(version)), protocol);
+        }
+    }
+
+    #[test]
+    fn test_tls_parameters_builder() {
+        let builder = ClientTlsParameters::builder("example.com".to_string());
+        assert_eq!(builder.min_tls_version, TlsVersion::Tlsv12);
+
+        let builder = builder.min_tls_version(TlsVersion::Tlsv13);
+        assert_eq!(builder.min_tls_version, TlsVersion::Tlsv13);
+
+        let parameters = builder.build().unwrap();
+        assert_eq!(parameters.domain, "example.com");
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:25", "[::2]:25", "[::3]:25", "1.1.1.1:25"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(
+            interleave_families(&addrs),
+            vec![addrs[0], addrs[3], addrs[1], addrs[2]]
+        );
+    }
+
+    #[test]
+    fn test_connect_any() {
+        // A port nobody listens on anymore
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        let stream = NetworkStream::connect_any(
+            &[unreachable, listener.local_addr().unwrap()],
+            Some(Duration::from_secs(30)),
+            None,
+        )
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+}
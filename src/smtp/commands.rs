@@ -9,7 +9,7 @@ use crate::EmailAddress;
 use base64;
 use log::debug;
 use std::convert::AsRef;
-use std::fmt::{self, Display, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 
 /// EHLO command
 #[derive(PartialEq, Clone, Debug)]
@@ -111,6 +111,33 @@ impl Display for DataCommand {
     }
 }
 
+/// BURL command, the message content is fetched by the server from an IMAP URL
+///
+/// [RFC 4468](https://tools.ietf.org/html/rfc4468)
+#[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
+pub struct BurlCommand {
+    url: String,
+    last: bool,
+}
+
+impl Display for BurlCommand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "BURL {}", self.url)?;
+        if self.last {
+            f.write_str(" LAST")?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+impl BurlCommand {
+    /// Creates a BURL command, `last` being set on the last part of the message
+    pub fn new(url: String, last: bool) -> BurlCommand {
+        BurlCommand { url, last }
+    }
+}
+
 /// QUIT command
 #[derive(PartialEq, Clone, Debug, Copy)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
@@ -211,7 +238,9 @@ impl Display for RsetCommand {
 }
 
 /// AUTH command
-#[derive(PartialEq, Clone, Debug)]
+///
+/// The response, which contains the credentials, is redacted from the `Debug` output.
+#[derive(PartialEq, Clone)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
 pub struct AuthCommand {
     mechanism: Mechanism,
@@ -220,6 +249,17 @@ pub struct AuthCommand {
     response: Option<String>,
 }
 
+impl Debug for AuthCommand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("AuthCommand")
+            .field("mechanism", &self.mechanism)
+            .field("credentials", &self.credentials)
+            .field("challenge", &self.challenge)
+            .field("response", &self.response.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
 impl Display for AuthCommand {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let encoded_response = self
@@ -292,7 +332,7 @@ impl AuthCommand {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::smtp::extension::MailBodyParameter;
+    use crate::smtp::extension::{DeliverByMode, MailBodyParameter};
 
     #[test]
     fn test_display() {
@@ -307,6 +347,13 @@ mod test {
             value: Some("value".to_string()),
         };
         assert_eq!(format!("{}", EhloCommand::new(id)), "EHLO localhost\r\n");
+        assert_eq!(
+            format!(
+                "{}",
+                EhloCommand::new(ClientId::ip_literal("1.2.3.4".parse().unwrap()))
+            ),
+            "EHLO [1.2.3.4]\r\n"
+        );
         assert_eq!(
             format!("{}", MailCommand::new(Some(email.clone()), vec![])),
             "MAIL FROM:<test@example.com>\r\n"
@@ -336,6 +383,34 @@ mod test {
             ),
             "MAIL FROM:<test@example.com> SIZE=42 BODY=8BITMIME TEST=value\r\n"
         );
+        assert_eq!(
+            format!(
+                "{}",
+                MailCommand::new(
+                    Some(email.clone()),
+                    vec![
+                        MailParameter::Auth(Some(
+                            EmailAddress::new("a+b=c@example.com".to_string()).unwrap()
+                        )),
+                        MailParameter::Auth(None),
+                    ],
+                )
+            ),
+            "MAIL FROM:<test@example.com> AUTH=a+2Bb+3Dc@example.com AUTH=<>\r\n"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                MailCommand::new(
+                    Some(email.clone()),
+                    vec![
+                        MailParameter::DeliverBy(120, DeliverByMode::Return),
+                        MailParameter::DeliverBy(3600, DeliverByMode::Notify),
+                    ],
+                )
+            ),
+            "MAIL FROM:<test@example.com> BY=120;R BY=3600;N\r\n"
+        );
         assert_eq!(
             format!("{}", RcptCommand::new(email.clone(), vec![])),
             "RCPT TO:<test@example.com>\r\n"
@@ -346,6 +421,16 @@ mod test {
         );
         assert_eq!(format!("{}", QuitCommand), "QUIT\r\n");
         assert_eq!(format!("{}", DataCommand), "DATA\r\n");
+        assert_eq!(
+            format!(
+                "{}",
+                BurlCommand::new(
+                    "imap://user@imap.example.com/Drafts;UID=1".to_string(),
+                    true
+                )
+            ),
+            "BURL imap://user@imap.example.com/Drafts;UID=1 LAST\r\n"
+        );
         assert_eq!(format!("{}", NoopCommand), "NOOP\r\n");
         assert_eq!(format!("{}", HelpCommand::new(None)), "HELP\r\n");
         assert_eq!(
@@ -377,4 +462,13 @@ mod test {
             "AUTH LOGIN\r\n"
         );
     }
+
+    #[test]
+    fn test_auth_debug() {
+        let credentials = Credentials::new("user".to_string(), "password".to_string());
+        let command = AuthCommand::new(Mechanism::Plain, credentials, None).unwrap();
+        let debug = format!("{:?}", command);
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("password"));
+    }
 }
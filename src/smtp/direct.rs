@@ -0,0 +1,379 @@
+//! Delivery to the mail exchangers of the recipient domains, without a relay
+//!
+//! [RFC 5321, section 5](https://tools.ietf.org/html/rfc5321#section-5)
+
+use crate::smtp::client::net::ClientTlsParameters;
+use crate::smtp::error::{Error, SmtpResult};
+use crate::smtp::extension::ClientId;
+use crate::smtp::{ClientSecurity, SmtpClient, SMTP_PORT};
+use crate::{EmailAddress, Envelope, SendableEmail, Transport};
+use log::info;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A mail exchanger of a domain, from an MX record
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MxRecord {
+    /// Preference of the exchanger, lower values are tried first
+    pub preference: u16,
+    /// Host name of the exchanger
+    pub exchange: String,
+}
+
+impl MxRecord {
+    /// Creates an MX record
+    pub fn new<S: Into<String>>(preference: u16, exchange: S) -> MxRecord {
+        MxRecord {
+            preference,
+            exchange: exchange.into(),
+        }
+    }
+}
+
+/// Function looking up the MX records of a domain
+type MxLookupFn = dyn Fn(&str) -> io::Result<Vec<MxRecord>> + Send + Sync;
+
+/// Callback looking up the MX records of a domain
+///
+/// lettre does not include a DNS client, so the lookup is done by the application, for
+/// example with `trust-dns-resolver`. An empty list means that the domain has no MX records.
+#[derive(Clone)]
+pub struct MxLookup(Arc<MxLookupFn>);
+
+impl MxLookup {
+    /// Creates a lookup from a function
+    pub fn new<F: Fn(&str) -> io::Result<Vec<MxRecord>> + Send + Sync + 'static>(
+        lookup: F,
+    ) -> MxLookup {
+        MxLookup(Arc::new(lookup))
+    }
+}
+
+impl Debug for MxLookup {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("MxLookup")
+    }
+}
+
+/// Outcome of the delivery to a recipient domain
+#[derive(Debug)]
+pub struct DomainReport {
+    /// Recipient domain
+    pub domain: String,
+    /// Recipients of the domain
+    pub recipients: Vec<EmailAddress>,
+    /// Response of the exchanger which accepted the message, or the last error
+    pub result: SmtpResult,
+}
+
+/// Outcome of a direct delivery, for each recipient domain
+///
+/// The message can be accepted by some domains and fail for others, a retry should then
+/// only target the failed recipients to avoid duplicates.
+#[derive(Debug)]
+pub struct DirectReport {
+    /// Outcome for each domain, in the order of the recipients
+    pub domains: Vec<DomainReport>,
+}
+
+impl DirectReport {
+    /// Checks if every domain accepted the message
+    pub fn is_complete(&self) -> bool {
+        self.domains.iter().all(|domain| domain.result.is_ok())
+    }
+
+    /// Recipients whose domain accepted the message
+    pub fn delivered(&self) -> Vec<&EmailAddress> {
+        self.domains
+            .iter()
+            .filter(|domain| domain.result.is_ok())
+            .flat_map(|domain| domain.recipients.iter())
+            .collect()
+    }
+
+    /// Recipients whose domain failed, with the corresponding error
+    pub fn failed(&self) -> Vec<(&EmailAddress, &Error)> {
+        self.domains
+            .iter()
+            .filter_map(|domain| match domain.result {
+                Err(ref err) => Some((domain, err)),
+                Ok(_) => None,
+            })
+            .flat_map(|(domain, err)| domain.recipients.iter().map(move |to| (to, err)))
+            .collect()
+    }
+}
+
+/// Result of a direct delivery
+pub type DirectResult = Result<DirectReport, Error>;
+
+/// Transport delivering messages to the mail exchangers of their recipient domains
+///
+/// The exchangers of a domain are tried in order of preference, with opportunistic
+/// STARTTLS, until one accepts the message or rejects it permanently. A domain without
+/// MX records is its own exchanger.
+#[derive(Clone, Debug)]
+pub struct DirectTransport {
+    /// Lookup of the MX records
+    lookup: MxLookup,
+    /// Name sent during EHLO
+    hello_name: ClientId,
+    /// Port of the exchangers
+    port: u16,
+    /// Network timeout
+    timeout: Option<Duration>,
+}
+
+impl DirectTransport {
+    /// Creates a transport looking up the exchangers with the given function
+    pub fn new(lookup: MxLookup) -> DirectTransport {
+        DirectTransport {
+            lookup,
+            hello_name: ClientId::hostname(),
+            port: SMTP_PORT,
+            timeout: Some(Duration::new(60, 0)),
+        }
+    }
+
+    /// Set the name used during EHLO
+    ///
+    /// Exchangers often check that it resolves to the address of the client.
+    pub fn hello_name(mut self, name: ClientId) -> DirectTransport {
+        self.hello_name = name;
+        self
+    }
+
+    /// Set the port of the exchangers, 25 by default
+    pub fn port(mut self, port: u16) -> DirectTransport {
+        self.port = port;
+        self
+    }
+
+    /// Set the network timeout, 60 seconds by default
+    pub fn timeout(mut self, timeout: Option<Duration>) -> DirectTransport {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the hosts to try for a domain, in order of preference
+    ///
+    /// Fails if the domain declares that it accepts no email with a null MX record.
+    ///
+    /// [RFC 7505](https://tools.ietf.org/html/rfc7505)
+    pub fn exchangers(&self, domain: &str) -> Result<Vec<String>, Error> {
+        // Address literals are delivered to the address itself
+        if domain.starts_with('[') && domain.ends_with(']') {
+            let address = &domain[1..domain.len() - 1];
+            return Ok(vec![address.trim_start_matches("IPv6:").to_string()]);
+        }
+
+        let mut records = (self.lookup.0)(domain)?;
+        if records.is_empty() {
+            return Ok(vec![domain.to_string()]);
+        }
+        if records.iter().any(|record| record.exchange == ".") {
+            return Err(Error::Client("The domain does not accept email"));
+        }
+        // The sort is stable, keeping the order of the lookup for equal preferences
+        records.sort_by_key(|record| record.preference);
+        Ok(records
+            .into_iter()
+            .map(|record| record.exchange.trim_end_matches('.').to_string())
+            .collect())
+    }
+
+    /// Sends a message to the exchangers of a domain, until one accepts or rejects it
+    fn send_to_domain(
+        &self,
+        domain: &str,
+        envelope: &Envelope,
+        message_id: &str,
+        message: &[u8],
+    ) -> SmtpResult {
+        let mut last_error = None;
+        for host in self.exchangers(domain)? {
+            let result = ClientTlsParameters::builder(host.clone())
+                .build()
+                .map_err(Error::from)
+                .and_then(|tls_parameters| {
+                    SmtpClient::new(
+                        (host.as_str(), self.port),
+                        ClientSecurity::Opportunistic(tls_parameters),
+                    )
+                })
+                .and_then(|client| {
+                    client
+                        .hello_name(self.hello_name.clone())
+                        .timeout(self.timeout)
+                        .transport()
+                        .send(SendableEmail::new(
+                            envelope.clone(),
+                            message_id.to_string(),
+                            message.to_vec(),
+                        ))
+                });
+            match result {
+                Err(err) if !err.is_permanent() => {
+                    info!("{}: delivery to {} failed ({})", message_id, host, err);
+                    last_error = Some(err);
+                }
+                result => return result,
+            }
+        }
+        // The unwrap is fine because there is at least one exchanger
+        Err(last_error.unwrap())
+    }
+}
+
+impl<'a> Transport<'a> for DirectTransport {
+    type Result = DirectResult;
+
+    /// Sends an email to each of its recipient domains in turn
+    ///
+    /// All the domains are tried. The first error is returned if no domain accepted the
+    /// message, otherwise the report lists the outcome of each domain.
+    fn send<E: Into<SendableEmail>>(&mut self, email: E) -> DirectResult {
+        let email = email.into();
+        let envelope = email.envelope().clone();
+        let message_id = email.message_id().to_string();
+        let mut message = vec![];
+        email.message().read_to_end(&mut message)?;
+
+        let mut domains: Vec<(String, Vec<EmailAddress>)> = vec![];
+        for address in envelope.to() {
+            let domain = address.split().1.to_lowercase();
+            match domains.iter_mut().find(|(name, _)| *name == domain) {
+                Some((_, recipients)) => recipients.push(address.clone()),
+                None => domains.push((domain, vec![address.clone()])),
+            }
+        }
+
+        let mut report = DirectReport { domains: vec![] };
+        for (domain, recipients) in domains {
+            // The unwrap is fine because the recipients are never empty
+            let envelope = Envelope::new(envelope.from().cloned(), recipients.clone()).unwrap();
+            let result = self.send_to_domain(&domain, &envelope, &message_id, &message);
+            report.domains.push(DomainReport {
+                domain,
+                recipients,
+                result,
+            });
+        }
+
+        if report.delivered().is_empty() {
+            // Nothing was delivered, the whole message can be retried
+            // The unwrap is fine because an envelope has at least one recipient
+            let first = report.domains.into_iter().next().unwrap();
+            return Err(first.result.unwrap_err());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DirectTransport, MxLookup, MxRecord};
+    use crate::smtp::test_server;
+    use crate::{EmailAddress, Envelope, SendableEmail, Transport};
+    use std::net::TcpListener;
+
+    fn email(to: &[&str]) -> SendableEmail {
+        let address = |address: &str| EmailAddress::new(address.to_string()).unwrap();
+        SendableEmail::new(
+            Envelope::new(
+                Some(address("sender@localhost")),
+                to.iter().map(|to| address(to)).collect(),
+            )
+            .unwrap(),
+            "id".to_string(),
+            b"Hello".to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_exchangers() {
+        let transport = DirectTransport::new(MxLookup::new(|domain| {
+            Ok(match domain {
+                "example.com" => vec![
+                    MxRecord::new(20, "b.example.com."),
+                    MxRecord::new(10, "a.example.com."),
+                    MxRecord::new(20, "c.example.com."),
+                ],
+                "null.example.com" => vec![MxRecord::new(0, ".")],
+                _ => vec![],
+            })
+        }));
+
+        assert_eq!(
+            transport.exchangers("example.com").unwrap(),
+            ["a.example.com", "b.example.com", "c.example.com"]
+        );
+        assert_eq!(
+            transport.exchangers("other.example.com").unwrap(),
+            ["other.example.com"]
+        );
+        assert_eq!(transport.exchangers("[127.0.0.1]").unwrap(), ["127.0.0.1"]);
+        assert_eq!(transport.exchangers("[IPv6:::1]").unwrap(), ["::1"]);
+        assert!(transport.exchangers("null.example.com").is_err());
+    }
+
+    #[test]
+    fn test_send_next_exchanger() {
+        let (addr, server) = test_server::serve("250 mx.example.com", 1);
+        // Nothing listens on the first exchanger
+        let mut transport = DirectTransport::new(MxLookup::new(|_| {
+            Ok(vec![
+                MxRecord::new(20, "127.0.0.1"),
+                MxRecord::new(10, "127.0.0.2"),
+            ])
+        }))
+        .port(addr.port());
+
+        let report = transport.send(email(&["user@example.com"])).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.domains[0].domain, "example.com");
+        let commands = server.join().unwrap();
+        assert!(commands.contains(&"RCPT TO:<user@example.com>".to_string()));
+    }
+
+    #[test]
+    fn test_send_partial() {
+        let (addr, server) = test_server::serve("250 mx.example.com", 1);
+        // Nothing listens on the exchanger of the second domain
+        let mut transport = DirectTransport::new(MxLookup::new(|domain| {
+            Ok(match domain {
+                "a.example.com" => vec![MxRecord::new(10, "127.0.0.1")],
+                _ => vec![MxRecord::new(10, "127.0.0.2")],
+            })
+        }))
+        .port(addr.port());
+
+        let report = transport
+            .send(email(&["alice@a.example.com", "bob@b.example.com"]))
+            .unwrap();
+        assert!(!report.is_complete());
+        let delivered = report.delivered();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].to_string(), "alice@a.example.com");
+        let failed = report.failed();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0.to_string(), "bob@b.example.com");
+        let commands = server.join().unwrap();
+        assert!(!commands.contains(&"RCPT TO:<bob@b.example.com>".to_string()));
+    }
+
+    #[test]
+    fn test_send_unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut transport =
+            DirectTransport::new(MxLookup::new(|_| Ok(vec![MxRecord::new(10, "127.0.0.1")])))
+                .port(port);
+        assert!(transport.send(email(&["user@example.com"])).is_err());
+    }
+}
@@ -18,6 +18,17 @@ pub enum Error {
     ///
     /// [RFC 5321, section 4.2.1](https://tools.ietf.org/html/rfc5321#section-4.2.1)
     Transient(Response),
+    /// The server is closing the transmission channel, 421 reply code
+    ///
+    /// The connection can not be used anymore.
+    ///
+    /// [RFC 5321, section 3.8](https://tools.ietf.org/html/rfc5321#section-3.8)
+    ServiceClosing(Response),
+    /// The connection was closed or reset by the server before it replied
+    ///
+    /// The connection can not be used anymore, and the server may or may not have
+    /// processed the last command.
+    ConnectionLost,
     /// Permanent SMTP error, 5xx reply code
     ///
     /// [RFC 5321, section 4.2.1](https://tools.ietf.org/html/rfc5321#section-4.2.1)
@@ -42,48 +53,76 @@ pub enum Error {
 
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        fmt.write_str(self.description())
-    }
-}
-
-impl StdError for Error {
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::match_same_arms))]
-    fn description(&self) -> &str {
         match *self {
             // Try to display the first line of the server's response that usually
             // contains a short humanly readable error message
-            Transient(ref err) => match err.first_line() {
-                Some(line) => line,
-                None => "undetailed transient error during SMTP transaction",
-            },
-            Permanent(ref err) => match err.first_line() {
-                Some(line) => line,
-                None => "undetailed permanent error during SMTP transaction",
-            },
-            ResponseParsing(err) => err,
-            ChallengeParsing(ref err) => err.description(),
-            Utf8Parsing(ref err) => err.description(),
-            Resolution => "could not resolve hostname",
-            Client(err) => err,
-            Io(ref err) => err.description(),
-            Tls(ref err) => err.description(),
-            Parsing(ref err) => err.description(),
+            Transient(ref err) => fmt.write_str(
+                err.first_line()
+                    .unwrap_or("undetailed transient error during SMTP transaction"),
+            ),
+            ServiceClosing(ref err) => fmt.write_str(
+                err.first_line()
+                    .unwrap_or("service not available, closing transmission channel"),
+            ),
+            Permanent(ref err) => fmt.write_str(
+                err.first_line()
+                    .unwrap_or("undetailed permanent error during SMTP transaction"),
+            ),
+            ConnectionLost => fmt.write_str("connection closed by the server"),
+            ResponseParsing(err) => fmt.write_str(err),
+            ChallengeParsing(ref err) => err.fmt(fmt),
+            Utf8Parsing(ref err) => err.fmt(fmt),
+            Resolution => fmt.write_str("could not resolve hostname"),
+            Client(err) => fmt.write_str(err),
+            Io(ref err) => err.fmt(fmt),
+            Tls(ref err) => err.fmt(fmt),
+            Parsing(ref err) => fmt.write_str(err.description()),
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn StdError> {
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
-            ChallengeParsing(ref err) => Some(&*err),
-            Utf8Parsing(ref err) => Some(&*err),
-            Io(ref err) => Some(&*err),
-            Tls(ref err) => Some(&*err),
+            ChallengeParsing(ref err) => Some(err),
+            Utf8Parsing(ref err) => Some(err),
+            Io(ref err) => Some(err),
+            Tls(ref err) => Some(err),
             _ => None,
         }
     }
 }
 
+impl Error {
+    /// Tells if the server closed the connection, which can not be used anymore
+    pub fn is_connection_closed(&self) -> bool {
+        matches!(*self, ServiceClosing(_) | ConnectionLost)
+    }
+
+    /// Tells if the server replied with a 5xx code, so the command will fail again
+    /// if retried unchanged
+    pub fn is_permanent(&self) -> bool {
+        matches!(*self, Permanent(_))
+    }
+
+    /// Tells if the server replied with a 4xx code, including 421, so the command may
+    /// succeed later
+    pub fn is_transient(&self) -> bool {
+        matches!(*self, Transient(_) | ServiceClosing(_))
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
+        // TLS errors happening in the stream are wrapped into IO errors
+        let is_tls = match err.get_ref() {
+            Some(inner) => inner.is::<native_tls::Error>(),
+            None => false,
+        };
+        if is_tls {
+            // The unwraps are fine because the inner error was just checked
+            return Tls(*err.into_inner().unwrap().downcast().unwrap());
+        }
         Io(err)
     }
 }
@@ -118,6 +157,9 @@ impl From<FromUtf8Error> for Error {
 
 impl From<Response> for Error {
     fn from(response: Response) -> Error {
+        if response.has_code(421) {
+            return ServiceClosing(response);
+        }
         match response.code.severity {
             Severity::TransientNegativeCompletion => Transient(response),
             Severity::PermanentNegativeCompletion => Permanent(response),
@@ -134,3 +176,51 @@ impl From<&'static str> for Error {
 
 /// SMTP result type
 pub type SmtpResult = Result<Response, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use crate::smtp::response::Response;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn test_source() {
+        let error = Error::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert_eq!(error.to_string(), "timed out");
+        assert!(error.source().unwrap().is::<io::Error>());
+
+        let error = Error::from(base64::decode("@").unwrap_err());
+        assert!(error.source().unwrap().is::<base64::DecodeError>());
+
+        let error = Error::from("451 4.3.0 Try again later\r\n".parse::<Response>().unwrap());
+        assert_eq!(error.to_string(), "4.3.0 Try again later");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_severity() {
+        let error = |response: &str| Error::from(response.parse::<Response>().unwrap());
+
+        for response in &[
+            "421 4.3.2 Service shutting down\r\n",
+            "450 4.2.1 Mailbox busy\r\n",
+            "452 4.3.1 Insufficient storage\r\n",
+        ] {
+            assert!(error(response).is_transient());
+            assert!(!error(response).is_permanent());
+        }
+        for response in &[
+            "550 5.1.1 Unknown user\r\n",
+            "552 5.3.4 Message too big\r\n",
+            "554 5.7.1 Rejected\r\n",
+        ] {
+            assert!(error(response).is_permanent());
+            assert!(!error(response).is_transient());
+        }
+
+        let error = Error::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert!(!error.is_permanent());
+        assert!(!error.is_transient());
+    }
+}
@@ -4,9 +4,12 @@ use crate::smtp::authentication::Mechanism;
 use crate::smtp::error::Error;
 use crate::smtp::response::Response;
 use crate::smtp::util::XText;
+use crate::EmailAddress;
 use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fmt::{self, Display, Formatter};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::result::Result;
 
 /// Default client id
@@ -28,8 +31,8 @@ impl Display for ClientId {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
             ClientId::Domain(ref value) => f.write_str(value),
-            ClientId::Ipv4(ref value) => write!(f, "{}", value),
-            ClientId::Ipv6(ref value) => write!(f, "{}", value),
+            ClientId::Ipv4(ref value) => write!(f, "[{}]", value),
+            ClientId::Ipv6(ref value) => write!(f, "[IPv6:{}]", value),
         }
     }
 }
@@ -40,18 +43,49 @@ impl ClientId {
         ClientId::Domain(domain)
     }
 
-    /// Defines a `ClientId` with the current hostname, of `localhost` if hostname could not be
-    /// found
+    /// Creates a new `ClientId` from an IP address, sent as an address literal
+    ///
+    /// [RFC 5321, section 4.1.3](https://tools.ietf.org/html/rfc5321#section-4.1.3)
+    pub fn ip_literal(address: IpAddr) -> ClientId {
+        match address {
+            IpAddr::V4(address) => ClientId::Ipv4(address),
+            IpAddr::V6(address) => ClientId::Ipv6(address),
+        }
+    }
+
+    /// Defines a `ClientId` with the current hostname, or `localhost` if the hostname could not
+    /// be found or is not a valid domain
     pub fn hostname() -> ClientId {
+        ClientId::from_hostname(hostname::get())
+    }
+
+    /// Defines a `ClientId` from the result of a hostname lookup
+    fn from_hostname(hostname: io::Result<OsString>) -> ClientId {
         ClientId::Domain(
-            hostname::get()
-                .map_err(|_| ())
-                .and_then(|s| s.into_string().map_err(|_| ()))
-                .unwrap_or_else(|_| DEFAULT_DOMAIN_CLIENT_ID.to_string()),
+            hostname
+                .ok()
+                .and_then(|hostname| hostname.into_string().ok())
+                .filter(|hostname| is_valid_domain(hostname))
+                .unwrap_or_else(|| DEFAULT_DOMAIN_CLIENT_ID.to_string()),
         )
     }
 }
 
+/// Checks if a name can be sent as the domain of `EHLO`
+///
+/// [RFC 5321, section 4.1.2](https://tools.ietf.org/html/rfc5321#section-4.1.2)
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 255
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
 /// Supported ESMTP keywords
 #[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
@@ -68,6 +102,18 @@ pub enum Extension {
     ///
     /// RFC 2487: https://tools.ietf.org/html/rfc2487
     StartTls,
+    /// BURL keyword
+    ///
+    /// RFC 4468: https://tools.ietf.org/html/rfc4468
+    Burl,
+    /// DELIVERBY keyword
+    ///
+    /// RFC 2852: https://tools.ietf.org/html/rfc2852
+    DeliverBy,
+    /// REQUIRETLS keyword
+    ///
+    /// RFC 8689: https://tools.ietf.org/html/rfc8689
+    RequireTls,
     /// AUTH mechanism
     Authentication(Mechanism),
 }
@@ -78,6 +124,9 @@ impl Display for Extension {
             Extension::EightBitMime => write!(f, "8BITMIME"),
             Extension::SmtpUtfEight => write!(f, "SMTPUTF8"),
             Extension::StartTls => write!(f, "STARTTLS"),
+            Extension::Burl => write!(f, "BURL"),
+            Extension::DeliverBy => write!(f, "DELIVERBY"),
+            Extension::RequireTls => write!(f, "REQUIRETLS"),
             Extension::Authentication(ref mechanism) => write!(f, "AUTH {}", mechanism),
         }
     }
@@ -95,6 +144,10 @@ pub struct ServerInfo {
     ///
     /// It contains the features supported by the server and known by the `Extension` module.
     pub features: HashSet<Extension>,
+    /// Shortest DELIVERBY by-time accepted with the `R` mode in seconds, `0` if the server
+    /// advertises none
+    #[cfg_attr(feature = "serde-impls", serde(default))]
+    pub deliver_by_min_time: u32,
 }
 
 impl Display for ServerInfo {
@@ -121,6 +174,7 @@ impl ServerInfo {
         };
 
         let mut features: HashSet<Extension> = HashSet::new();
+        let mut deliver_by_min_time = 0;
 
         for line in response.message.as_slice() {
             if line.is_empty() {
@@ -138,6 +192,19 @@ impl ServerInfo {
                 "STARTTLS" => {
                     features.insert(Extension::StartTls);
                 }
+                "BURL" => {
+                    features.insert(Extension::Burl);
+                }
+                "DELIVERBY" => {
+                    deliver_by_min_time = split
+                        .get(1)
+                        .and_then(|limit| limit.parse().ok())
+                        .unwrap_or(0);
+                    features.insert(Extension::DeliverBy);
+                }
+                "REQUIRETLS" => {
+                    features.insert(Extension::RequireTls);
+                }
                 "AUTH" => {
                     for &mechanism in &split[1..] {
                         match mechanism {
@@ -150,6 +217,9 @@ impl ServerInfo {
                             "XOAUTH2" => {
                                 features.insert(Extension::Authentication(Mechanism::Xoauth2));
                             }
+                            "SCRAM-SHA-256" => {
+                                features.insert(Extension::Authentication(Mechanism::ScramSha256));
+                            }
                             _ => (),
                         }
                     }
@@ -161,6 +231,7 @@ impl ServerInfo {
         Ok(ServerInfo {
             name: name.to_string(),
             features,
+            deliver_by_min_time,
         })
     }
 
@@ -174,6 +245,25 @@ impl ServerInfo {
         self.features
             .contains(&Extension::Authentication(mechanism))
     }
+
+    /// Gets the minimum DELIVERBY by-time of the server in seconds, `0` if it advertises none
+    ///
+    /// Returns `None` if the server does not support DELIVERBY.
+    pub fn deliver_by_minimum(&self) -> Option<u32> {
+        if self.supports_feature(Extension::DeliverBy) {
+            Some(self.deliver_by_min_time)
+        } else {
+            None
+        }
+    }
+
+    /// Gets the first mechanism of the list that the server supports
+    pub fn get_auth_mechanism(&self, mechanisms: &[Mechanism]) -> Option<Mechanism> {
+        mechanisms
+            .iter()
+            .cloned()
+            .find(|&mechanism| self.supports_auth_mechanism(mechanism))
+    }
 }
 
 /// A `MAIL FROM` extension parameter
@@ -186,6 +276,18 @@ pub enum MailParameter {
     Size(usize),
     /// `SMTPUTF8` parameter
     SmtpUtfEight,
+    /// `AUTH` parameter, the identity the message is submitted for, `<>` if unknown
+    ///
+    /// [RFC 4954, section 5](https://tools.ietf.org/html/rfc4954#section-5)
+    Auth(Option<EmailAddress>),
+    /// `BY` parameter, the by-time in seconds and what to do when it expires
+    ///
+    /// [RFC 2852, section 4](https://tools.ietf.org/html/rfc2852#section-4)
+    DeliverBy(u32, DeliverByMode),
+    /// `REQUIRETLS` parameter, requiring TLS for every hop of the delivery
+    ///
+    /// [RFC 8689, section 4](https://tools.ietf.org/html/rfc8689#section-4)
+    RequireTls,
     /// Custom parameter
     Other {
         /// Parameter keyword
@@ -201,6 +303,12 @@ impl Display for MailParameter {
             MailParameter::Body(ref value) => write!(f, "BODY={}", value),
             MailParameter::Size(size) => write!(f, "SIZE={}", size),
             MailParameter::SmtpUtfEight => f.write_str("SMTPUTF8"),
+            MailParameter::Auth(Some(ref address)) => {
+                write!(f, "AUTH={}", XText(address.as_ref()))
+            }
+            MailParameter::Auth(None) => f.write_str("AUTH=<>"),
+            MailParameter::DeliverBy(time, mode) => write!(f, "BY={};{}", time, mode),
+            MailParameter::RequireTls => f.write_str("REQUIRETLS"),
             MailParameter::Other {
                 ref keyword,
                 value: Some(ref value),
@@ -213,6 +321,25 @@ impl Display for MailParameter {
     }
 }
 
+/// What the server does when a message is not delivered before its by-time
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeliverByMode {
+    /// `R`, the message is returned as undeliverable
+    Return,
+    /// `N`, a delay notification is sent and delivery continues
+    Notify,
+}
+
+impl Display for DeliverByMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DeliverByMode::Return => f.write_str("R"),
+            DeliverByMode::Notify => f.write_str("N"),
+        }
+    }
+}
+
 /// Values for the `BODY` parameter to `MAIL FROM`
 #[derive(PartialEq, Eq, Clone, Debug, Copy)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
@@ -267,6 +394,37 @@ mod test {
     use crate::smtp::authentication::Mechanism;
     use crate::smtp::response::{Category, Code, Detail, Response, Severity};
     use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::io;
+
+    #[test]
+    fn test_clientid_from_hostname() {
+        let client_id = |hostname: &str| ClientId::from_hostname(Ok(OsString::from(hostname)));
+        let localhost = ClientId::new("localhost".to_string());
+
+        assert_eq!(
+            client_id("mail.example.com"),
+            ClientId::new("mail.example.com".to_string())
+        );
+        assert_eq!(client_id("host-1"), ClientId::new("host-1".to_string()));
+        assert_eq!(client_id(""), localhost);
+        assert_eq!(client_id("my host"), localhost);
+        assert_eq!(client_id("my_host.local"), localhost);
+        assert_eq!(client_id("host..example"), localhost);
+        assert_eq!(client_id("-host"), localhost);
+        assert_eq!(
+            ClientId::from_hostname(Err(io::Error::from(io::ErrorKind::NotFound))),
+            localhost
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            assert_eq!(
+                ClientId::from_hostname(Ok(OsString::from_vec(b"host\xff".to_vec()))),
+                localhost
+            );
+        }
+    }
 
     #[test]
     fn test_clientid_fmt() {
@@ -274,6 +432,14 @@ mod test {
             format!("{}", ClientId::new("test".to_string())),
             "test".to_string()
         );
+        assert_eq!(
+            format!("{}", ClientId::ip_literal("1.2.3.4".parse().unwrap())),
+            "[1.2.3.4]".to_string()
+        );
+        assert_eq!(
+            format!("{}", ClientId::ip_literal("::1".parse().unwrap())),
+            "[IPv6:::1]".to_string()
+        );
     }
 
     #[test]
@@ -299,6 +465,7 @@ mod test {
                 ServerInfo {
                     name: "name".to_string(),
                     features: eightbitmime.clone(),
+                    deliver_by_min_time: 0,
                 }
             ),
             "name with {EightBitMime}".to_string()
@@ -312,6 +479,7 @@ mod test {
                 ServerInfo {
                     name: "name".to_string(),
                     features: empty,
+                    deliver_by_min_time: 0,
                 }
             ),
             "name with no supported features".to_string()
@@ -326,6 +494,7 @@ mod test {
                 ServerInfo {
                     name: "name".to_string(),
                     features: plain.clone(),
+                    deliver_by_min_time: 0,
                 }
             ),
             "name with {Authentication(Plain)}".to_string()
@@ -353,6 +522,7 @@ mod test {
         let server_info = ServerInfo {
             name: "me".to_string(),
             features,
+            deliver_by_min_time: 0,
         };
 
         assert_eq!(ServerInfo::from_response(&response).unwrap(), server_info);
@@ -371,17 +541,20 @@ mod test {
                 "AUTH PLAIN CRAM-MD5 XOAUTH2 OTHER".to_string(),
                 "8BITMIME".to_string(),
                 "SIZE 42".to_string(),
+                "REQUIRETLS".to_string(),
             ],
         );
 
         let mut features2 = HashSet::new();
         assert!(features2.insert(Extension::EightBitMime));
+        assert!(features2.insert(Extension::RequireTls));
         assert!(features2.insert(Extension::Authentication(Mechanism::Plain),));
         assert!(features2.insert(Extension::Authentication(Mechanism::Xoauth2),));
 
         let server_info2 = ServerInfo {
             name: "me".to_string(),
             features: features2,
+            deliver_by_min_time: 0,
         };
 
         assert_eq!(ServerInfo::from_response(&response2).unwrap(), server_info2);
@@ -390,4 +563,61 @@ mod test {
         assert!(server_info2.supports_auth_mechanism(Mechanism::Plain));
         assert!(!server_info2.supports_feature(Extension::StartTls));
     }
+
+    #[test]
+    fn test_serverinfo_auth_mechanism() {
+        let response = Response::new(
+            Code::new(
+                Severity::PositiveCompletion,
+                Category::Unspecified4,
+                Detail::One,
+            ),
+            vec![
+                "me".to_string(),
+                "AUTH LOGIN PLAIN SCRAM-SHA-256".to_string(),
+            ],
+        );
+        let server_info = ServerInfo::from_response(&response).unwrap();
+
+        assert_eq!(
+            server_info.get_auth_mechanism(&[Mechanism::ScramSha256, Mechanism::Plain]),
+            Some(Mechanism::ScramSha256)
+        );
+        assert_eq!(
+            server_info.get_auth_mechanism(&[Mechanism::Plain, Mechanism::ScramSha256]),
+            Some(Mechanism::Plain)
+        );
+        assert_eq!(
+            server_info.get_auth_mechanism(&[Mechanism::Xoauth2, Mechanism::Login]),
+            Some(Mechanism::Login)
+        );
+        assert_eq!(server_info.get_auth_mechanism(&[Mechanism::Xoauth2]), None);
+        assert_eq!(server_info.get_auth_mechanism(&[]), None);
+    }
+
+    #[test]
+    fn test_serverinfo_deliver_by() {
+        let response = |line: &str| {
+            Response::new(
+                Code::new(
+                    Severity::PositiveCompletion,
+                    Category::Unspecified4,
+                    Detail::One,
+                ),
+                vec!["me".to_string(), line.to_string()],
+            )
+        };
+
+        let server_info = ServerInfo::from_response(&response("DELIVERBY 240")).unwrap();
+        assert!(server_info.supports_feature(Extension::DeliverBy));
+        assert_eq!(server_info.deliver_by_min_time, 240);
+        assert_eq!(server_info.deliver_by_minimum(), Some(240));
+        assert_eq!(Extension::DeliverBy.to_string(), "DELIVERBY");
+
+        let server_info = ServerInfo::from_response(&response("DELIVERBY")).unwrap();
+        assert_eq!(server_info.deliver_by_minimum(), Some(0));
+
+        let server_info = ServerInfo::from_response(&response("8BITMIME")).unwrap();
+        assert_eq!(server_info.deliver_by_minimum(), None);
+    }
 }
@@ -0,0 +1,478 @@
+//! A scripted SMTP server listening on a loopback port, to test code sending emails
+//! through a real `SmtpTransport` without an external server
+//!
+//! ```rust,no_run
+//! use lettre::smtp::mock_server::MockSmtpServer;
+//! use lettre::{ClientSecurity, SmtpClient};
+//!
+//! let server = MockSmtpServer::builder()
+//!     .reject_recipient("unknown@example.com")
+//!     .start()
+//!     .unwrap();
+//! let mut transport = SmtpClient::new(server.addr(), ClientSecurity::None)
+//!     .unwrap()
+//!     .transport();
+//! // Send emails with the transport, then check `server.messages()`
+//! ```
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A message accepted by a `MockSmtpServer`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MockMessage {
+    /// Reverse path given to `MAIL FROM`, empty for a null sender
+    pub from: String,
+    /// Accepted recipients
+    pub to: Vec<String>,
+    /// Message content, with the dot-stuffing removed
+    pub data: String,
+}
+
+/// Builder for a `MockSmtpServer`
+#[derive(Clone, Debug)]
+pub struct MockSmtpServerBuilder {
+    greeting: String,
+    capabilities: Vec<String>,
+    rejected_recipients: HashSet<String>,
+    data_response: String,
+    credentials: Option<(String, String)>,
+    starttls_required: bool,
+}
+
+impl MockSmtpServerBuilder {
+    /// Set the text of the `220` greeting
+    pub fn greeting<S: Into<String>>(mut self, greeting: S) -> MockSmtpServerBuilder {
+        self.greeting = greeting.into();
+        self
+    }
+
+    /// Add a line to the EHLO response, like `8BITMIME` or `SIZE 1000`
+    pub fn capability<S: Into<String>>(mut self, capability: S) -> MockSmtpServerBuilder {
+        self.capabilities.push(capability.into());
+        self
+    }
+
+    /// Reject the given recipient with a `550` reply
+    pub fn reject_recipient<S: Into<String>>(mut self, address: S) -> MockSmtpServerBuilder {
+        self.rejected_recipients.insert(address.into());
+        self
+    }
+
+    /// Set the reply to the end of the message data, `250 2.0.0 Queued` by default
+    ///
+    /// The message is only recorded if the reply is positive.
+    pub fn data_response<S: Into<String>>(mut self, response: S) -> MockSmtpServerBuilder {
+        self.data_response = response.into();
+        self
+    }
+
+    /// Require authentication with the given credentials before `MAIL FROM`
+    ///
+    /// The server advertises the PLAIN and LOGIN mechanisms.
+    pub fn auth_required<S: Into<String>>(
+        mut self,
+        username: S,
+        password: S,
+    ) -> MockSmtpServerBuilder {
+        self.credentials = Some((username.into(), password.into()));
+        self.capabilities.push("AUTH PLAIN LOGIN".to_string());
+        self
+    }
+
+    /// Advertise STARTTLS and refuse `MAIL FROM` on the unencrypted connection
+    ///
+    /// The server cannot negotiate TLS, it replies `454` to `STARTTLS`, so this tests how
+    /// clients behave when encryption is required.
+    pub fn starttls_required(mut self) -> MockSmtpServerBuilder {
+        self.starttls_required = true;
+        self.capabilities.push("STARTTLS".to_string());
+        self
+    }
+
+    /// Starts listening on a loopback port
+    pub fn start(self) -> io::Result<MockSmtpServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State::default());
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_state = Arc::clone(&state);
+        let thread_stopped = Arc::clone(&stopped);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let config = self.clone();
+                    let state = Arc::clone(&thread_state);
+                    thread::spawn(move || {
+                        let _ = Session::new(&config, &state).run(stream);
+                    });
+                }
+            }
+        });
+
+        Ok(MockSmtpServer {
+            addr,
+            state,
+            stopped,
+        })
+    }
+}
+
+/// Data shared by the sessions of a server
+#[derive(Debug, Default)]
+struct State {
+    commands: Mutex<Vec<String>>,
+    messages: Mutex<Vec<MockMessage>>,
+}
+
+/// An SMTP server following a script, stopped when dropped
+///
+/// It accepts any number of connections and records the received commands and messages.
+#[derive(Debug)]
+pub struct MockSmtpServer {
+    addr: SocketAddr,
+    state: Arc<State>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockSmtpServer {
+    /// Creates a builder for a server accepting every message
+    pub fn builder() -> MockSmtpServerBuilder {
+        MockSmtpServerBuilder {
+            greeting: "mock.localhost ESMTP".to_string(),
+            capabilities: vec![],
+            rejected_recipients: HashSet::new(),
+            data_response: "250 2.0.0 Queued".to_string(),
+            credentials: None,
+            starttls_required: false,
+        }
+    }
+
+    /// Address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Commands received by the server, without the line ending and the message data
+    pub fn commands(&self) -> Vec<String> {
+        self.state.commands.lock().unwrap().clone()
+    }
+
+    /// Messages accepted by the server
+    pub fn messages(&self) -> Vec<MockMessage> {
+        self.state.messages.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockSmtpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the listening thread so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// A connection to the server
+struct Session<'a> {
+    config: &'a MockSmtpServerBuilder,
+    state: &'a State,
+    authenticated: bool,
+    from: Option<String>,
+    to: Vec<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(config: &'a MockSmtpServerBuilder, state: &'a State) -> Session<'a> {
+        Session {
+            config,
+            state,
+            authenticated: config.credentials.is_none(),
+            from: None,
+            to: vec![],
+        }
+    }
+
+    fn run(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        write!(writer, "220 {}\r\n", self.config.greeting)?;
+
+        loop {
+            let line = match read_line(&mut reader)? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            self.state.commands.lock().unwrap().push(line.clone());
+
+            let (verb, argument) = match line.find(' ') {
+                Some(idx) => (line[..idx].to_uppercase(), line[idx + 1..].to_string()),
+                None => (line.to_uppercase(), String::new()),
+            };
+            let reply = match verb.as_str() {
+                "EHLO" => ehlo_reply(&self.config.capabilities)
+                    .unwrap_or_else(|| "250 mock.localhost".to_string()),
+                "HELO" => "250 mock.localhost".to_string(),
+                "STARTTLS" => "454 4.7.0 TLS not available".to_string(),
+                "AUTH" => self.auth(&argument, &mut reader, &mut writer)?,
+                "MAIL" => self.mail(&argument),
+                "RCPT" => self.rcpt(&argument),
+                "DATA" => self.data(&mut reader, &mut writer)?,
+                "RSET" => {
+                    self.reset();
+                    "250 2.0.0 OK".to_string()
+                }
+                "NOOP" => "250 2.0.0 OK".to_string(),
+                "QUIT" => {
+                    write!(writer, "221 2.0.0 Bye\r\n")?;
+                    return writer.shutdown(Shutdown::Both);
+                }
+                _ => "502 5.5.2 Command not implemented".to_string(),
+            };
+            write!(writer, "{}\r\n", reply)?;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.from = None;
+        self.to.clear();
+    }
+
+    fn auth<R: BufRead, W: Write>(
+        &mut self,
+        argument: &str,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<String> {
+        let (username, password) = match self.config.credentials {
+            Some((ref username, ref password)) => (username, password),
+            None => return Ok("502 5.5.1 Authentication not enabled".to_string()),
+        };
+        let mut words = argument.split_whitespace();
+        let valid = match words.next().map(|mechanism| mechanism.to_uppercase()) {
+            Some(ref mechanism) if mechanism == "PLAIN" => {
+                let response = match words.next() {
+                    Some(response) => response.to_string(),
+                    None => challenge(reader, writer, "")?,
+                };
+                decode(&response) == format!("\0{}\0{}", username, password)
+            }
+            Some(ref mechanism) if mechanism == "LOGIN" => {
+                let user = challenge(reader, writer, "VXNlcm5hbWU6")?;
+                let pass = challenge(reader, writer, "UGFzc3dvcmQ6")?;
+                decode(&user) == *username && decode(&pass) == *password
+            }
+            _ => return Ok("504 5.5.4 Unrecognized authentication type".to_string()),
+        };
+        self.authenticated = valid;
+        Ok(if valid {
+            "235 2.7.0 Authentication successful".to_string()
+        } else {
+            "535 5.7.8 Authentication credentials invalid".to_string()
+        })
+    }
+
+    fn mail(&mut self, argument: &str) -> String {
+        if self.config.starttls_required {
+            return "530 5.7.0 Must issue a STARTTLS command first".to_string();
+        }
+        if !self.authenticated {
+            return "530 5.7.0 Authentication required".to_string();
+        }
+        if self.from.is_some() {
+            return "503 5.5.1 Sender already specified".to_string();
+        }
+        match path(argument, "FROM:") {
+            Some(from) => {
+                self.from = Some(from);
+                "250 2.1.0 OK".to_string()
+            }
+            None => "501 5.5.4 Syntax error in parameters".to_string(),
+        }
+    }
+
+    fn rcpt(&mut self, argument: &str) -> String {
+        if self.from.is_none() {
+            return "503 5.5.1 Need MAIL before RCPT".to_string();
+        }
+        match path(argument, "TO:") {
+            Some(ref to) if self.config.rejected_recipients.contains(to) => {
+                "550 5.1.1 Mailbox unavailable".to_string()
+            }
+            Some(to) => {
+                self.to.push(to);
+                "250 2.1.5 OK".to_string()
+            }
+            None => "501 5.5.4 Syntax error in parameters".to_string(),
+        }
+    }
+
+    fn data<R: BufRead, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<String> {
+        if self.to.is_empty() {
+            return Ok("554 5.5.1 No valid recipients".to_string());
+        }
+        write!(writer, "354 Start mail input; end with <CRLF>.<CRLF>\r\n")?;
+
+        let mut data = String::new();
+        loop {
+            let line = match read_line(reader)? {
+                Some(line) => line,
+                None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            };
+            if line == "." {
+                break;
+            }
+            data.push_str(line.strip_prefix('.').unwrap_or(&line));
+            data.push_str("\r\n");
+        }
+
+        if self.config.data_response.starts_with('2') {
+            self.state.messages.lock().unwrap().push(MockMessage {
+                from: self.from.take().unwrap_or_default(),
+                to: self.to.clone(),
+                data,
+            });
+        }
+        self.reset();
+        Ok(self.config.data_response.clone())
+    }
+}
+
+/// Formats the EHLO reply advertising the given capabilities
+fn ehlo_reply(capabilities: &[String]) -> Option<String> {
+    let (last, others) = capabilities.split_last()?;
+    let mut reply = String::from("250-mock.localhost");
+    for capability in others {
+        reply.push_str(&format!("\r\n250-{}", capability));
+    }
+    reply.push_str(&format!("\r\n250 {}", last));
+    Some(reply)
+}
+
+/// Sends an authentication challenge and reads the response
+fn challenge<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    text: &str,
+) -> io::Result<String> {
+    write!(writer, "334 {}\r\n", text)?;
+    read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+/// Decodes a base64 authentication response, empty if invalid
+fn decode(response: &str) -> String {
+    base64::decode(response)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .unwrap_or_default()
+}
+
+/// Extracts the address of a `MAIL FROM` or `RCPT TO` argument
+fn path(argument: &str, prefix: &str) -> Option<String> {
+    if !argument.to_uppercase().starts_with(prefix) {
+        return None;
+    }
+    let argument = argument[prefix.len()..].trim_start();
+    let end = argument.find('>')?;
+    if !argument.starts_with('<') {
+        return None;
+    }
+    Some(argument[1..end].to_string())
+}
+
+/// Reads a line without its line ending, `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ehlo_reply, path, MockSmtpServer};
+    use crate::smtp::{ClientSecurity, SmtpClient};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_ehlo_reply() {
+        assert_eq!(ehlo_reply(&[]), None);
+        assert_eq!(
+            ehlo_reply(&["8BITMIME".to_string(), "STARTTLS".to_string()]).unwrap(),
+            "250-mock.localhost\r\n250-8BITMIME\r\n250 STARTTLS"
+        );
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path("FROM:<a@example.com> BODY=8BITMIME", "FROM:"),
+            Some("a@example.com".to_string())
+        );
+        assert_eq!(path("from:<>", "FROM:"), Some(String::new()));
+        assert_eq!(path("TO:a@example.com", "TO:"), None);
+        assert_eq!(path("FROM:<a@example.com>", "TO:"), None);
+    }
+
+    #[test]
+    fn test_conversation() {
+        let server = MockSmtpServer::builder()
+            .capability("8BITMIME")
+            .reject_recipient("bob@example.com")
+            .start()
+            .unwrap();
+        let stream = TcpStream::connect(server.addr()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut exchange = |command: &str| {
+            writer.write_all(command.as_bytes()).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+
+        assert!(exchange("").starts_with("220 "));
+        assert_eq!(
+            exchange("MAIL FROM:<a@example.com>\r\n"),
+            "250 2.1.0 OK\r\n"
+        );
+        assert!(exchange("RCPT TO:<bob@example.com>\r\n").starts_with("550 "));
+        assert!(exchange("DATA\r\n").starts_with("554 "));
+        assert!(exchange("RCPT TO:<carol@example.com>\r\n").starts_with("250 "));
+        assert!(exchange("DATA\r\n").starts_with("354 "));
+        assert!(exchange("Hello\r\n..dot\r\n.\r\n").starts_with("250 "));
+        assert!(exchange("QUIT\r\n").starts_with("221 "));
+
+        let messages = server.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].from, "a@example.com");
+        assert_eq!(messages[0].to, ["carol@example.com"]);
+        assert_eq!(messages[0].data, "Hello\r\n.dot\r\n");
+        assert_eq!(server.commands()[0], "MAIL FROM:<a@example.com>");
+    }
+
+    #[test]
+    fn test_starttls_required() {
+        let server = MockSmtpServer::builder()
+            .starttls_required()
+            .start()
+            .unwrap();
+        let mut transport = SmtpClient::new(server.addr(), ClientSecurity::None)
+            .unwrap()
+            .transport();
+
+        let server_info = transport.test_connection().unwrap();
+        assert!(server_info.supports_feature(crate::smtp::extension::Extension::StartTls));
+    }
+}
@@ -8,34 +8,58 @@
 //! It implements the following extensions:
 //!
 //! * 8BITMIME ([RFC 6152](https://tools.ietf.org/html/rfc6152))
-//! * AUTH ([RFC 4954](http://tools.ietf.org/html/rfc4954)) with PLAIN, LOGIN and XOAUTH2 mechanisms
+//! * AUTH ([RFC 4954](http://tools.ietf.org/html/rfc4954)) with PLAIN, LOGIN, XOAUTH2 and
+//!   SCRAM-SHA-256 mechanisms
 //! * STARTTLS ([RFC 2487](http://tools.ietf.org/html/rfc2487))
 //! * SMTPUTF8 ([RFC 6531](http://tools.ietf.org/html/rfc6531))
+//! * DELIVERBY ([RFC 2852](https://tools.ietf.org/html/rfc2852))
+//!
+//! With the `direct-transport` feature, `direct::DirectTransport` delivers to the mail
+//! exchangers of the recipients, without a relay.
+//!
+//! With the `tracing` feature, connections, EHLO, authentication and sends are wrapped in
+//! `tracing` spans, and each command and response is emitted as an event, with the
+//! credentials redacted.
 //!
 
 use crate::smtp::authentication::{
     Credentials, Mechanism, DEFAULT_ENCRYPTED_MECHANISMS, DEFAULT_UNENCRYPTED_MECHANISMS,
 };
 use crate::smtp::client::net::ClientTlsParameters;
-use crate::smtp::client::net::DEFAULT_TLS_MIN_PROTOCOL;
-use crate::smtp::client::InnerClient;
+use crate::smtp::client::{
+    Direction, InnerClient, Tap, DEFAULT_MAX_AUTH_CHALLENGES, DEFAULT_MAX_RESPONSE_SIZE,
+};
 use crate::smtp::commands::*;
 use crate::smtp::error::{Error, SmtpResult};
-use crate::smtp::extension::{ClientId, Extension, MailBodyParameter, MailParameter, ServerInfo};
-use crate::{SendableEmail, Transport};
+use crate::smtp::extension::{
+    ClientId, DeliverByMode, Extension, MailBodyParameter, MailParameter, ServerInfo,
+};
+use crate::smtp::response::Response;
+use crate::{EmailAddress, Envelope, Message, SendableEmail, Transport};
 use log::{debug, info};
-use native_tls::TlsConnector;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, Read};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub mod authentication;
 pub mod client;
 pub mod commands;
+#[cfg(feature = "direct-transport")]
+pub mod direct;
 pub mod error;
 pub mod extension;
+#[cfg(feature = "test-util")]
+pub mod mock_server;
 #[cfg(feature = "connection-pool")]
 pub mod r2d2;
 pub mod response;
+#[cfg(test)]
+mod test_server;
 pub mod util;
 
 // Registered port numbers:
@@ -63,6 +87,31 @@ pub enum ClientSecurity {
     Wrapper(ClientTlsParameters),
 }
 
+/// Function returning the addresses to connect to
+type ResolverFn = dyn Fn() -> io::Result<Vec<SocketAddr>> + Send + Sync;
+
+/// Callback resolving the addresses of the server when connecting, instead of the
+/// address given to the client
+///
+/// This allows split-horizon DNS, custom lookups or tests. The addresses are tried in order.
+#[derive(Clone)]
+pub struct Resolver(Arc<ResolverFn>);
+
+impl Resolver {
+    /// Creates a resolver from a function
+    pub fn new<F: Fn() -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static>(
+        resolver: F,
+    ) -> Resolver {
+        Resolver(Arc::new(resolver))
+    }
+}
+
+impl Debug for Resolver {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
 /// Configures connection reuse behavior
 #[derive(Clone, Debug, Copy)]
 #[cfg_attr(feature = "serde-impls", derive(serde::Serialize, serde::Deserialize))]
@@ -87,17 +136,41 @@ pub struct SmtpClient {
     credentials: Option<Credentials>,
     /// Socket we are connecting to
     server_addr: SocketAddr,
+    /// Unix socket we are connecting to instead of `server_addr`
+    socket_path: Option<PathBuf>,
     /// TLS security configuration
     security: ClientSecurity,
     /// Enable UTF8 mailboxes in envelope or headers
     smtp_utf8: bool,
-    /// Optional enforced authentication mechanism
-    authentication_mechanism: Option<Mechanism>,
+    /// Optional accepted authentication mechanisms, in order of preference
+    authentication: Option<Vec<Mechanism>>,
     /// Force use of the set authentication mechanism even if server does not report to support it
     force_set_auth: bool,
+    /// Fail if none of the accepted authentication mechanisms is supported by the server
+    strict_auth: bool,
+    /// Send the message to the accepted recipients when some are rejected
+    continue_on_rejected_rcpt: bool,
+    /// Maximum number of challenges accepted during authentication
+    max_auth_challenges: u8,
+    /// Maximum size of a response of the server, in bytes
+    max_response_size: usize,
+    /// Requested DELIVERBY by-time in seconds and mode
+    deliver_by: Option<(u32, DeliverByMode)>,
+    /// Require TLS for every hop of the delivery
+    require_tls: bool,
+    /// Callback receiving the exchanged bytes
+    tap: Option<Tap>,
+    /// Callback resolving the addresses to connect to instead of `server_addr`
+    resolver: Option<Resolver>,
     /// Define network timeout
     /// It can be changed later for specific needs (like a different timeout for each SMTP command)
     timeout: Option<Duration>,
+    /// Maximum duration of a whole send
+    send_timeout: Option<Duration>,
+    /// Fail when a server that advertised STARTTLS stops advertising it
+    strict_starttls: bool,
+    /// Send QUIT before closing the connection
+    send_quit: bool,
 }
 
 /// Builder for the SMTP `SmtpTransport`
@@ -118,14 +191,26 @@ impl SmtpClient {
         match addresses.next() {
             Some(addr) => Ok(SmtpClient {
                 server_addr: addr,
+                socket_path: None,
                 security,
                 smtp_utf8: false,
                 credentials: None,
                 connection_reuse: ConnectionReuseParameters::NoReuse,
                 hello_name: ClientId::hostname(),
-                authentication_mechanism: None,
+                authentication: None,
                 force_set_auth: false,
+                strict_auth: false,
+                continue_on_rejected_rcpt: false,
+                max_auth_challenges: DEFAULT_MAX_AUTH_CHALLENGES,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+                deliver_by: None,
+                require_tls: false,
+                tap: None,
+                resolver: None,
                 timeout: Some(Duration::new(60, 0)),
+                send_timeout: None,
+                strict_starttls: false,
+                send_quit: true,
             }),
             None => Err(Error::Resolution),
         }
@@ -135,11 +220,9 @@ impl SmtpClient {
     /// Creates an encrypted transport over submissions port, using the provided domain
     /// to validate TLS certificates.
     pub fn new_simple(domain: &str) -> Result<SmtpClient, Error> {
-        let mut tls_builder = TlsConnector::builder();
-        tls_builder.min_protocol_version(Some(DEFAULT_TLS_MIN_PROTOCOL));
-
-        let tls_parameters =
-            ClientTlsParameters::new(domain.to_string(), tls_builder.build().unwrap());
+        let tls_parameters = ClientTlsParameters::builder(domain.to_string())
+            .build()
+            .unwrap();
 
         SmtpClient::new(
             (domain, SUBMISSIONS_PORT),
@@ -147,11 +230,49 @@ impl SmtpClient {
         )
     }
 
+    /// Creates a transport over the submission port, upgraded with STARTTLS, using the
+    /// provided domain to validate TLS certificates.
+    ///
+    /// The connection fails if the server does not support STARTTLS.
+    pub fn new_starttls(domain: &str) -> Result<SmtpClient, Error> {
+        let tls_parameters = ClientTlsParameters::builder(domain.to_string())
+            .build()
+            .unwrap();
+
+        SmtpClient::new(
+            (domain, SUBMISSION_PORT),
+            ClientSecurity::Required(tls_parameters),
+        )
+    }
+
     /// Creates a new local SMTP client to port 25
     pub fn new_unencrypted_localhost() -> Result<SmtpClient, Error> {
         SmtpClient::new(("localhost", SMTP_PORT), ClientSecurity::None)
     }
 
+    /// Creates a new local SMTP client connecting to a unix socket
+    ///
+    /// The connection is not encrypted.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<Path>>(path: P) -> SmtpClient {
+        let mut client = SmtpClient::new(
+            SocketAddr::from(([127, 0, 0, 1], SMTP_PORT)),
+            ClientSecurity::None,
+        )
+        // The unwrap is fine because an IP address does not need to be resolved
+        .unwrap();
+        client.socket_path = Some(path.as_ref().to_path_buf());
+        client
+    }
+
+    /// Returns the address of the server, for logging
+    fn server_name(&self) -> String {
+        match self.socket_path {
+            Some(ref path) => path.display().to_string(),
+            None => self.server_addr.to_string(),
+        }
+    }
+
     /// Enable SMTPUTF8 if the server supports it
     pub fn smtp_utf8(mut self, enabled: bool) -> SmtpClient {
         self.smtp_utf8 = enabled;
@@ -159,6 +280,9 @@ impl SmtpClient {
     }
 
     /// Set the name used during EHLO
+    ///
+    /// Defaults to the hostname of the machine. Use `ClientId::new` or `ClientId::ip_literal`
+    /// to avoid exposing it.
     pub fn hello_name(mut self, name: ClientId) -> SmtpClient {
         self.hello_name = name;
         self
@@ -177,23 +301,128 @@ impl SmtpClient {
     }
 
     /// Set the authentication mechanism to use
-    pub fn authentication_mechanism(mut self, mechanism: Mechanism) -> SmtpClient {
-        self.authentication_mechanism = Some(mechanism);
+    pub fn authentication_mechanism(self, mechanism: Mechanism) -> SmtpClient {
+        self.authentication(vec![mechanism])
+    }
+
+    /// Set the accepted authentication mechanisms, in order of preference
+    ///
+    /// The first one advertised by the server is used.
+    pub fn authentication(mut self, mechanisms: Vec<Mechanism>) -> SmtpClient {
+        self.authentication = Some(mechanisms);
         self
     }
 
     /// Set if the set authentication mechanism should be force
+    ///
+    /// The first accepted mechanism is used, even if the server does not advertise it.
     pub fn force_set_auth(mut self, force: bool) -> SmtpClient {
         self.force_set_auth = force;
         self
     }
 
+    /// Set if connecting should fail when the server supports none of the accepted
+    /// authentication mechanisms, instead of continuing without authentication
+    pub fn strict_auth(mut self, strict: bool) -> SmtpClient {
+        self.strict_auth = strict;
+        self
+    }
+
+    /// Set if the message should still be sent to the accepted recipients when the server
+    /// rejects some of them
+    ///
+    /// By default, the first rejected recipient aborts the transaction. The rejected
+    /// recipients are listed in the `SendReport` returned by `SmtpTransport::send_with_report`.
+    pub fn continue_on_rejected_rcpt(mut self, enabled: bool) -> SmtpClient {
+        self.continue_on_rejected_rcpt = enabled;
+        self
+    }
+
+    /// Request the delivery of the messages within the given number of seconds
+    ///
+    /// The server must support DELIVERBY, and with `DeliverByMode::Return` the time must not
+    /// be shorter than the minimum it advertises.
+    pub fn deliver_by(mut self, seconds: u32, mode: DeliverByMode) -> SmtpClient {
+        self.deliver_by = Some((seconds, mode));
+        self
+    }
+
+    /// Require the messages to be relayed over TLS on every hop, with REQUIRETLS
+    ///
+    /// The server must support REQUIRETLS and the connection must be encrypted, otherwise
+    /// the messages are not sent.
+    pub fn require_tls(mut self, enabled: bool) -> SmtpClient {
+        self.require_tls = enabled;
+        self
+    }
+
+    /// Set a callback receiving all the bytes written to and read from the server
+    ///
+    /// This is useful to debug the exchange, for example in tests.
+    pub fn tap<F: Fn(&[u8], Direction) + Send + Sync + 'static>(mut self, tap: F) -> SmtpClient {
+        self.tap = Some(Tap::new(tap));
+        self
+    }
+
+    /// Set a resolver called on each connection for the addresses of the server
+    ///
+    /// The addresses given when creating the client are then not used, except for logging.
+    pub fn resolver(mut self, resolver: Resolver) -> SmtpClient {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Set the maximum number of challenges accepted during authentication, 10 by default
+    pub fn max_auth_challenges(mut self, max_challenges: u8) -> SmtpClient {
+        self.max_auth_challenges = max_challenges;
+        self
+    }
+
+    /// Set the maximum size of a response of the server in bytes, 64 KiB by default
+    ///
+    /// A longer response aborts the connection, so that a malicious server can not exhaust
+    /// the memory.
+    pub fn max_response_size(mut self, size: usize) -> SmtpClient {
+        self.max_response_size = size;
+        self
+    }
+
     /// Set the timeout duration
     pub fn timeout(mut self, timeout: Option<Duration>) -> SmtpClient {
         self.timeout = timeout;
         self
     }
 
+    /// Set if a missing STARTTLS is an error once the server has advertised it
+    ///
+    /// With opportunistic security, an attacker can remove STARTTLS from the EHLO response
+    /// to keep the connection in plaintext. When enabled, the transport remembers that the
+    /// server advertised STARTTLS, and later connections without it fail instead of
+    /// sending in plaintext. `ClientSecurity::Required` also covers the first connection.
+    pub fn strict_starttls(mut self, strict: bool) -> SmtpClient {
+        self.strict_starttls = strict;
+        self
+    }
+
+    /// Set if QUIT is sent before closing the connection, enabled by default
+    ///
+    /// When disabled, the socket is closed without waiting for the reply to QUIT, which
+    /// saves a round trip for each connection when sending in bulk without reuse.
+    pub fn send_quit(mut self, send_quit: bool) -> SmtpClient {
+        self.send_quit = send_quit;
+        self
+    }
+
+    /// Set the maximum duration of a whole send, including the connection if needed
+    ///
+    /// Unlike `timeout`, which applies to each read and write, this also stops servers
+    /// answering slowly enough to never reach it. The connection is closed when the send
+    /// takes too long.
+    pub fn send_timeout(mut self, timeout: Option<Duration>) -> SmtpClient {
+        self.send_timeout = timeout;
+        self
+    }
+
     /// Build the SMTP client
     ///
     /// It does not connect to the server, but only creates the `SmtpTransport`
@@ -202,6 +431,22 @@ impl SmtpClient {
     }
 }
 
+/// Recipients rejected by the server, with the corresponding error
+type RejectedRecipients = Vec<(EmailAddress, Error)>;
+
+/// Outcome of a transaction, with the recipients accepted and rejected by the server
+#[derive(Debug)]
+pub struct SendReport {
+    /// Response of the server to the message content
+    pub response: Response,
+    /// Recipients accepted by the server
+    pub accepted: Vec<EmailAddress>,
+    /// Recipients rejected by the server, with the corresponding error
+    pub rejected: RejectedRecipients,
+    /// Whether the message was sent over an encrypted connection
+    pub encrypted: bool,
+}
+
 /// Represents the state of a client
 #[derive(Debug)]
 struct State {
@@ -209,6 +454,14 @@ struct State {
     pub panic: bool,
     /// Connection reuse counter
     pub connection_reuse_count: u16,
+    /// The server closed the connection, with a 421 reply or abruptly
+    pub closed_by_server: bool,
+    /// The client is authenticated
+    pub authenticated: bool,
+    /// The server advertised STARTTLS on a previous connection
+    pub starttls_advertised: bool,
+    /// A connection pool already counted the connection as broken
+    pub counted_broken: bool,
 }
 
 /// Structure that implements the high level SMTP client
@@ -230,11 +483,15 @@ macro_rules! try_smtp (
         match $err {
             Ok(val) => val,
             Err(err) => {
+                let err: Error = From::from(err);
                 if !$client.state.panic {
                     $client.state.panic = true;
                     $client.close();
                 }
-                return Err(From::from(err))
+                if err.is_connection_closed() {
+                    $client.state.closed_by_server = true;
+                }
+                return Err(err)
             },
         }
     })
@@ -245,7 +502,11 @@ impl<'a> SmtpTransport {
     ///
     /// It does not connect to the server, but only creates the `SmtpTransport`
     pub fn new(builder: SmtpClient) -> SmtpTransport {
-        let client = InnerClient::new();
+        let mut client = InnerClient::new();
+        if let Some(ref tap) = builder.tap {
+            client.set_tap(tap.clone());
+        }
+        client.set_max_response_size(builder.max_response_size);
 
         SmtpTransport {
             client,
@@ -254,6 +515,10 @@ impl<'a> SmtpTransport {
             state: State {
                 panic: false,
                 connection_reuse_count: 0,
+                closed_by_server: false,
+                authenticated: false,
+                starttls_advertised: false,
+                counted_broken: false,
             },
         }
     }
@@ -267,25 +532,56 @@ impl<'a> SmtpTransport {
         if self.state.connection_reuse_count > 0 {
             info!(
                 "connection already established to {}",
-                self.client_info.server_addr
+                self.client_info.server_name()
             );
             return Ok(());
         }
 
-        self.client.connect(
-            &self.client_info.server_addr,
-            self.client_info.timeout,
-            match self.client_info.security {
-                ClientSecurity::Wrapper(ref tls_parameters) => Some(tls_parameters),
-                _ => None,
-            },
-        )?;
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "smtp_connect",
+            host = %self.client_info.server_name(),
+            port = tracing::field::Empty,
+            encrypted = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        #[cfg(feature = "tracing")]
+        {
+            if self.client_info.socket_path.is_none() {
+                span.record("port", self.client_info.server_addr.port());
+            }
+        }
+
+        self.state.closed_by_server = false;
+        self.state.counted_broken = false;
+        let tls_parameters = match self.client_info.security {
+            ClientSecurity::Wrapper(ref tls_parameters) => Some(tls_parameters),
+            _ => None,
+        };
+        match (&self.client_info.socket_path, &self.client_info.resolver) {
+            #[cfg(unix)]
+            (Some(path), _) => self.client.connect_unix(path)?,
+            (_, Some(resolver)) => self.client.connect(
+                &(resolver.0)()?.as_slice(),
+                self.client_info.timeout,
+                tls_parameters,
+            )?,
+            _ => self.client.connect(
+                &self.client_info.server_addr,
+                self.client_info.timeout,
+                tls_parameters,
+            )?,
+        }
 
         self.client.set_timeout(self.client_info.timeout)?;
         let _response = self.client.read_response()?;
 
         // Log the connection
-        info!("connection established to {}", self.client_info.server_addr);
+        info!(
+            "connection established to {}",
+            self.client_info.server_name()
+        );
 
         self.ehlo()?;
 
@@ -299,11 +595,19 @@ impl<'a> SmtpTransport {
             (&ClientSecurity::Required(_), false) => {
                 return Err(From::from("Could not encrypt connection, aborting"));
             }
-            (&ClientSecurity::Opportunistic(_), false) => (),
+            (&ClientSecurity::Opportunistic(_), false) => {
+                if self.client_info.strict_starttls && self.state.starttls_advertised {
+                    self.close();
+                    return Err(From::from(
+                        "STARTTLS is no longer advertised, the connection may be downgraded",
+                    ));
+                }
+            }
             (&ClientSecurity::None, _) => (),
             (&ClientSecurity::Wrapper(_), _) => (),
             (&ClientSecurity::Opportunistic(ref tls_parameters), true)
             | (&ClientSecurity::Required(ref tls_parameters), true) => {
+                self.state.starttls_advertised = true;
                 try_smtp!(self.client.command(StarttlsCommand), self);
                 try_smtp!(self.client.upgrade_tls_stream(tls_parameters), self);
 
@@ -314,53 +618,61 @@ impl<'a> SmtpTransport {
             }
         }
 
-        if self.client_info.credentials.is_some() {
-            let mut found = false;
+        #[cfg(feature = "tracing")]
+        span.record("encrypted", self.client.is_encrypted());
 
-            if !self.client_info.force_set_auth {
-                // Compute accepted mechanism
-                let accepted_mechanisms = match self.client_info.authentication_mechanism {
-                    Some(mechanism) => vec![mechanism],
+        if let Some(ref credentials) = self.client_info.credentials {
+            let mechanism = if !self.client_info.force_set_auth {
+                // Compute accepted mechanisms
+                let accepted_mechanisms = match self.client_info.authentication {
+                    Some(ref mechanisms) => mechanisms.as_slice(),
                     None => {
                         if self.client.is_encrypted() {
-                            DEFAULT_ENCRYPTED_MECHANISMS.to_vec()
+                            DEFAULT_ENCRYPTED_MECHANISMS
                         } else {
-                            DEFAULT_UNENCRYPTED_MECHANISMS.to_vec()
+                            DEFAULT_UNENCRYPTED_MECHANISMS
                         }
                     }
                 };
 
-                for mechanism in accepted_mechanisms {
-                    if self
-                        .server_info
-                        .as_ref()
-                        .unwrap()
-                        .supports_auth_mechanism(mechanism)
-                    {
-                        found = true;
-                        try_smtp!(
-                            self.client
-                                .auth(mechanism, self.client_info.credentials.as_ref().unwrap(),),
-                            self
-                        );
-                        break;
-                    }
-                }
+                self.server_info
+                    .as_ref()
+                    .unwrap()
+                    .get_auth_mechanism(accepted_mechanisms)
             } else {
-                try_smtp!(
-                    self.client.auth(
-                        self.client_info.authentication_mechanism.expect(
-                            "force_set_auth set to true, but no authentication mechanism set"
-                        ),
-                        self.client_info.credentials.as_ref().unwrap(),
-                    ),
-                    self
-                );
-                found = true;
-            }
+                Some(
+                    *self
+                        .client_info
+                        .authentication
+                        .as_ref()
+                        .and_then(|mechanisms| mechanisms.first())
+                        .expect("force_set_auth set to true, but no authentication mechanism set"),
+                )
+            };
 
-            if !found {
-                info!("No supported authentication mechanisms available");
+            match mechanism {
+                Some(mechanism) => {
+                    #[cfg(feature = "tracing")]
+                    let span = tracing::info_span!("smtp_auth", mechanism = %mechanism);
+                    #[cfg(feature = "tracing")]
+                    let _enter = span.enter();
+                    try_smtp!(
+                        self.client.auth_with_max_challenges(
+                            mechanism,
+                            credentials,
+                            self.client_info.max_auth_challenges
+                        ),
+                        self
+                    );
+                    self.state.authenticated = true;
+                }
+                None if self.client_info.strict_auth => {
+                    self.close();
+                    return Err(From::from(
+                        "No supported authentication mechanisms available, aborting",
+                    ));
+                }
+                None => info!("No supported authentication mechanisms available"),
             }
         }
         Ok(())
@@ -368,11 +680,14 @@ impl<'a> SmtpTransport {
 
     /// Gets the EHLO response and updates server information
     fn ehlo(&mut self) -> SmtpResult {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("smtp_ehlo");
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
         // Extended Hello
         let ehlo_response = try_smtp!(
-            self.client.command(EhloCommand::new(ClientId::new(
-                self.client_info.hello_name.to_string()
-            ),)),
+            self.client
+                .command(EhloCommand::new(self.client_info.hello_name.clone())),
             self
         );
 
@@ -384,35 +699,197 @@ impl<'a> SmtpTransport {
         Ok(ehlo_response)
     }
 
+    /// Connects to the server and returns the information it advertises, without sending
+    /// anything
+    ///
+    /// The connection is closed afterwards. This is useful to check the configuration, or
+    /// the capabilities of the server before sending.
+    pub fn test_connection(&mut self) -> Result<ServerInfo, Error> {
+        if !self.client.is_connected() {
+            self.connect()?;
+        }
+        let server_info = self.server_info.clone();
+        self.close();
+        server_info.ok_or(Error::Client("Missing server information"))
+    }
+
     /// Reset the client state
     pub fn close(&mut self) {
         // Close the SMTP transaction if needed
-        self.client.close();
+        if self.client_info.send_quit {
+            self.client.close();
+        } else {
+            self.client.abort();
+        }
 
         // Reset the client state
         self.server_info = None;
         self.state.panic = false;
         self.state.connection_reuse_count = 0;
+        self.state.authenticated = false;
     }
-}
 
-impl<'a> Transport<'a> for SmtpTransport {
-    type Result = SmtpResult;
+    /// Runs a send, aborting it after the send timeout
+    fn with_send_timeout<T, F>(&mut self, send: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut SmtpTransport) -> Result<T, Error>,
+    {
+        let deadline = match self.client_info.send_timeout {
+            Some(timeout) => Instant::now() + timeout,
+            None => return send(self),
+        };
 
-    /// Sends an email
-    #[cfg_attr(
-        feature = "cargo-clippy",
-        allow(clippy::match_same_arms, clippy::cyclomatic_complexity)
-    )]
-    fn send<E: Into<SendableEmail>>(&mut self, email: E) -> SmtpResult {
+        self.client.set_deadline(Some(deadline))?;
+        let result = send(self);
+        // The connection is left in an unknown state when the deadline interrupts the exchange
+        if result.is_err() && Instant::now() >= deadline {
+            self.close();
+        }
+        if self.client.set_deadline(None).is_err() {
+            self.close();
+        }
+        result
+    }
+
+    /// Sends an email, and reports the recipients accepted and rejected by the server
+    pub fn send_with_report<E: Into<SendableEmail>>(
+        &mut self,
+        email: E,
+    ) -> Result<SendReport, Error> {
         let email = email.into();
+        self.with_send_timeout(|transport| transport.send_message(email))
+    }
 
+    fn send_message(&mut self, email: SendableEmail) -> Result<SendReport, Error> {
         let message_id = email.message_id().to_string();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "smtp_send",
+            message_id = %message_id,
+            recipients = email.envelope().to().len(),
+            size = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
 
         if !self.client.is_connected() {
             self.connect()?;
         }
 
+        let envelope = email.envelope().clone();
+        let message = if self
+            .server_info
+            .as_ref()
+            .unwrap()
+            .supports_feature(Extension::EightBitMime)
+        {
+            email.message()
+        } else {
+            check_seven_bit(email.message())?
+        };
+
+        let (accepted, rejected) = self.start_transaction(&envelope, &message_id)?;
+
+        // Data
+        try_smtp!(self.client.command(DataCommand), self);
+
+        // Message content
+        let encrypted = self.client.is_encrypted();
+        let result = self.client.message(Box::new(message));
+        if result.is_err() {
+            // The server still waits for the end of the data, closing the connection
+            // without QUIT makes it discard the partial message
+            self.client.abort();
+            self.close();
+        }
+        let result = self.end_transaction(result, &message_id);
+
+        result.map(|response| SendReport {
+            response,
+            accepted,
+            rejected,
+            encrypted,
+        })
+    }
+
+    /// Sends a message stored on an IMAP server, which the server fetches from the given
+    /// URL with BURL instead of receiving it with DATA
+    ///
+    /// The server must advertise the BURL extension.
+    pub fn send_burl(&mut self, envelope: &Envelope, url: &str) -> Result<SendReport, Error> {
+        self.with_send_timeout(|transport| transport.send_burl_message(envelope, url))
+    }
+
+    fn send_burl_message(&mut self, envelope: &Envelope, url: &str) -> Result<SendReport, Error> {
+        if !self.client.is_connected() {
+            self.connect()?;
+        }
+
+        if !self
+            .server_info
+            .as_ref()
+            .unwrap()
+            .supports_feature(Extension::Burl)
+        {
+            return Err(From::from("The server does not support BURL"));
+        }
+
+        let (accepted, rejected) = self.start_transaction(envelope, url)?;
+
+        let encrypted = self.client.is_encrypted();
+        let result = self.client.command(BurlCommand::new(url.to_string(), true));
+        let result = self.end_transaction(result, url);
+
+        result.map(|response| SendReport {
+            response,
+            accepted,
+            rejected,
+            encrypted,
+        })
+    }
+
+    /// Asks the server if an address is valid with VRFY, for diagnostics
+    ///
+    /// Many servers disable VRFY, or reply 252 without checking the address. Negative
+    /// replies, like 502 or 550, are returned as a response instead of an error, so check
+    /// it with `Response::is_positive`.
+    pub fn vrfy(&mut self, address: &str) -> SmtpResult {
+        self.diagnostic_command(address, VrfyCommand::new)
+    }
+
+    /// Asks the server for the members of a mailing list with EXPN, for diagnostics
+    ///
+    /// Replies are handled like in `vrfy`.
+    pub fn expn(&mut self, list: &str) -> SmtpResult {
+        self.diagnostic_command(list, ExpnCommand::new)
+    }
+
+    fn diagnostic_command<C: Display, F: FnOnce(String) -> C>(
+        &mut self,
+        argument: &str,
+        command: F,
+    ) -> SmtpResult {
+        if argument.contains(&['\r', '\n'][..]) {
+            return Err(From::from("Argument contains a line break"));
+        }
+        if !self.client.is_connected() {
+            self.connect()?;
+        }
+
+        let response = match self.client.command(command(argument.to_string())) {
+            Err(Error::Transient(response)) | Err(Error::Permanent(response)) => response,
+            result => try_smtp!(result, self),
+        };
+        self.release_connection();
+        Ok(response)
+    }
+
+    /// Sends the MAIL and RCPT commands, and returns the accepted and rejected recipients
+    fn start_transaction(
+        &mut self,
+        envelope: &Envelope,
+        message_id: &str,
+    ) -> Result<(Vec<EmailAddress>, RejectedRecipients), Error> {
         // Mail
         let mut mail_options = vec![];
 
@@ -435,11 +912,48 @@ impl<'a> Transport<'a> for SmtpTransport {
             mail_options.push(MailParameter::SmtpUtfEight);
         }
 
+        if self.state.authenticated
+            && self
+                .server_info
+                .as_ref()
+                .unwrap()
+                .features
+                .iter()
+                .any(|feature| matches!(feature, Extension::Authentication(_)))
+        {
+            mail_options.push(MailParameter::Auth(envelope.from().cloned()));
+        }
+
+        if let Some((seconds, mode)) = self.client_info.deliver_by {
+            match self.server_info.as_ref().unwrap().deliver_by_minimum() {
+                None => return Err(From::from("The server does not support DELIVERBY")),
+                Some(minimum) if mode == DeliverByMode::Return && seconds < minimum => {
+                    return Err(From::from(
+                        "The DELIVERBY time is shorter than the minimum of the server",
+                    ))
+                }
+                Some(_) => mail_options.push(MailParameter::DeliverBy(seconds, mode)),
+            }
+        }
+
+        if self.client_info.require_tls {
+            if !self
+                .server_info
+                .as_ref()
+                .unwrap()
+                .supports_feature(Extension::RequireTls)
+            {
+                return Err(From::from("The server does not support REQUIRETLS"));
+            }
+            if !self.client.is_encrypted() {
+                return Err(From::from("REQUIRETLS needs an encrypted connection"));
+            }
+            mail_options.push(MailParameter::RequireTls);
+        }
+
         try_smtp!(
-            self.client.command(MailCommand::new(
-                email.envelope().from().cloned(),
-                mail_options,
-            )),
+            self.client
+                .command(MailCommand::new(envelope.from().cloned(), mail_options,)),
             self
         );
 
@@ -447,28 +961,56 @@ impl<'a> Transport<'a> for SmtpTransport {
         info!(
             "{}: from=<{}>",
             message_id,
-            match email.envelope().from() {
+            match envelope.from() {
                 Some(address) => address.to_string(),
                 None => "".to_string(),
             }
         );
 
         // Recipient
-        for to_address in email.envelope().to() {
-            try_smtp!(
-                self.client
-                    .command(RcptCommand::new(to_address.clone(), vec![])),
-                self
-            );
-            // Log the rcpt command
-            info!("{}: to=<{}>", message_id, to_address);
+        let mut accepted = vec![];
+        let mut rejected = vec![];
+        for to_address in envelope.to() {
+            match self
+                .client
+                .command(RcptCommand::new(to_address.clone(), vec![]))
+            {
+                Err(err @ Error::Transient(_)) | Err(err @ Error::Permanent(_))
+                    if self.client_info.continue_on_rejected_rcpt =>
+                {
+                    info!(
+                        "{}: to=<{}>, status=rejected ({})",
+                        message_id, to_address, err
+                    );
+                    rejected.push((to_address.clone(), err));
+                }
+                result => {
+                    try_smtp!(result, self);
+                    // Log the rcpt command
+                    info!("{}: to=<{}>", message_id, to_address);
+                    accepted.push(to_address.clone());
+                }
+            }
         }
 
-        // Data
-        try_smtp!(self.client.command(DataCommand), self);
+        // No recipient left, reset the transaction
+        if accepted.is_empty() {
+            try_smtp!(self.client.command(RsetCommand), self);
+            self.release_connection();
+            return Err(rejected.pop().unwrap().1);
+        }
 
-        // Message content
-        let result = self.client.message(Box::new(email.message()));
+        Ok((accepted, rejected))
+    }
+
+    /// Handles the final response to the message content
+    fn end_transaction(&mut self, result: SmtpResult, message_id: &str) -> SmtpResult {
+        if let Err(ref err) = result {
+            if err.is_connection_closed() {
+                self.close();
+                self.state.closed_by_server = true;
+            }
+        }
 
         if let Ok(ref result) = result {
             // Increment the connection reuse counter
@@ -487,7 +1029,12 @@ impl<'a> Transport<'a> for SmtpTransport {
             );
         }
 
-        // Test if we can reuse the existing connection
+        self.release_connection();
+        result
+    }
+
+    /// Closes the connection if it can not be reused
+    fn release_connection(&mut self) {
         match self.client_info.connection_reuse {
             ConnectionReuseParameters::ReuseLimited(limit)
                 if self.state.connection_reuse_count >= limit =>
@@ -497,7 +1044,924 @@ impl<'a> Transport<'a> for SmtpTransport {
             ConnectionReuseParameters::NoReuse => self.close(),
             _ => (),
         }
+    }
+}
 
-        result
+/// Error for a message with 8-bit data sent to a server without 8BITMIME
+const EIGHT_BIT_ERROR: &str =
+    "The message contains 8-bit data, but the server does not support 8BITMIME";
+
+/// Checks that a message only contains 7-bit data, for servers without 8BITMIME
+///
+/// Messages given as bytes are checked before starting the transaction, readers are
+/// checked while the message is sent and fail with an `InvalidData` IO error.
+fn check_seven_bit(message: Message) -> Result<Message, Error> {
+    match message {
+        Message::Bytes(bytes) => {
+            if !bytes.get_ref().is_ascii() {
+                return Err(From::from(EIGHT_BIT_ERROR));
+            }
+            Ok(Message::Bytes(bytes))
+        }
+        Message::Reader(reader) => Ok(Message::Reader(Box::new(SevenBitReader(reader)))),
+    }
+}
+
+/// Reader failing on the first 8-bit byte
+struct SevenBitReader(Box<dyn Read + Send>);
+
+impl Read for SevenBitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.0.read(buf)?;
+        if !buf[..len].is_ascii() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, EIGHT_BIT_ERROR));
+        }
+        Ok(len)
+    }
+}
+
+impl<'a> Transport<'a> for SmtpTransport {
+    type Result = SmtpResult;
+
+    /// Sends an email
+    fn send<E: Into<SendableEmail>>(&mut self, email: E) -> SmtpResult {
+        self.send_with_report(email).map(|report| report.response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ClientSecurity, ClientTlsParameters, ConnectionReuseParameters, SmtpClient, SmtpTransport,
+        SUBMISSION_PORT,
+    };
+    use crate::smtp::authentication::{Credentials, Mechanism};
+    use crate::smtp::client::mock::MockStream;
+    use crate::smtp::client::net::NetworkStream;
+    use crate::smtp::client::Direction;
+    use crate::smtp::error::Error;
+    use crate::smtp::extension::{DeliverByMode, Extension, ServerInfo};
+    use crate::smtp::test_server;
+    use crate::{EmailAddress, Envelope, SendableEmail, Transport};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    fn transport(client: SmtpClient, responses: &str) -> (SmtpTransport, MockStream) {
+        let mock = MockStream::with_vec(responses.as_bytes().to_vec());
+        let mut transport = client.transport();
+        transport
+            .client
+            .set_stream(NetworkStream::Mock(mock.clone()));
+        transport.server_info = Some(ServerInfo {
+            name: "localhost".to_string(),
+            features: HashSet::new(),
+            deliver_by_min_time: 0,
+        });
+        transport.state.connection_reuse_count = 1;
+        (transport, mock)
+    }
+
+    fn email() -> SendableEmail {
+        let address = |address: &str| EmailAddress::new(address.to_string()).unwrap();
+        SendableEmail::new(
+            Envelope::new(
+                Some(address("sender@localhost")),
+                vec![
+                    address("alice@localhost"),
+                    address("bob@localhost"),
+                    address("carol@localhost"),
+                ],
+            )
+            .unwrap(),
+            "id".to_string(),
+            b"Hello".to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_new_starttls() {
+        let client = SmtpClient::new_starttls("localhost").unwrap();
+        assert_eq!(client.server_addr.port(), SUBMISSION_PORT);
+        match client.security {
+            ClientSecurity::Required(ref tls_parameters) => {
+                assert_eq!(tls_parameters.domain, "localhost")
+            }
+            _ => panic!("STARTTLS is not required"),
+        }
+    }
+
+    #[test]
+    fn test_rejected_rcpt_aborts() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n550 No such user\r\n221 Bye\r\n",
+        );
+
+        match transport.send(email()) {
+            Err(Error::Permanent(response)) => assert_eq!(response.message, ["No such user"]),
+            result => panic!("unexpected result {:?}", result),
+        }
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(!written.contains("carol@localhost"));
+        assert!(!written.contains("DATA"));
+    }
+
+    #[test]
+    fn test_send_report_not_encrypted() {
+        let tls_parameters = ClientTlsParameters::builder("localhost".to_string())
+            .build()
+            .unwrap();
+        let client = SmtpClient::new(
+            "localhost:25",
+            ClientSecurity::Opportunistic(tls_parameters),
+        )
+        .unwrap();
+        let (mut transport, _) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n\
+             354 Go ahead\r\n250 Queued\r\n",
+        );
+
+        let report = transport.send_with_report(email()).unwrap();
+        assert_eq!(report.response.message, ["Queued"]);
+        assert!(!report.encrypted);
+    }
+
+    #[test]
+    fn test_mail_auth_parameter() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n\
+             354 Go ahead\r\n250 Queued\r\n",
+        );
+        transport
+            .server_info
+            .as_mut()
+            .unwrap()
+            .features
+            .insert(Extension::Authentication(Mechanism::Plain));
+        transport.state.authenticated = true;
+
+        assert!(transport.send(email()).is_ok());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("MAIL FROM:<sender@localhost> AUTH=sender@localhost\r\n"));
+    }
+
+    #[test]
+    fn test_deliver_by() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .deliver_by(120, DeliverByMode::Return);
+        // Each attempt checks the connection with a NOOP first
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n\
+             354 Go ahead\r\n250 Queued\r\n",
+        );
+
+        // The server does not advertise DELIVERBY
+        assert!(transport.send(email()).is_err());
+
+        let server_info = transport.server_info.as_mut().unwrap();
+        server_info.features.insert(Extension::DeliverBy);
+        server_info.deliver_by_min_time = 240;
+        // The time is shorter than the minimum of the server
+        assert!(transport.send(email()).is_err());
+
+        transport.server_info.as_mut().unwrap().deliver_by_min_time = 60;
+        assert!(transport.send(email()).is_ok());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("MAIL FROM:<sender@localhost> BY=120;R\r\n"));
+    }
+
+    #[test]
+    fn test_require_tls() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .require_tls(true);
+        let (mut transport, _) = transport(client, "250 OK\r\n250 OK\r\n");
+
+        // The server does not advertise REQUIRETLS
+        assert!(transport.send(email()).is_err());
+
+        let features = &mut transport.server_info.as_mut().unwrap().features;
+        features.insert(Extension::RequireTls);
+        // The connection is not encrypted
+        assert!(transport.send(email()).is_err());
+
+        let mut mock = MockStream::with_vec(
+            b"250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n\
+              250 Queued\r\n"
+                .to_vec(),
+        );
+        mock.set_encrypted(true);
+        transport
+            .client
+            .set_stream(NetworkStream::Mock(mock.clone()));
+        assert!(transport.send(email()).is_ok());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("\r\nMAIL FROM:<sender@localhost> REQUIRETLS\r\n"));
+    }
+
+    #[test]
+    fn test_send_burl() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 Queued\r\n",
+        );
+        let url = "imap://user@imap.example.com/Drafts;UIDVALIDITY=1/;UID=2;urlauth=submit+user:internal:abc";
+
+        // The server does not advertise BURL
+        assert!(transport.send_burl(email().envelope(), url).is_err());
+
+        transport
+            .server_info
+            .as_mut()
+            .unwrap()
+            .features
+            .insert(Extension::Burl);
+        let report = transport.send_burl(email().envelope(), url).unwrap();
+        assert_eq!(report.response.message, ["Queued"]);
+        assert_eq!(report.accepted.len(), 3);
+
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains(&format!(
+            "RCPT TO:<carol@localhost>\r\nBURL {} LAST\r\n",
+            url
+        )));
+        assert!(!written.contains("DATA"));
+    }
+
+    #[test]
+    fn test_tap() {
+        let exchange = Arc::new(Mutex::new(vec![]));
+        let tap_exchange = exchange.clone();
+        let client =
+            SmtpClient::new_unencrypted_localhost()
+                .unwrap()
+                .tap(move |bytes, direction| {
+                    tap_exchange
+                        .lock()
+                        .unwrap()
+                        .push((direction, String::from_utf8_lossy(bytes).into_owned()))
+                });
+        let (mut transport, _) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n\
+             354 Go ahead\r\n250 Queued\r\n221 Bye\r\n",
+        );
+        assert!(transport.send(email()).is_ok());
+
+        let exchange = exchange.lock().unwrap();
+        let sent = exchange
+            .iter()
+            .filter(|&&(direction, _)| direction == Direction::Sent)
+            .map(|(_, bytes)| bytes.as_str())
+            .collect::<String>();
+        assert_eq!(
+            sent,
+            "NOOP\r\nMAIL FROM:<sender@localhost>\r\nRCPT TO:<alice@localhost>\r\n\
+             RCPT TO:<bob@localhost>\r\nRCPT TO:<carol@localhost>\r\nDATA\r\n\
+             Hello\r\n.\r\nQUIT\r\n"
+        );
+        assert_eq!(
+            exchange[exchange.len() - 3],
+            (Direction::Received, "250 Queued\r\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rejected_rcpt_continue() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .continue_on_rejected_rcpt(true);
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n550 No such user\r\n250 OK\r\n\
+             354 Go ahead\r\n250 Queued\r\n221 Bye\r\n",
+        );
+
+        let report = transport.send_with_report(email()).unwrap();
+        assert_eq!(report.response.message, ["Queued"]);
+        assert_eq!(
+            report.accepted,
+            [
+                EmailAddress::new("alice@localhost".to_string()).unwrap(),
+                EmailAddress::new("carol@localhost".to_string()).unwrap(),
+            ]
+        );
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].0.to_string(), "bob@localhost");
+        match report.rejected[0].1 {
+            Error::Permanent(ref response) => assert_eq!(response.message, ["No such user"]),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("RCPT TO:<carol@localhost>\r\nDATA\r\n"));
+    }
+
+    #[test]
+    fn test_rejected_rcpt_continue_all_rejected() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .continue_on_rejected_rcpt(true);
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n550 No such user\r\n450 Mailbox busy\r\n\
+             550 No such user\r\n250 OK\r\n221 Bye\r\n",
+        );
+
+        assert!(transport.send_with_report(email()).is_err());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("RSET\r\n"));
+        assert!(!written.contains("DATA"));
+    }
+
+    #[test]
+    fn test_failed_send_quits() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n\
+             554 Transaction failed\r\n221 Bye\r\n",
+        );
+
+        match transport.send(email()) {
+            Err(Error::Permanent(response)) => {
+                assert_eq!(response.message, ["Transaction failed"])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.ends_with("DATA\r\nQUIT\r\n"));
+    }
+
+    #[test]
+    fn test_connection_lost_during_data() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        // The server closes the connection after receiving the message
+        let (mut transport, _) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n",
+        );
+
+        match transport.send(email()) {
+            Err(Error::ConnectionLost) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(transport.state.closed_by_server);
+        assert_eq!(transport.state.connection_reuse_count, 0);
+    }
+
+    #[test]
+    fn test_send_shared_bytes() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n221 Bye\r\n",
+        );
+
+        let body: Arc<[u8]> = Arc::from(&b"Subject: Hello\r\n\r\n.Shared\r\n"[..]);
+        let email = SendableEmail::new_with_bytes(
+            Envelope::new(
+                Some(EmailAddress::new("sender@localhost".to_string()).unwrap()),
+                vec![EmailAddress::new("alice@localhost".to_string()).unwrap()],
+            )
+            .unwrap(),
+            "id".to_string(),
+            body.clone(),
+        );
+        assert!(transport.send(email).is_ok());
+        // The transport dropped its handle on the buffer
+        assert_eq!(Arc::strong_count(&body), 1);
+
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.ends_with("DATA\r\nSubject: Hello\r\n\r\n..Shared\r\n.\r\nQUIT\r\n"));
+    }
+
+    #[test]
+    fn test_send_8bit_without_8bitmime() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let envelope = email().envelope().clone();
+        let (mut seven_bit, mut mock) = transport(client, "250 OK\r\n");
+
+        let email = SendableEmail::new(envelope.clone(), "id".to_string(), "Café".into());
+        match seven_bit.send(email) {
+            Err(Error::Client(message)) => assert!(message.contains("8BITMIME")),
+            result => panic!("unexpected result {:?}", result),
+        }
+        // Nothing was sent after the connection check
+        assert_eq!(mock.take_vec(), b"NOOP\r\n");
+
+        // Readers are checked while the message is sent
+        let (mut seven_bit, mut mock) = transport(
+            SmtpClient::new_unencrypted_localhost().unwrap(),
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n",
+        );
+        let reader = Box::new(std::io::Cursor::new("Hello\r\nCafé".as_bytes()));
+        let email = SendableEmail::new_with_reader(envelope.clone(), "id".to_string(), reader);
+        match seven_bit.send(email) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!seven_bit.client.is_connected());
+        // The connection was dropped before the end of the data
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.ends_with("DATA\r\n"));
+
+        let (mut transport, mut mock) = transport(
+            SmtpClient::new_unencrypted_localhost().unwrap(),
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n",
+        );
+        transport
+            .server_info
+            .as_mut()
+            .unwrap()
+            .features
+            .insert(Extension::EightBitMime);
+        let email = SendableEmail::new(envelope, "id".to_string(), "Café".into());
+        assert!(transport.send(email).is_ok());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("BODY=8BITMIME"));
+        assert!(written.contains("Café\r\n.\r\n"));
+    }
+
+    #[test]
+    fn test_vrfy() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 Alice <alice@localhost>\r\n250 OK\r\n252 Cannot VRFY user\r\n\
+             250 OK\r\n502 5.5.1 VRFY command is disabled\r\n250 OK\r\n\
+             250-Alice <alice@localhost>\r\n250 Bob <bob@localhost>\r\n",
+        );
+
+        let response = transport.vrfy("alice").unwrap();
+        assert!(response.has_code(250));
+        assert_eq!(response.message, ["Alice <alice@localhost>"]);
+        assert!(transport.vrfy("bob").unwrap().has_code(252));
+        let response = transport.vrfy("carol").unwrap();
+        assert!(!response.is_positive());
+        assert!(response.has_code(502));
+        assert_eq!(transport.expn("team").unwrap().message.len(), 2);
+        assert!(transport.vrfy("alice\r\nRSET").is_err());
+
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("VRFY alice\r\n"));
+        assert!(written.contains("VRFY carol\r\n"));
+        assert!(written.ends_with("EXPN team\r\n"));
+    }
+
+    #[test]
+    fn test_send_queue_id() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, _) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n\
+             250 2.0.0 Ok: queued as 4Bz3Xk1Q2sz9rxN\r\n221 Bye\r\n",
+        );
+
+        let response = transport.send(email()).unwrap();
+        let message = response.message();
+        let queue_id = message.rsplit("queued as ").next().unwrap();
+        assert_eq!(queue_id, "4Bz3Xk1Q2sz9rxN");
+        assert_eq!(response.enhanced_code(), Some((2, 0, 0)));
+    }
+
+    #[test]
+    fn test_send_quit() {
+        let client = SmtpClient::new_unencrypted_localhost()
+            .unwrap()
+            .send_quit(false);
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n",
+        );
+
+        assert!(transport.send(email()).is_ok());
+        assert!(!transport.client.is_connected());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.ends_with("DATA\r\nHello\r\n.\r\n"));
+    }
+
+    #[test]
+    fn test_send_bounce() {
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n221 Bye\r\n",
+        );
+
+        let envelope = Envelope::bounce(vec![
+            EmailAddress::new("alice@localhost".to_string()).unwrap()
+        ])
+        .unwrap();
+        let email = SendableEmail::new(envelope, "id".to_string(), b"Hello".to_vec());
+        assert!(transport.send(email).is_ok());
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("\r\nMAIL FROM:<>\r\nRCPT TO:<alice@localhost>\r\n"));
+    }
+
+    #[cfg(feature = "builder")]
+    #[test]
+    fn test_send_empty_body() {
+        use crate::builder::EmailBuilder;
+
+        let client = SmtpClient::new_unencrypted_localhost().unwrap();
+        let (mut transport, mut mock) = transport(
+            client,
+            "250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n250 Queued\r\n221 Bye\r\n",
+        );
+
+        let email = EmailBuilder::new()
+            .to("alice@localhost")
+            .from("sender@localhost")
+            .subject("Ping")
+            .build()
+            .unwrap();
+        assert!(transport.send(SendableEmail::from(&email)).is_ok());
+
+        let written = String::from_utf8(mock.take_vec()).unwrap();
+        assert!(written.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(written.ends_with("\r\n\r\n\r\n.\r\nQUIT\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixListener;
+        use std::thread;
+
+        let path = std::env::temp_dir().join(format!("lettre-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            test_server::session(stream, "250 smtp.example.com")
+        });
+
+        let mut transport = SmtpClient::new_unix(&path).transport();
+        assert!(transport.send(email()).is_ok());
+        let commands = server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(commands[0].starts_with("EHLO "));
+        assert_eq!(commands[1], "MAIL FROM:<sender@localhost>");
+        assert_eq!(commands[5], "DATA");
+        assert_eq!(commands[6], ".");
+    }
+
+    #[test]
+    fn test_test_connection() {
+        let (addr, server) =
+            test_server::serve("250-smtp.example.com\r\n250-AUTH PLAIN\r\n250 8BITMIME", 1);
+
+        let mut transport = SmtpClient::new(addr, ClientSecurity::None)
+            .unwrap()
+            .transport();
+        let server_info = transport.test_connection().unwrap();
+        assert_eq!(server_info.name, "smtp.example.com");
+        assert!(server_info.supports_feature(Extension::EightBitMime));
+        assert!(server_info.supports_auth_mechanism(Mechanism::Plain));
+        let commands = server.join().unwrap();
+        assert!(commands[0].starts_with("EHLO "));
+        assert_eq!(commands[1..], ["QUIT"]);
+        assert!(transport.server_info.is_none());
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            writer.write_all(b"220 smtp.example.com ESMTP\r\n").unwrap();
+            let _ = lines.next();
+            // Drips the EHLO response, each byte well within the IO timeout
+            for &byte in b"250-smtp.example.com\r\n".iter().cycle().take(200) {
+                thread::sleep(Duration::from_millis(20));
+                if writer.write_all(&[byte]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut transport = SmtpClient::new(addr, ClientSecurity::None)
+            .unwrap()
+            .timeout(Some(Duration::from_secs(1)))
+            .send_timeout(Some(Duration::from_millis(300)))
+            .transport();
+        let start = Instant::now();
+        match transport.send(email()) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!transport.client.is_connected());
+    }
+
+    #[test]
+    fn test_send_timeout_connect() {
+        use std::net::TcpListener;
+        use std::time::{Duration, Instant};
+
+        // The server accepts the connection but never answers the TLS handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tls_parameters = ClientTlsParameters::new(
+            "smtp.example.com".to_string(),
+            native_tls::TlsConnector::new().unwrap(),
+        );
+        let mut transport = SmtpClient::new(addr, ClientSecurity::Wrapper(tls_parameters))
+            .unwrap()
+            .timeout(Some(Duration::from_secs(10)))
+            .send_timeout(Some(Duration::from_millis(300)))
+            .transport();
+        let start = Instant::now();
+        assert!(transport.send(email()).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_resolver() {
+        use super::Resolver;
+
+        let (addr, _server) = test_server::serve("250 smtp.example.com", 1);
+
+        // The address of the client is never used
+        let resolved = Arc::new(Mutex::new(0));
+        let count = resolved.clone();
+        let mut transport = SmtpClient::new("192.0.2.1:25", ClientSecurity::None)
+            .unwrap()
+            .resolver(Resolver::new(move || {
+                *count.lock().unwrap() += 1;
+                Ok(vec![addr])
+            }))
+            .transport();
+        let server_info = transport.test_connection().unwrap();
+        assert_eq!(server_info.name, "smtp.example.com");
+        assert_eq!(*resolved.lock().unwrap(), 1);
+
+        let mut transport = SmtpClient::new("192.0.2.1:25", ClientSecurity::None)
+            .unwrap()
+            .resolver(Resolver::new(|| Ok(vec![])))
+            .transport();
+        assert!(transport.test_connection().is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::fmt::Debug;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+        use tracing_core::span::Current;
+
+        /// Records the spans, their recorded fields and the events as strings
+        struct Capture {
+            lines: Arc<Mutex<Vec<String>>>,
+            next_id: AtomicU64,
+            spans: Mutex<Vec<(u64, &'static Metadata<'static>)>>,
+            entered: Mutex<Vec<u64>>,
+        }
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields(format!("span {}", span.metadata().name()));
+                span.record(&mut fields);
+                self.lines.lock().unwrap().push(fields.0);
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                self.spans.lock().unwrap().push((id, span.metadata()));
+                Id::from_u64(id)
+            }
+            fn record(&self, _: &Id, values: &Record<'_>) {
+                let mut fields = Fields("record".to_string());
+                values.record(&mut fields);
+                self.lines.lock().unwrap().push(fields.0);
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields("event".to_string());
+                event.record(&mut fields);
+                self.lines.lock().unwrap().push(fields.0);
+            }
+            fn enter(&self, span: &Id) {
+                self.entered.lock().unwrap().push(span.into_u64());
+            }
+            fn exit(&self, _: &Id) {
+                self.entered.lock().unwrap().pop();
+            }
+            fn current_span(&self) -> Current {
+                let entered = self.entered.lock().unwrap();
+                let spans = self.spans.lock().unwrap();
+                match entered
+                    .last()
+                    .and_then(|id| spans.iter().find(|span| span.0 == *id))
+                {
+                    Some(&(id, metadata)) => Current::new(Id::from_u64(id), metadata),
+                    None => Current::none(),
+                }
+            }
+        }
+
+        let (addr, _server) = test_server::serve("250-smtp.example.com\r\n250 AUTH PLAIN", 1);
+
+        let lines = Arc::new(Mutex::new(vec![]));
+        let subscriber = Capture {
+            lines: lines.clone(),
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(vec![]),
+            entered: Mutex::new(vec![]),
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            let mut transport = SmtpClient::new(addr, ClientSecurity::None)
+                .unwrap()
+                .credentials(Credentials::new("user".to_string(), "password".to_string()))
+                .authentication_mechanism(Mechanism::Plain)
+                .transport();
+            assert!(transport.send(email()).is_ok());
+        });
+
+        let lines = lines.lock().unwrap();
+        let has = |line: &str| lines.iter().any(|captured| captured == line);
+        assert!(has("span smtp_send message_id=id recipients=3"));
+        assert!(has(&format!("span smtp_connect host={}", addr)));
+        assert!(has(&format!("record port={}", addr.port())));
+        assert!(has("record encrypted=false"));
+        assert!(has("span smtp_ehlo"));
+        assert!(has("span smtp_auth mechanism=PLAIN"));
+        assert!(has("record size=5"));
+        assert!(has(
+            "event message=smtp command command=MAIL FROM:<sender@localhost> \
+             AUTH=sender@localhost<CRLF>"
+        ));
+        assert!(has(
+            "event message=smtp command command=\"<redacted credentials>\""
+        ));
+        assert!(has("event message=smtp response code=250 message=Queued"));
+        // The base64 of the credentials never appears
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains("AHVzZXIAcGFzc3dvcmQ=")));
+    }
+
+    #[test]
+    fn test_strict_starttls() {
+        // The server does not advertise STARTTLS, as if the EHLO response was stripped
+        let (addr, _server) = test_server::serve("250 smtp.example.com", 2);
+
+        let client = |strict| {
+            let tls_parameters = ClientTlsParameters::new(
+                "smtp.example.com".to_string(),
+                native_tls::TlsConnector::new().unwrap(),
+            );
+            SmtpClient::new(addr, ClientSecurity::Opportunistic(tls_parameters))
+                .unwrap()
+                .strict_starttls(strict)
+                .transport()
+        };
+
+        // STARTTLS was advertised on a previous connection
+        let mut transport = client(true);
+        transport.state.starttls_advertised = true;
+        assert!(transport.test_connection().is_err());
+        assert!(!transport.client.is_connected());
+
+        let mut transport = client(false);
+        transport.state.starttls_advertised = true;
+        assert!(transport.test_connection().is_ok());
+    }
+
+    #[test]
+    fn test_strict_auth() {
+        // The server does not advertise any mechanism
+        let (addr, server) = test_server::serve("250 smtp.example.com", 2);
+        let mut transport = SmtpClient::new(addr, ClientSecurity::None)
+            .unwrap()
+            .credentials(Credentials::new("user".to_string(), "password".to_string()))
+            .strict_auth(true)
+            .transport();
+
+        assert!(transport.send(email()).is_err());
+        assert!(!transport.client.is_connected());
+        assert!(transport.send(email()).is_err());
+        assert!(!transport.client.is_connected());
+
+        let commands = server.join().unwrap();
+        assert!(!commands.iter().any(|command| command.starts_with("MAIL ")));
+        assert_eq!(
+            commands
+                .iter()
+                .filter(|command| command.starts_with("EHLO "))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_auth_after_starttls() {
+        use bufstream::BufStream;
+        use native_tls::{Identity, TlsAcceptor, TlsConnector};
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        /// Replies to the commands until QUIT or STARTTLS, and returns the last command
+        fn serve<S: BufRead + Write>(stream: &mut S, ehlo: &[u8], commands: &mut Vec<String>) {
+            let mut line = String::new();
+            while stream.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let reply: &[u8] = if command.starts_with("EHLO ") {
+                    ehlo
+                } else if command.starts_with("AUTH ") {
+                    b"235 2.7.0 Authentication successful\r\n"
+                } else if command == "STARTTLS" {
+                    b"220 2.0.0 Ready to start TLS\r\n"
+                } else {
+                    b"221 Bye\r\n"
+                };
+                stream.write_all(reply).unwrap();
+                stream.flush().unwrap();
+                let done = command == "STARTTLS" || command == "QUIT";
+                commands.push(command);
+                if done {
+                    return;
+                }
+            }
+        }
+
+        let identity = Identity::from_pkcs8(
+            include_bytes!("../../tests/certs/localhost.crt"),
+            include_bytes!("../../tests/certs/localhost.key"),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut commands = vec![];
+            let mut plain = BufStream::new(stream);
+            plain.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            plain.flush().unwrap();
+            // AUTH is only advertised once the connection is encrypted
+            serve(
+                &mut plain,
+                b"250-localhost\r\n250 STARTTLS\r\n",
+                &mut commands,
+            );
+            let tls = acceptor.accept(plain.into_inner().unwrap()).unwrap();
+            serve(
+                &mut BufStream::new(tls),
+                b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n",
+                &mut commands,
+            );
+            commands
+        });
+
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let tls_parameters = ClientTlsParameters::new("localhost".to_string(), connector);
+        let mut transport = SmtpClient::new(addr, ClientSecurity::Required(tls_parameters))
+            .unwrap()
+            .credentials(Credentials::new("user".to_string(), "password".to_string()))
+            .strict_auth(true)
+            .transport();
+
+        let server_info = transport.test_connection().unwrap();
+        assert!(server_info.supports_auth_mechanism(Mechanism::Plain));
+        let commands = server.join().unwrap();
+        assert_eq!(commands[1], "STARTTLS");
+        assert!(commands[3].starts_with("AUTH PLAIN "));
     }
 }
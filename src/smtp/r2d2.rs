@@ -1,9 +1,48 @@
 use crate::smtp::error::Error;
 use crate::smtp::{ConnectionReuseParameters, SmtpClient, SmtpTransport};
-use r2d2::ManageConnection;
+use r2d2::{Builder, ManageConnection, Pool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default time after which an idle connection is closed, 10 minutes as in r2d2
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Default lifetime of a connection, 30 minutes as in r2d2
+pub const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// Counters updated by a `SmtpConnectionManager`
+///
+/// Handles are cheap to clone and share the same counters, so one can be kept
+/// after the manager has been moved into the pool.
+#[derive(Debug, Clone, Default)]
+pub struct PoolMetrics {
+    created: Arc<AtomicUsize>,
+    validated: Arc<AtomicUsize>,
+    broken: Arc<AtomicUsize>,
+}
+
+impl PoolMetrics {
+    /// Number of connections opened by the pool
+    pub fn connections_created(&self) -> usize {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    /// Number of successful checks of an existing connection, i.e. reuses
+    pub fn connections_validated(&self) -> usize {
+        self.validated.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections found broken or no longer connected
+    pub fn connections_broken(&self) -> usize {
+        self.broken.load(Ordering::Relaxed)
+    }
+}
 
 pub struct SmtpConnectionManager {
     transport_builder: SmtpClient,
+    metrics: PoolMetrics,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
 }
 
 impl SmtpConnectionManager {
@@ -11,8 +50,46 @@ impl SmtpConnectionManager {
         Ok(SmtpConnectionManager {
             transport_builder: transport_builder
                 .connection_reuse(ConnectionReuseParameters::ReuseUnlimited),
+            metrics: PoolMetrics::default(),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            max_lifetime: Some(DEFAULT_MAX_LIFETIME),
         })
     }
+
+    /// Set the time after which an idle connection is closed, `None` to keep them open
+    ///
+    /// Many servers drop idle connections after about a minute, so this should be shorter
+    /// than the timeout of the server.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> SmtpConnectionManager {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set the time after which a connection is closed, `None` to keep them open
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> SmtpConnectionManager {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Returns a pool builder configured with the timeouts of this manager
+    pub fn pool_builder(&self) -> Builder<SmtpConnectionManager> {
+        Pool::builder()
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime)
+    }
+
+    /// Returns a handle to the counters of this manager
+    pub fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
+    }
+
+    /// Counts a broken connection, once even if both checks find it broken
+    fn count_broken(&self, conn: &mut SmtpTransport) {
+        if !conn.state.counted_broken {
+            conn.state.counted_broken = true;
+            self.metrics.broken.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
 
 impl ManageConnection for SmtpConnectionManager {
@@ -22,17 +99,140 @@ impl ManageConnection for SmtpConnectionManager {
     fn connect(&self) -> Result<Self::Connection, Error> {
         let mut transport = SmtpTransport::new(self.transport_builder.clone());
         transport.connect()?;
+        self.metrics.created.fetch_add(1, Ordering::Relaxed);
         Ok(transport)
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Error> {
         if conn.client.is_connected() {
+            self.metrics.validated.fetch_add(1, Ordering::Relaxed);
             return Ok(());
         }
+        self.count_broken(conn);
         Err(Error::Client("is not connected anymore"))
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.state.panic
+        let broken = conn.state.panic || conn.state.closed_by_server;
+        if broken {
+            self.count_broken(conn);
+        }
+        broken
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SmtpConnectionManager;
+    use crate::smtp::client::mock::MockStream;
+    use crate::smtp::client::net::NetworkStream;
+    use crate::smtp::error::Error;
+    use crate::smtp::extension::ServerInfo;
+    use crate::smtp::{SmtpClient, SmtpTransport};
+    use crate::{EmailAddress, Envelope, SendableEmail, Transport};
+    use r2d2::ManageConnection;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn email() -> SendableEmail {
+        SendableEmail::new(
+            Envelope::new(
+                Some(EmailAddress::new("user@localhost".to_string()).unwrap()),
+                vec![EmailAddress::new("root@localhost".to_string()).unwrap()],
+            )
+            .unwrap(),
+            "id".to_string(),
+            b"Hello".to_vec(),
+        )
+    }
+
+    fn transport(responses: &str) -> SmtpTransport {
+        let mut transport = SmtpTransport::new(SmtpClient::new_unencrypted_localhost().unwrap());
+        transport
+            .client
+            .set_stream(NetworkStream::Mock(MockStream::with_vec(
+                responses.as_bytes().to_vec(),
+            )));
+        transport
+    }
+
+    #[test]
+    fn test_metrics() {
+        let manager =
+            SmtpConnectionManager::new(SmtpClient::new_unencrypted_localhost().unwrap()).unwrap();
+        let metrics = manager.metrics();
+
+        let mut conn = transport("250 OK\r\n250 OK\r\n250 OK\r\n");
+        for _ in 0..3 {
+            assert!(manager.is_valid(&mut conn).is_ok());
+            assert!(!manager.has_broken(&mut conn));
+        }
+        assert_eq!(metrics.connections_validated(), 3);
+        assert_eq!(metrics.connections_broken(), 0);
+
+        assert!(manager.is_valid(&mut conn).is_err());
+        conn.state.panic = true;
+        assert!(manager.has_broken(&mut conn));
+        assert_eq!(metrics.connections_validated(), 3);
+        assert_eq!(metrics.connections_broken(), 1);
+        assert_eq!(metrics.connections_created(), 0);
+    }
+
+    #[test]
+    fn test_pool_timeouts() {
+        let manager = SmtpConnectionManager::new(SmtpClient::new_unencrypted_localhost().unwrap())
+            .unwrap()
+            .idle_timeout(Some(Duration::from_secs(30)))
+            .max_lifetime(None);
+        let pool = manager.pool_builder().max_size(1).build_unchecked(manager);
+        assert_eq!(pool.idle_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(pool.max_lifetime(), None);
+
+        let manager =
+            SmtpConnectionManager::new(SmtpClient::new_unencrypted_localhost().unwrap()).unwrap();
+        let pool = manager.pool_builder().build_unchecked(manager);
+        assert_eq!(pool.idle_timeout(), Some(Duration::from_secs(10 * 60)));
+        assert_eq!(pool.max_lifetime(), Some(Duration::from_secs(30 * 60)));
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let manager =
+            SmtpConnectionManager::new(SmtpClient::new_unencrypted_localhost().unwrap()).unwrap();
+        let mut conn = transport("250 OK\r\n250 OK\r\n");
+        conn.server_info = Some(ServerInfo {
+            name: "localhost".to_string(),
+            features: HashSet::new(),
+            deliver_by_min_time: 0,
+        });
+        conn.state.connection_reuse_count = 1;
+
+        match conn.send(email()) {
+            Err(Error::ConnectionLost) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(manager.has_broken(&mut conn));
+    }
+
+    #[test]
+    fn test_service_closing() {
+        let manager =
+            SmtpConnectionManager::new(SmtpClient::new_unencrypted_localhost().unwrap()).unwrap();
+        let mut conn = transport("250 OK\r\n421 Service shutting down\r\n");
+        conn.server_info = Some(ServerInfo {
+            name: "localhost".to_string(),
+            features: HashSet::new(),
+            deliver_by_min_time: 0,
+        });
+        conn.state.connection_reuse_count = 1;
+        assert!(!manager.has_broken(&mut conn));
+
+        match conn.send(email()) {
+            Err(Error::ServiceClosing(response)) => {
+                assert_eq!(response.message, ["Service shutting down"])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(manager.has_broken(&mut conn));
     }
 }
@@ -167,10 +167,45 @@ impl Response {
             .and_then(|line| line.split_whitespace().next())
     }
 
+    /// Returns the whole text of the message, with the lines joined by `\n`
+    ///
+    /// This is useful to parse details given by the server, like the queue ID in the
+    /// response to the message content.
+    pub fn message(&self) -> String {
+        self.message.join("\n")
+    }
+
     /// Returns only the line of the message if possible
     pub fn first_line(&self) -> Option<&str> {
         self.message.first().map(String::as_str)
     }
+
+    /// Returns the enhanced status code at the beginning of the message, if any
+    ///
+    /// [RFC 3463](https://tools.ietf.org/html/rfc3463)
+    pub fn enhanced_code(&self) -> Option<(u8, u16, u16)> {
+        let mut parts = self.first_word()?.split('.');
+        let class = parts.next()?;
+        let subject = parts.next()?;
+        let detail = parts.next()?;
+        if parts.next().is_some()
+            || !["2", "4", "5"].contains(&class)
+            || !is_enhanced_code_number(subject)
+            || !is_enhanced_code_number(detail)
+        {
+            return None;
+        }
+        Some((
+            class.parse().ok()?,
+            subject.parse().ok()?,
+            detail.parse().ok()?,
+        ))
+    }
+}
+
+/// Checks a subject or detail of an enhanced status code, one to three digits
+fn is_enhanced_code_number(number: &str) -> bool {
+    !number.is_empty() && number.len() <= 3 && number.bytes().all(|b| b.is_ascii_digit())
 }
 
 // Parsers (originally from tokio-smtp)
@@ -470,6 +505,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_response_message() {
+        let response = "250-me\r\n250-8BITMIME\r\n250 SIZE 42\r\n"
+            .parse::<Response>()
+            .unwrap();
+        assert_eq!(response.message(), "me\n8BITMIME\nSIZE 42");
+        assert_eq!("250 \r\n".parse::<Response>().unwrap().message(), "");
+    }
+
     #[test]
     fn test_response_first_line() {
         assert_eq!(
@@ -553,4 +597,38 @@ mod test {
             Some("")
         );
     }
+
+    #[test]
+    fn test_response_enhanced_code() {
+        let response = |line: &str| {
+            Response::new(
+                Code {
+                    severity: Severity::PermanentNegativeCompletion,
+                    category: Category::MailSystem,
+                    detail: Detail::Zero,
+                },
+                vec![line.to_string()],
+            )
+        };
+        assert_eq!(
+            response("5.1.1 <nobody@example.com>: Recipient address rejected").enhanced_code(),
+            Some((5, 1, 1))
+        );
+        assert_eq!(
+            response("5.7.1 Message rejected by policy").enhanced_code(),
+            Some((5, 7, 1))
+        );
+        assert_eq!(response("4.4.2 Timeout").enhanced_code(), Some((4, 4, 2)));
+        assert_eq!(response("2.0.0 Ok").enhanced_code(), Some((2, 0, 0)));
+        assert_eq!(
+            response("5.3.123 Too big").enhanced_code(),
+            Some((5, 3, 123))
+        );
+        assert_eq!(response("Mailbox unavailable").enhanced_code(), None);
+        assert_eq!(response("3.1.1 Invalid class").enhanced_code(), None);
+        assert_eq!(response("5.1 Incomplete").enhanced_code(), None);
+        assert_eq!(response("5.1.1.1 Too long").enhanced_code(), None);
+        assert_eq!(response("5.1.1000 Too long").enhanced_code(), None);
+        assert_eq!(response("").enhanced_code(), None);
+    }
 }
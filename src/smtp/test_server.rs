@@ -0,0 +1,70 @@
+//! A minimal SMTP server for the tests of the transports
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+/// Replies to the commands of one session, with `ehlo` as the EHLO response, and
+/// returns the received commands
+///
+/// Every other command is accepted, the message data is skipped.
+pub fn session<S: Read + Write>(stream: S, ehlo: &str) -> Vec<String> {
+    let mut reader = BufReader::new(stream);
+    let mut commands = vec![];
+    if reader
+        .get_mut()
+        .write_all(b"220 smtp.example.com ESMTP\r\n")
+        .is_err()
+    {
+        return commands;
+    }
+    let mut in_data = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        let command = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+        let reply = if in_data {
+            if command != "." {
+                continue;
+            }
+            in_data = false;
+            "250 Queued".to_string()
+        } else if command.starts_with("EHLO ") {
+            ehlo.to_string()
+        } else if command.starts_with("AUTH ") {
+            "235 2.7.0 Accepted".to_string()
+        } else if command == "DATA" {
+            in_data = true;
+            "354 Go ahead".to_string()
+        } else if command == "QUIT" {
+            "221 Bye".to_string()
+        } else {
+            "250 OK".to_string()
+        };
+        let quit = command == "QUIT";
+        commands.push(command);
+        if write!(reader.get_mut(), "{}\r\n", reply).is_err() || quit {
+            break;
+        }
+    }
+    commands
+}
+
+/// Serves the given number of sessions on a loopback port, and returns the commands
+/// received in all of them
+pub fn serve(ehlo: &'static str, sessions: usize) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        listener
+            .incoming()
+            .take(sessions)
+            .flat_map(|stream| session(stream.unwrap(), ehlo))
+            .collect()
+    });
+    (addr, server)
+}
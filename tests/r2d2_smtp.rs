@@ -29,6 +29,22 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn send_many_metrics() {
+        let client = SmtpClient::new("127.0.0.1:2525", ClientSecurity::None).unwrap();
+        let manager = SmtpConnectionManager::new(client).unwrap();
+        let metrics = manager.metrics();
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+
+        for _ in 0..3 {
+            let mut mailer = pool.get().unwrap();
+            (*mailer).send(email("send many")).unwrap();
+        }
+        assert_eq!(metrics.connections_created(), 1);
+        assert_eq!(metrics.connections_validated(), 3);
+        assert_eq!(metrics.connections_broken(), 0);
+    }
+
     #[test]
     fn send_from_thread() {
         let client = SmtpClient::new("127.0.0.1:2525", ClientSecurity::None).unwrap();
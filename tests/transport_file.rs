@@ -2,10 +2,11 @@
 #[cfg(feature = "file-transport")]
 mod test {
     use lettre::file::FileTransport;
-    use lettre::{EmailAddress, Envelope, SendableEmail, Transport};
+    use lettre::stub::StubTransport;
+    use lettre::{EmailAddress, Envelope, RawMessage, SendableEmail, Transport};
     use std::env::temp_dir;
-    use std::fs::remove_file;
     use std::fs::File;
+    use std::fs::{remove_dir_all, remove_file};
     use std::io::Read;
 
     #[test]
@@ -37,4 +38,64 @@ mod test {
 
         remove_file(file).unwrap();
     }
+
+    #[test]
+    fn file_transport_create_dir() {
+        let root = temp_dir().join(format!("lettre-{}", std::process::id()));
+        let path = root.join("nested").join("dir");
+        let email = || {
+            SendableEmail::new(
+                Envelope::new(
+                    Some(EmailAddress::new("user@localhost".to_string()).unwrap()),
+                    vec![EmailAddress::new("root@localhost".to_string()).unwrap()],
+                )
+                .unwrap(),
+                "id".to_string(),
+                "Hello".to_string().into_bytes(),
+            )
+        };
+
+        assert!(FileTransport::new(&path).send(email()).is_err());
+        assert!(!path.exists());
+
+        let result = FileTransport::new(&path).create_dir(true).send(email());
+        assert!(result.is_ok());
+        assert!(path.join("id.json").is_file());
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn file_transport_raw_message() {
+        let message = RawMessage::new(
+            Envelope::new(
+                Some(EmailAddress::new("user@localhost".to_string()).unwrap()),
+                vec![EmailAddress::new("root@localhost".to_string()).unwrap()],
+            )
+            .unwrap(),
+            "raw-id".to_string(),
+            b"Subject: Hello\r\n\r\nHello".to_vec(),
+        );
+
+        assert!(FileTransport::new(temp_dir()).send(&message).is_ok());
+        assert!(StubTransport::new_positive().send(&message).is_ok());
+
+        let file = temp_dir().join("raw-id.json");
+        let mut buffer = String::new();
+        File::open(&file)
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        let bytes = format!("{:?}", message.bytes()).replace(' ', "");
+        assert!(buffer.contains(&format!("\"message\":{}", bytes)));
+
+        // The message can still be sent again
+        let email = SendableEmail::from(message.clone());
+        assert_eq!(
+            email.message_to_string().unwrap().as_bytes(),
+            message.bytes()
+        );
+
+        remove_file(file).unwrap();
+    }
 }
@@ -0,0 +1,103 @@
+#[cfg(test)]
+#[cfg(all(feature = "test-util", feature = "builder"))]
+mod test {
+    use lettre::builder::EmailBuilder;
+    use lettre::smtp::authentication::{Credentials, Mechanism};
+    use lettre::smtp::error::Error;
+    use lettre::smtp::mock_server::MockSmtpServer;
+    use lettre::{ClientSecurity, SmtpClient, Transport};
+
+    fn client(server: &MockSmtpServer) -> SmtpClient {
+        SmtpClient::new(server.addr(), ClientSecurity::None).unwrap()
+    }
+
+    fn email(to: &[&str]) -> lettre::builder::Email {
+        to.iter()
+            .fold(EmailBuilder::new(), |builder, &to| builder.to(to))
+            .from("user@example.com")
+            .subject("Hello")
+            .text("Hello world!")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn smtp_mock_send() {
+        let server = MockSmtpServer::builder()
+            .capability("8BITMIME")
+            .reject_recipient("unknown@example.com")
+            .start()
+            .unwrap();
+        let mut transport = client(&server).continue_on_rejected_rcpt(true).transport();
+
+        let report = transport
+            .send_with_report(email(&["root@example.com", "unknown@example.com"]))
+            .unwrap();
+        assert_eq!(report.accepted.len(), 1);
+        assert_eq!(report.rejected.len(), 1);
+
+        let messages = server.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].from, "user@example.com");
+        assert_eq!(messages[0].to, ["root@example.com"]);
+        assert!(messages[0].data.contains("Subject: Hello\r\n"));
+        assert!(server
+            .commands()
+            .contains(&"MAIL FROM:<user@example.com> BODY=8BITMIME".to_string()));
+    }
+
+    #[test]
+    fn smtp_mock_data_rejected() {
+        let server = MockSmtpServer::builder()
+            .data_response("554 5.7.1 Message rejected as spam")
+            .start()
+            .unwrap();
+        let mut transport = client(&server).transport();
+
+        match transport.send(email(&["root@example.com"])) {
+            Err(Error::Permanent(response)) => {
+                assert_eq!(response.message, ["5.7.1 Message rejected as spam"])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(server.messages().is_empty());
+    }
+
+    #[test]
+    fn smtp_mock_auth_required() {
+        let server = MockSmtpServer::builder()
+            .auth_required("user", "password")
+            .start()
+            .unwrap();
+
+        let mut transport = client(&server).transport();
+        assert!(transport.send(email(&["root@example.com"])).is_err());
+
+        for mechanism in &[Mechanism::Plain, Mechanism::Login] {
+            let mut transport = client(&server)
+                .credentials(Credentials::new("user".to_string(), "password".to_string()))
+                .authentication_mechanism(*mechanism)
+                .transport();
+            assert!(transport.send(email(&["root@example.com"])).is_ok());
+        }
+        assert_eq!(server.messages().len(), 2);
+
+        let mut transport = client(&server)
+            .credentials(Credentials::new("user".to_string(), "wrong".to_string()))
+            .authentication_mechanism(Mechanism::Plain)
+            .transport();
+        assert!(transport.send(email(&["root@example.com"])).is_err());
+    }
+
+    #[test]
+    fn smtp_mock_starttls_required() {
+        let server = MockSmtpServer::builder()
+            .starttls_required()
+            .start()
+            .unwrap();
+        let mut transport = client(&server).transport();
+
+        assert!(transport.send(email(&["root@example.com"])).is_err());
+        assert!(server.messages().is_empty());
+    }
+}